@@ -9,6 +9,31 @@ pub enum JumpInst {
     JumpIf(usize, usize),
 }
 
+impl JumpInst {
+    /// The blocks this may jump to
+    pub fn successors(&self) -> impl Iterator<Item = usize> {
+        let (first, second) = match *self {
+            JumpInst::Jump(to) => (Some(to), None),
+            JumpInst::JumpIf(t, f) => (Some(t), Some(f)),
+            _ => (None, None),
+        };
+        first.into_iter().chain(second)
+    }
+
+    /// The number of instructions this takes when `next` is the block placed
+    /// right after it, which it can fall through to
+    pub fn code_len(&self, next: Option<usize>) -> usize {
+        match *self {
+            JumpInst::Undefined | JumpInst::Unreachable => 0,
+            JumpInst::Return => 1,
+            JumpInst::Jump(to) if Some(to) == next => 0,
+            JumpInst::Jump(_) => 1,
+            JumpInst::JumpIf(t, f) if Some(t) == next || Some(f) == next => 1,
+            JumpInst::JumpIf(..) => 2,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BasicBlock {
     pub code: Vec<Op>,
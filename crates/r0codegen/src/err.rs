@@ -1,4 +1,6 @@
-use r0syntax::span::Span;
+use crate::lint::{Lint, Warning};
+use r0syntax::{diagnostic::Diagnostic, span::Span};
+use std::cell::RefCell;
 
 #[derive(Debug, Clone)]
 pub struct CompileError {
@@ -15,19 +17,91 @@ pub fn CompileError(kind: CompileErrorKind, span: Option<Span>) -> CompileError
 pub enum CompileErrorKind {
     UnknownType(String),
     NoSuchSymbol(String),
-    DuplicateSymbol(String),
+    DuplicateSymbol {
+        name: String,
+        /// Where the name was defined first, if it's in the source
+        previous: Option<Span>,
+    },
     VoidTypeVariable,
     TypeMismatch {
         expected: String,
         got: Option<String>,
     },
     NotLValue,
+    AggregateAsValue(String),
+    NotIndexable(String),
+    NoSuchField {
+        ty: String,
+        field: String,
+    },
     InvalidCalculation(String),
+    IntegerOutOfRange,
     FuncParamSizeMismatch(usize, usize),
-    AssignToConst,
+    AssignToConst {
+        /// Where the constant is declared, if it's in the source
+        decl: Option<Span>,
+    },
     NoBreakContext,
     NoContinueContext,
     NotAllRoutesReturn,
+    TypeAnnotationNeeded(String),
+    UnknownLabel(String),
+    ZeroLengthArray,
+}
+
+/// Collects the errors and warnings found during compilation, so that
+/// compilation can go on after the first one.
+#[derive(Debug, Default)]
+pub struct ErrorSink {
+    errors: RefCell<Vec<CompileError>>,
+    warnings: RefCell<Vec<Warning>>,
+    /// Lints whose warnings are dropped
+    allowed: Vec<Lint>,
+}
+
+impl ErrorSink {
+    pub fn new() -> ErrorSink {
+        ErrorSink::default()
+    }
+
+    pub fn with_allowed_lints(allowed: Vec<Lint>) -> ErrorSink {
+        ErrorSink {
+            allowed,
+            ..ErrorSink::default()
+        }
+    }
+
+    pub fn is_allowed(&self, lint: Lint) -> bool {
+        self.allowed.contains(&lint)
+    }
+
+    pub fn warn(&self, warning: Warning) {
+        if !self.is_allowed(warning.lint) {
+            self.warnings.borrow_mut().push(warning);
+        }
+    }
+
+    pub fn report(&self, error: CompileError) {
+        self.errors.borrow_mut().push(error);
+    }
+
+    /// Report the error in `res` if there is one
+    pub fn ok_or_report<T>(&self, res: Result<T, CompileError>) -> Option<T> {
+        res.map_err(|e| self.report(e)).ok()
+    }
+
+    /// The number of errors reported so far
+    pub fn len(&self) -> usize {
+        self.errors.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.borrow().is_empty()
+    }
+
+    pub fn into_parts(self) -> (Vec<CompileError>, Vec<Warning>) {
+        (self.errors.into_inner(), self.warnings.into_inner())
+    }
 }
 
 pub trait WithSpan {
@@ -46,3 +120,105 @@ impl<T> WithSpan for Result<T, CompileError> {
         self.map_err(|e| e.with_span(span))
     }
 }
+
+impl CompileErrorKind {
+    /// The stable error code of this kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileErrorKind::UnknownType(_) => "E0100",
+            CompileErrorKind::NoSuchSymbol(_) => "E0101",
+            CompileErrorKind::DuplicateSymbol { .. } => "E0102",
+            CompileErrorKind::VoidTypeVariable => "E0103",
+            CompileErrorKind::TypeMismatch { .. } => "E0104",
+            CompileErrorKind::NotLValue => "E0105",
+            CompileErrorKind::AggregateAsValue(_) => "E0106",
+            CompileErrorKind::NotIndexable(_) => "E0107",
+            CompileErrorKind::NoSuchField { .. } => "E0108",
+            CompileErrorKind::InvalidCalculation(_) => "E0109",
+            CompileErrorKind::IntegerOutOfRange => "E0110",
+            CompileErrorKind::FuncParamSizeMismatch(..) => "E0111",
+            CompileErrorKind::AssignToConst { .. } => "E0112",
+            CompileErrorKind::NoBreakContext => "E0113",
+            CompileErrorKind::NoContinueContext => "E0114",
+            CompileErrorKind::NotAllRoutesReturn => "E0115",
+            CompileErrorKind::TypeAnnotationNeeded(_) => "E0116",
+            CompileErrorKind::UnknownLabel(_) => "E0117",
+            CompileErrorKind::ZeroLengthArray => "E0118",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            CompileErrorKind::UnknownType(ty) => format!("unknown type `{}`", ty),
+            CompileErrorKind::NoSuchSymbol(name) => {
+                format!("cannot find `{}` in this scope", name)
+            }
+            CompileErrorKind::DuplicateSymbol { name, .. } => {
+                format!("`{}` is already defined in this scope", name)
+            }
+            CompileErrorKind::VoidTypeVariable => "variables cannot have type `void`".into(),
+            CompileErrorKind::TypeMismatch {
+                expected,
+                got: Some(got),
+            } => format!("mismatched types: expected `{}`, found `{}`", expected, got),
+            CompileErrorKind::TypeMismatch {
+                expected,
+                got: None,
+            } => format!("mismatched types: expected `{}`, found nothing", expected),
+            CompileErrorKind::NotLValue => "cannot assign to this expression".into(),
+            CompileErrorKind::AggregateAsValue(ty) => {
+                format!("values of aggregate type `{}` cannot be used here", ty)
+            }
+            CompileErrorKind::NotIndexable(ty) => format!("cannot index into type `{}`", ty),
+            CompileErrorKind::NoSuchField { ty, field } => {
+                format!("no field `{}` on type `{}`", field, ty)
+            }
+            CompileErrorKind::InvalidCalculation(ty) => {
+                format!("this operation is not supported on type `{}`", ty)
+            }
+            CompileErrorKind::IntegerOutOfRange => "integer literal is out of range".into(),
+            CompileErrorKind::FuncParamSizeMismatch(got, expected) => format!(
+                "this function takes {} argument(s) but {} were supplied",
+                expected, got
+            ),
+            CompileErrorKind::AssignToConst { .. } => "cannot assign to a constant".into(),
+            CompileErrorKind::NoBreakContext => "`break` outside of a loop".into(),
+            CompileErrorKind::NoContinueContext => "`continue` outside of a loop".into(),
+            CompileErrorKind::NotAllRoutesReturn => {
+                "not all paths of this function return a value".into()
+            }
+            CompileErrorKind::TypeAnnotationNeeded(name) => {
+                format!("type annotation needed for `{}`", name)
+            }
+            CompileErrorKind::UnknownLabel(name) => {
+                format!("use of undeclared label `'{}`", name)
+            }
+            CompileErrorKind::ZeroLengthArray => "arrays must have at least one element".into(),
+        }
+    }
+}
+
+impl CompileError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diag = Diagnostic::error(self.kind.code(), self.kind.message()).with_span(self.span);
+        match &self.kind {
+            CompileErrorKind::TypeMismatch { .. } => {
+                diag.with_note("values can be converted between types with `as`")
+            }
+            CompileErrorKind::AggregateAsValue(_) => {
+                diag.with_note("arrays and structs can only be accessed by element or field")
+            }
+            CompileErrorKind::IntegerOutOfRange => {
+                diag.with_note("integer literals must be within the range of `int`")
+            }
+            CompileErrorKind::DuplicateSymbol {
+                name,
+                previous: Some(previous),
+            } => diag.with_label(*previous, format!("`{}` is first defined here", name)),
+            CompileErrorKind::AssignToConst { decl: Some(decl) } => {
+                diag.with_label(*decl, "the constant is declared here")
+            }
+            _ => diag,
+        }
+    }
+}
@@ -2,176 +2,123 @@ mod util;
 
 use crate::{
     code::{BasicBlock, JumpInst},
-    err::{CompileError, CompileErrorKind, WithSpan},
-    scope::{Scope, Symbol, SymbolIdGenerator},
-    ty::{FuncTy, Ty},
+    err::{CompileError, ErrorSink, WithSpan},
+    hir::{self, LoopId, SymbolId},
+    lint::{self, Lint, Warning},
+    opt::PassManager,
+    ssa,
+    ty::Ty,
 };
-use ast::FuncStmt;
-use bit_set::BitSet;
 use indexmap::{IndexMap, IndexSet};
-use r0syntax::{
-    ast,
-    span::Span,
-    util::Mut,
-    util::{MutWeak, P},
-};
+use r0syntax::ast;
 use r0vm::{opcodes::Op, s0};
 use smol_str::SmolStr;
-use std::cell::RefCell;
 use util::BBArranger;
 
-static RET_VAL_KEY: &str = "$ret";
-static FUNC_VAL_KEY: &str = "$func";
+/// Switches with more case values than this find the matching case with a
+/// binary search instead of comparing against each value in turn
+const SWITCH_LINEAR_CASES: usize = 4;
 
 type CompileResult<T> = std::result::Result<T, CompileError>;
 type BB = usize;
 
-pub fn compile(tree: &ast::Program) -> CompileResult<s0::S0> {
-    let global_sym_gen = RefCell::new(SymbolIdGenerator::new());
-    let mut global_scope = Scope::new(&global_sym_gen);
-    let global_entries = Mut::new(GlobalEntries {
-        functions: indexmap::indexset! {"_start".into()},
-        values: IndexMap::new(),
-    });
+/// Options that change the generated code
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Check array indices at runtime, panicking when out of bounds
+    pub bounds_check: bool,
+    /// Lints that don't emit warnings
+    pub allowed_lints: Vec<Lint>,
+    /// How much to optimise the generated code, see `PassManager::for_level`
+    pub opt_level: u8,
+}
 
-    let mut funcs = vec![];
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            bounds_check: true,
+            allowed_lints: vec![],
+            opt_level: 0,
+        }
+    }
+}
 
-    create_lib_func(&mut global_scope);
+/// Compile `tree`, returning every error found if it fails
+pub fn compile(tree: &ast::Program) -> Result<s0::S0, Vec<CompileError>> {
+    compile_with_options(tree, &CompileOptions::default())
+}
 
-    for decl in &tree.decls {
-        let (var_id, ty) = add_decl_scope(decl, &mut global_scope)?;
-        global_entries
-            .borrow_mut()
-            .values
-            .insert(var_id, vec![0u8; ty.size()]);
+pub fn compile_with_options(
+    tree: &ast::Program,
+    options: &CompileOptions,
+) -> Result<s0::S0, Vec<CompileError>> {
+    compile_with_warnings(tree, options).0
+}
+
+/// Compile `tree`, also returning the warnings found, whether it fails or not.
+/// Lints only run once the program type checks.
+pub fn compile_with_warnings(
+    tree: &ast::Program,
+    options: &CompileOptions,
+) -> (Result<s0::S0, Vec<CompileError>>, Vec<Warning>) {
+    let errors = ErrorSink::with_allowed_lints(options.allowed_lints.clone());
+    let program = hir::lower_program(tree, &errors);
+
+    let s0 = if errors.is_empty() {
+        lint::check_program(&program, &errors);
+        Some(compile_program(&program, options, &errors))
+    } else {
+        None
+    };
+
+    let (compile_errors, warnings) = errors.into_parts();
+    match s0 {
+        Some(s0) if compile_errors.is_empty() => (Ok(s0), warnings),
+        _ => (Err(compile_errors), warnings),
     }
-    {
-        global_entries
-            .borrow_mut()
-            .functions
-            .insert("_start".into());
+}
+
+/// Generate code for `program`, which must have type checked
+fn compile_program(program: &hir::Program, options: &CompileOptions, errors: &ErrorSink) -> s0::S0 {
+    let mut global_entries = GlobalEntries {
+        functions: indexmap::indexset! {"_start".into()},
+        values: vec![],
+        global_vars: IndexMap::new(),
+    };
+    for var in &program.globals {
+        let value_id = global_entries.insert_value(vec![0u8; var.ty.size()]);
+        global_entries.global_vars.insert(var.id, value_id);
     }
-    for func in &tree.funcs {
-        if !global_entries
-            .borrow_mut()
-            .functions
-            .insert(func.name.name.clone())
-        {
-            return Err(CompileError(
-                CompileErrorKind::DuplicateSymbol(func.name.name.as_str().into()),
-                Some(func.name.span),
-            ));
-        }
-        let func = compile_func(func, &mut global_scope, global_entries.clone())?;
-        funcs.push(func);
+    for func in &program.funcs {
+        global_entries.functions.insert(func.name.clone());
     }
 
-    let start = compile_start_func(tree, &mut global_scope, global_entries.clone())?;
+    let mut funcs = program
+        .funcs
+        .iter()
+        .map(|func| FuncCodegen::new(func, &mut global_entries, options, errors).compile())
+        .collect::<Vec<_>>();
+    let start = compile_start_func(&program.start, &mut global_entries, options, errors);
     funcs.insert(0, start);
 
-    let mut global_entries = Mut::take_inner(global_entries).unwrap_or_else(|_| panic!());
-
-    let s0 = s0::S0 {
+    s0::S0 {
         globals: global_entries
             .values
-            .drain(..)
-            .map(|(_, val)| s0::GlobalValue {
+            .into_iter()
+            .map(|val| s0::GlobalValue {
                 is_const: false,
                 bytes: val,
             })
             .collect(),
         functions: funcs,
-    };
-
-    Ok(s0)
-}
-
-fn create_lib_func(scope: &mut Scope) {
-    scope.insert(
-        "putint".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![P(Ty::Int)],
-                ret: P(Ty::Void),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "putstr".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![P(Ty::Int)],
-                ret: P(Ty::Void),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "putdouble".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![P(Ty::Double)],
-                ret: P(Ty::Void),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "putchar".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![P(Ty::Int)],
-                ret: P(Ty::Void),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "putln".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![],
-                ret: P(Ty::Void),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "getchar".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![],
-                ret: P(Ty::Int),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "getint".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![],
-                ret: P(Ty::Int),
-            }),
-            true,
-        ),
-    );
-    scope.insert(
-        "getdouble".into(),
-        Symbol::new(
-            Ty::Func(FuncTy {
-                params: vec![],
-                ret: P(Ty::Double),
-            }),
-            true,
-        ),
-    );
+    }
 }
 
 struct GlobalEntries {
     functions: IndexSet<SmolStr>,
-    values: IndexMap<u64, Vec<u8>>,
+    values: Vec<Vec<u8>>,
+    /// The global value of each global variable
+    global_vars: IndexMap<SymbolId, u32>,
 }
 
 impl GlobalEntries {
@@ -179,112 +126,30 @@ impl GlobalEntries {
         self.functions.get_index_of(func_name).map(|x| x as u32)
     }
 
-    pub fn value_id(&self, symbol_id: u64) -> Option<u32> {
-        self.values.get_index_of(&symbol_id).map(|x| x as u32)
-    }
-
-    pub fn insert_string_literal(&mut self, s: &str, val_id: u64) -> u32 {
-        self.values.insert(val_id, s.as_bytes().into());
-        self.value_id(val_id).unwrap()
+    pub fn insert_value(&mut self, bytes: Vec<u8>) -> u32 {
+        self.values.push(bytes);
+        (self.values.len() - 1) as u32
     }
 }
 
 fn compile_start_func(
-    tree: &ast::Program,
-    global_scope: &mut Scope,
-    global_entries: Mut<GlobalEntries>,
-) -> CompileResult<s0::FnDef> {
-    let start_func = FuncStmt {
-        name: ast::Ident {
-            name: "_start".into(),
-            span: Span::default(),
-        },
-        params: vec![],
-        ret_ty: ast::TyDef {
-            name: "void".into(),
-            params: None,
-            span: Span::default(),
-        },
-        body: ast::BlockStmt {
-            span: Span::default(),
-            stmts: tree
-                .decls
-                .iter()
-                .cloned()
-                .filter_map(|decl: ast::DeclStmt| {
-                    Some(ast::Stmt::Expr(ast::Expr::Assign(ast::AssignExpr {
-                        span: decl.span,
-                        allow_assign_const: true,
-                        lhs: P::new(ast::Expr::Ident(decl.name)),
-                        rhs: decl.val?,
-                    })))
-                })
-                .chain(
-                    vec![
-                        ast::Stmt::Expr(ast::Expr::Call(ast::CallExpr {
-                            span: Span::default(),
-                            func: ast::Ident {
-                                span: Span::default(),
-                                name: "main".into(),
-                            },
-                            params: vec![],
-                        })),
-                        ast::Stmt::Return(ast::ReturnStmt {
-                            val: None,
-                            span: Span::default(),
-                        }),
-                    ]
-                    .into_iter(),
-                )
-                .collect(),
-        },
-        span: Span::default(),
-    };
-    let mut func = compile_func(&start_func, global_scope, global_entries)?;
-    // remove the last 'ret'
-    func.ins.pop();
-    Ok(func)
-}
-
-macro_rules! check_type_eq {
-    ($lhs:expr, $rhs:expr, $span:expr) => {
-        if $lhs != $rhs {
-            return Err(CompileError(
-                CompileErrorKind::TypeMismatch {
-                    expected: $lhs.to_string(),
-                    got: Some($rhs.to_string()),
-                },
-                Some($span),
-            ));
+    start_func: &hir::Func,
+    global_entries: &mut GlobalEntries,
+    options: &CompileOptions,
+    errors: &ErrorSink,
+) -> s0::FnDef {
+    let mut func = FuncCodegen::new(start_func, global_entries, options, errors).compile();
+    // `_start` ends by running off the end of its code instead of returning
+    if func.ins.last() == Some(&Op::Ret) {
+        func.ins.pop();
+    }
+    let len = func.ins.len();
+    for (idx, op) in func.ins.iter_mut().enumerate() {
+        if *op == Op::Ret {
+            *op = Op::Br((len - idx - 1) as i32);
         }
-    };
-}
-
-fn compile_func(
-    func: &FuncStmt,
-    global_scope: &mut Scope,
-    global_entries: Mut<GlobalEntries>,
-) -> CompileResult<s0::FnDef> {
-    let ret_ty = P::new(get_ty(&func.ret_ty)?);
-
-    let params = func
-        .params
-        .iter()
-        .map(|param| Ok(P::new(get_ty_nonvoid(&param.ty)?)))
-        .collect::<Result<Vec<_>, _>>()?;
-    let func_ty = FuncTy {
-        params,
-        ret: ret_ty,
-    };
-
-    global_scope.insert(func.name.name.clone(), Symbol::new(Ty::Func(func_ty), true));
-    global_entries
-        .borrow_mut()
-        .functions
-        .insert(func.name.name.clone());
-
-    let mut fc = FuncCodegen::new(func, global_scope, global_entries);
-    fc.compile()
+    }
+    func
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -294,35 +159,52 @@ enum Place {
 }
 
 struct FuncCodegen<'f> {
-    func: &'f FuncStmt,
-    global_scope: &'f Scope<'f>,
-    global_entries: Mut<GlobalEntries>,
+    func: &'f hir::Func,
+    global_entries: &'f mut GlobalEntries,
+    options: &'f CompileOptions,
+    errors: &'f ErrorSink,
     basic_blocks: Vec<BasicBlock>,
-    break_continue_positions: Vec<(BB, BB)>,
-    place_mapping: IndexMap<u64, Place>,
+    /// The block the body starts in, which self tail calls jump back to
+    start_bb: BB,
+    /// Shared block for runtime checks that fail, created on first use
+    panic_bb: Option<BB>,
+    /// The `continue` and `break` targets of the loops and switches entered
+    /// so far. Switches have no `continue` target.
+    jump_targets: IndexMap<LoopId, (Option<BB>, BB)>,
+    place_mapping: IndexMap<SymbolId, Place>,
+    /// The parameter and return slots of every function called
+    calls: Vec<(Op, u32, u32)>,
     arg_top: u32,
     loc_top: u32,
+    /// Temporary slots that were released and can be reused
+    free_temps: Vec<u32>,
 }
 
 impl<'f> FuncCodegen<'f> {
     pub fn new(
-        func: &'f FuncStmt,
-        scope: &'f Scope<'f>,
-        global_entries: Mut<GlobalEntries>,
+        func: &'f hir::Func,
+        global_entries: &'f mut GlobalEntries,
+        options: &'f CompileOptions,
+        errors: &'f ErrorSink,
     ) -> FuncCodegen<'f> {
         FuncCodegen {
             func,
-            global_scope: scope,
             global_entries,
+            options,
+            errors,
             basic_blocks: vec![],
-            break_continue_positions: vec![],
+            start_bb: 0,
+            panic_bb: None,
+            jump_targets: IndexMap::new(),
             place_mapping: IndexMap::new(),
+            calls: vec![],
             arg_top: 0,
             loc_top: 0,
+            free_temps: vec![],
         }
     }
 
-    pub fn compile(mut self) -> CompileResult<s0::FnDef> {
+    pub fn compile(self) -> s0::FnDef {
         self.compile_func()
     }
 
@@ -340,6 +222,17 @@ impl<'f> FuncCodegen<'f> {
         }
     }
 
+    fn get_panic_bb(&mut self) -> BB {
+        if let Some(bb_id) = self.panic_bb {
+            return bb_id;
+        }
+        let bb_id = self.new_bb();
+        self.append_code(bb_id, Op::Panic);
+        self.set_jump(bb_id, JumpInst::Unreachable);
+        self.panic_bb = Some(bb_id);
+        bb_id
+    }
+
     fn set_jump(&mut self, bb_id: BB, jump: JumpInst) {
         if let Some(bb) = self.basic_blocks.get_mut(bb_id) {
             if matches!(bb.jump, JumpInst::Undefined) {
@@ -352,186 +245,202 @@ impl<'f> FuncCodegen<'f> {
         }
     }
 
-    fn compile_func(mut self) -> CompileResult<s0::FnDef> {
-        let mut scope = Scope::new_with_parent(self.global_scope);
-
-        let (ret_slots, param_slots) = self.add_params(&mut scope)?;
+    fn compile_func(mut self) -> s0::FnDef {
+        let func = self.func;
+        let ret_slots = func.ret_ty.size_slot() as u32;
+        self.arg_top = ret_slots;
+        for param in &func.params {
+            self.place_mapping
+                .insert(param.id, Place::Arg(self.arg_top));
+            self.arg_top += param.ty.size_slot() as u32;
+        }
 
         let start_bb = self.new_bb();
-
-        let end_bb = self.compile_block_without_scope(&self.func.body, start_bb, &mut scope)?;
-
-        if scope.find(RET_VAL_KEY).unwrap().ty == Ty::Void {
+        self.start_bb = start_bb;
+        let end_bb = self.compile_block(&func.body, start_bb);
+        if func.ret_ty == Ty::Void {
             self.set_jump(end_bb, JumpInst::Return);
         }
 
-        let arrange = self.bb_arrange(start_bb)?;
+        // Every route must be checked to return before optimising, which may
+        // remove the ones that don't
+        let arrange = self.bb_arrange(start_bb).and_then(|arrange| {
+            if self.options.opt_level == 0 {
+                return Ok(arrange);
+            }
+            let passes = PassManager::for_level(self.options.opt_level);
+            let mut start_bb = passes.run(&mut self.basic_blocks, start_bb);
+            if self.options.opt_level >= 3 {
+                start_bb = self.optimise_ssa(start_bb, ret_slots);
+                start_bb = passes.run(&mut self.basic_blocks, start_bb);
+            }
+            self.bb_arrange(start_bb)
+        });
+        let arrange = self.errors.ok_or_report(arrange).unwrap_or_default();
 
+        // Jumps to the next block are left out, falling through to it
         let start_offset = arrange
             .iter()
-            .map(|a| (*a, &self.basic_blocks[*a]))
-            .fold((0, IndexMap::new()), |(acc, mut map), (bb_id, bb)| {
+            .enumerate()
+            .map(|(idx, a)| (*a, &self.basic_blocks[*a], arrange.get(idx + 1).copied()))
+            .fold((0, IndexMap::new()), |(acc, mut map), (bb_id, bb, next)| {
                 map.insert(bb_id, acc);
-                let acc = acc
-                    + bb.code.len()
-                    + match bb.jump {
-                        JumpInst::Undefined => 0,
-                        JumpInst::Unreachable => 0,
-                        JumpInst::Return => 1,
-                        JumpInst::Jump(_) => 1,
-                        JumpInst::JumpIf(..) => 2,
-                    };
+                let acc = acc + bb.code.len() + bb.jump.code_len(next);
                 (acc, map)
             })
             .1;
 
         let mut result_code = vec![];
-        for bb in arrange {
-            let bb = &mut self.basic_blocks[bb];
+        for (idx, bb_id) in arrange.iter().enumerate() {
+            let next = arrange.get(idx + 1).copied();
+            let bb = &mut self.basic_blocks[*bb_id];
             result_code.append(&mut bb.code);
+            let offset =
+                |target: BB, code: &Vec<Op>| start_offset[&target] as i32 - code.len() as i32 - 1;
             match bb.jump {
                 JumpInst::Return => result_code.push(Op::Ret),
-                JumpInst::Jump(id) => {
-                    result_code.push(Op::Br(
-                        start_offset[&id] as i32 - result_code.len() as i32 - 1,
-                    ));
+                JumpInst::Jump(id) if Some(id) != next => {
+                    result_code.push(Op::Br(offset(id, &result_code)));
+                }
+                JumpInst::JumpIf(bb_true, bb_false) if Some(bb_false) == next => {
+                    result_code.push(Op::BrTrue(offset(bb_true, &result_code)));
+                }
+                JumpInst::JumpIf(bb_true, bb_false) if Some(bb_true) == next => {
+                    result_code.push(Op::BrFalse(offset(bb_false, &result_code)));
                 }
                 JumpInst::JumpIf(bb_true, bb_false) => {
-                    result_code.push(Op::BrTrue(
-                        start_offset[&bb_true] as i32 - result_code.len() as i32 - 1,
-                    ));
-                    result_code.push(Op::Br(
-                        start_offset[&bb_false] as i32 - result_code.len() as i32 - 1,
-                    ));
+                    result_code.push(Op::BrTrue(offset(bb_true, &result_code)));
+                    result_code.push(Op::Br(offset(bb_false, &result_code)));
                 }
                 _ => {}
             }
         }
 
-        let name_global_id = {
-            let name_val_id = self.global_scope.get_new_id();
-            self.global_entries
-                .borrow_mut()
-                .insert_string_literal(&self.func.name.name, name_val_id)
-        };
+        let name_global_id = self
+            .global_entries
+            .insert_value(func.name.as_bytes().into());
 
-        Ok(s0::FnDef {
+        s0::FnDef {
             name: name_global_id,
-            ret_slots: ret_slots as u32,
-            param_slots: param_slots as u32,
+            ret_slots,
+            param_slots: self.arg_top - ret_slots,
             loc_slots: self.loc_top,
             ins: result_code,
-        })
+        }
     }
 
     fn bb_arrange(&self, start: BB) -> CompileResult<Vec<BB>> {
-        // dbg!(&self.basic_blocks);
         let mut arr_state = BBArranger::new(&self.basic_blocks);
 
         arr_state
             .construct_arrangement(start)
-            .with_span(self.func.name.span)?;
+            .with_span(self.func.span)?;
 
         let arrange = arr_state.arrange();
 
         Ok(arrange)
     }
 
-    fn add_params(&mut self, scope: &mut Scope) -> CompileResult<(usize, usize)> {
-        let ret_ty = get_ty(&self.func.ret_ty)?;
-        let ret_size = ret_ty.size_slot();
-        let ret_id = scope
-            .insert(RET_VAL_KEY.into(), Symbol::new(ret_ty, false))
-            .expect("Return value should be valid");
-        self.place_mapping.insert(ret_id, Place::Arg(self.arg_top));
-        self.arg_top += ret_size as u32;
-
-        for param in &self.func.params {
-            let param_ty = get_ty_nonvoid(&param.ty)?;
-            let param_size = param_ty.size_slot();
-
-            let param_id = scope
-                .insert(
-                    param.name.name.clone(),
-                    Symbol::new(param_ty, param.is_const),
-                )
-                .ok_or_else(|| CompileError {
-                    kind: CompileErrorKind::DuplicateSymbol(param.name.name.as_str().into()),
-                    span: Some(param.name.span),
-                })?;
-            self.place_mapping
-                .insert(param_id, Place::Arg(self.arg_top));
-            self.arg_top += param_size as u32;
-        }
-
-        Ok((ret_size, self.arg_top as usize - ret_size))
+    /// Lift the blocks reachable from `start` into SSA form, optimise them and
+    /// lower them back, reallocating the local slots. Returns the new start
+    /// block, which is `start` if the code can't be lifted.
+    fn optimise_ssa(&mut self, start: BB, ret_slots: u32) -> BB {
+        let frame = ssa::Frame {
+            ret_slots,
+            param_slots: self.arg_top - ret_slots,
+            loc_slots: self.loc_top,
+            calls: self.calls.clone(),
+        };
+        let mut func = match ssa::build(&self.basic_blocks, start, frame) {
+            Some(func) => func,
+            None => return start,
+        };
+        ssa::optimise(&mut func);
+        let (blocks, start, loc_slots) = func.lower();
+        self.basic_blocks = blocks;
+        self.loc_top = loc_slots;
+        start
     }
 
-    fn get_place(&self, var_id: u64) -> Option<Place> {
-        self.place_mapping.get(&var_id).cloned()
+    /// Reserve a local slot for an intermediate value, reusing a released one
+    /// if there's any
+    fn new_temp_slot(&mut self) -> u32 {
+        if let Some(slot) = self.free_temps.pop() {
+            return slot;
+        }
+        let slot = self.loc_top;
+        self.loc_top += 1;
+        slot
     }
 
-    fn compile_block(
-        &mut self,
-        blk: &ast::BlockStmt,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<BB> {
-        let mut block_scope = Scope::new_with_parent(scope);
-        self.compile_block_without_scope(blk, bb_id, &mut block_scope)
+    /// Allow `slot` to be reused once the code reading it has been emitted
+    fn release_temp_slot(&mut self, slot: u32) {
+        self.free_temps.push(slot);
     }
 
-    fn compile_block_without_scope(
-        &mut self,
-        blk: &ast::BlockStmt,
-        bb_id: BB,
-        scope: &mut Scope,
-    ) -> CompileResult<BB> {
-        let mut cur_bb_id = bb_id;
+    fn compile_block(&mut self, blk: &hir::Block, bb_id: BB) -> BB {
+        let mut bb_id = bb_id;
         for stmt in &blk.stmts {
-            cur_bb_id = self.compile_stmt(stmt, cur_bb_id, scope)?;
+            bb_id = self.compile_stmt(stmt, bb_id);
         }
-        Ok(cur_bb_id)
+        bb_id
     }
 
-    fn compile_stmt(
-        &mut self,
-        stmt: &ast::Stmt,
-        bb_id: BB,
-        scope: &mut Scope,
-    ) -> CompileResult<BB> {
-        match stmt {
-            ast::Stmt::Block(blk) => self.compile_block(blk, bb_id, scope),
-            ast::Stmt::While(stmt) => self.compile_while(stmt, bb_id, scope),
-            ast::Stmt::If(stmt) => self.compile_if(stmt, bb_id, scope),
-            ast::Stmt::Expr(expr) => self.compile_expr_stmt(expr, bb_id, scope),
-            ast::Stmt::Decl(stmt) => self.compile_decl(stmt, bb_id, scope),
-            ast::Stmt::Return(stmt) => self.compile_return(stmt, bb_id, scope),
-            ast::Stmt::Break(span) => self.compile_break(*span, bb_id, scope),
-            ast::Stmt::Continue(span) => self.compile_continue(*span, bb_id, scope),
-            ast::Stmt::Empty(_span) => Ok(bb_id),
+    fn compile_stmt(&mut self, stmt: &hir::Stmt, bb_id: BB) -> BB {
+        match &stmt.kind {
+            hir::StmtKind::Block(blk) => self.compile_block(blk, bb_id),
+            hir::StmtKind::While { id, cond, body } => self.compile_while(*id, cond, body, bb_id),
+            hir::StmtKind::For {
+                id,
+                init,
+                cond,
+                step,
+                body,
+            } => self.compile_for(
+                *id,
+                init.as_deref(),
+                cond.as_ref(),
+                step.as_ref(),
+                body,
+                bb_id,
+            ),
+            hir::StmtKind::DoWhile { id, body, cond } => {
+                self.compile_do_while(*id, body, cond, bb_id)
+            }
+            hir::StmtKind::If {
+                cond,
+                if_block,
+                else_block,
+            } => self.compile_if(cond, if_block, else_block.as_ref(), bb_id),
+            hir::StmtKind::Switch { id, expr, cases } => {
+                self.compile_switch(*id, expr, cases, bb_id)
+            }
+            hir::StmtKind::Expr(expr) => self.compile_expr_stmt(expr, bb_id),
+            hir::StmtKind::Decl { var, init } => self.compile_decl(var, init.as_ref(), bb_id),
+            hir::StmtKind::Return(val) => self.compile_return(val.as_ref(), bb_id),
+            hir::StmtKind::Break(id) => {
+                let (_, break_target) = self.jump_targets[id];
+                self.set_jump(bb_id, JumpInst::Jump(break_target));
+                self.new_bb()
+            }
+            hir::StmtKind::Continue(id) => {
+                let continue_target = self.jump_targets[id].0.expect("Continue of a loop");
+                self.set_jump(bb_id, JumpInst::Jump(continue_target));
+                self.new_bb()
+            }
         }
     }
 
-    fn compile_expr_stmt(
-        &mut self,
-        expr: &ast::Expr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<BB> {
-        let ty = self.compile_expr(expr, bb_id, scope)?;
-        if ty.size_slot() > 0 {
-            self.append_code(bb_id, Op::PopN(ty.size_slot() as u32));
+    fn compile_expr_stmt(&mut self, expr: &hir::Expr, bb_id: BB) -> BB {
+        let mut bb_id = bb_id;
+        self.compile_expr(expr, &mut bb_id);
+        if expr.ty.size_slot() > 0 {
+            self.append_code(bb_id, Op::PopN(expr.ty.size_slot() as u32));
         }
-        Ok(bb_id)
+        bb_id
     }
 
-    fn compile_while(
-        &mut self,
-        stmt: &ast::WhileStmt,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<BB> {
+    fn compile_while(&mut self, id: LoopId, cond: &hir::Expr, body: &hir::Block, bb_id: BB) -> BB {
         /*
          * break -v      v----------------------------------\  v- continue
          * begin --> [bb:A cond] -true--> [bb:B body bb:C] -/  /--> [bb:D next]
@@ -542,21 +451,106 @@ impl<'f> FuncCodegen<'f> {
         let body_bb = self.new_bb();
         let next_bb = self.new_bb();
 
-        self.break_continue_positions.push((cond_bb, next_bb));
+        self.jump_targets.insert(id, (Some(cond_bb), next_bb));
 
-        self.compile_expr(stmt.cond.as_ref(), cond_bb, scope)?;
+        let mut cond_end_bb = cond_bb;
+        self.compile_expr(cond, &mut cond_end_bb);
         self.set_jump(bb_id, JumpInst::Jump(cond_bb));
-        self.set_jump(cond_bb, JumpInst::JumpIf(body_bb, next_bb));
+        self.set_jump(cond_end_bb, JumpInst::JumpIf(body_bb, next_bb));
 
-        let body_end_bb = self.compile_block(stmt.body.as_ref(), body_bb, scope)?;
+        let body_end_bb = self.compile_block(body, body_bb);
         self.set_jump(body_end_bb, JumpInst::Jump(cond_bb));
 
-        self.break_continue_positions.pop();
+        next_bb
+    }
+
+    fn compile_for(
+        &mut self,
+        id: LoopId,
+        init: Option<&hir::Stmt>,
+        cond: Option<&hir::Expr>,
+        step: Option<&hir::Expr>,
+        body: &hir::Block,
+        bb_id: BB,
+    ) -> BB {
+        /*
+         *                   v------------------------------------------------\
+         * begin [init] --> [bb:A cond] -true--> [bb:B body] --> [bb:C step] -/
+         *                      \-false--> [bb:D next]
+         *
+         * continue -> C, break -> D
+         */
+
+        let bb_id = match init {
+            Some(init) => self.compile_stmt(init, bb_id),
+            None => bb_id,
+        };
+
+        let cond_bb = self.new_bb();
+        let body_bb = self.new_bb();
+        let step_bb = self.new_bb();
+        let next_bb = self.new_bb();
 
-        Ok(next_bb)
+        self.set_jump(bb_id, JumpInst::Jump(cond_bb));
+        if let Some(cond) = cond {
+            let mut cond_end_bb = cond_bb;
+            self.compile_expr(cond, &mut cond_end_bb);
+            self.set_jump(cond_end_bb, JumpInst::JumpIf(body_bb, next_bb));
+        } else {
+            self.set_jump(cond_bb, JumpInst::Jump(body_bb));
+        }
+
+        self.jump_targets.insert(id, (Some(step_bb), next_bb));
+        let body_end_bb = self.compile_block(body, body_bb);
+        self.set_jump(body_end_bb, JumpInst::Jump(step_bb));
+
+        let step_end_bb = match step {
+            Some(step) => self.compile_expr_stmt(step, step_bb),
+            None => step_bb,
+        };
+        self.set_jump(step_end_bb, JumpInst::Jump(cond_bb));
+
+        next_bb
     }
 
-    fn compile_if(&mut self, stmt: &ast::IfStmt, bb_id: BB, scope: &Scope) -> CompileResult<BB> {
+    fn compile_do_while(
+        &mut self,
+        id: LoopId,
+        body: &hir::Block,
+        cond: &hir::Expr,
+        bb_id: BB,
+    ) -> BB {
+        /*
+         *          v------------------------------------\
+         * begin --> [bb:A body] --> [bb:B cond] -true----/
+         *                               \-false--> [bb:C next]
+         *
+         * continue -> B, break -> C
+         */
+        let body_bb = self.new_bb();
+        let cond_bb = self.new_bb();
+        let next_bb = self.new_bb();
+
+        self.set_jump(bb_id, JumpInst::Jump(body_bb));
+
+        self.jump_targets.insert(id, (Some(cond_bb), next_bb));
+        let body_end_bb = self.compile_block(body, body_bb);
+        self.set_jump(body_end_bb, JumpInst::Jump(cond_bb));
+
+        let mut cond_end_bb = cond_bb;
+        self.compile_expr(cond, &mut cond_end_bb);
+        self.set_jump(cond_end_bb, JumpInst::JumpIf(body_bb, next_bb));
+
+        next_bb
+    }
+
+    fn compile_if(
+        &mut self,
+        cond: &hir::Expr,
+        if_block: &hir::Block,
+        else_block: Option<&hir::Block>,
+        bb_id: BB,
+    ) -> BB {
         /*
          * begin --> [bb:A1 cond] -true--> [bb:B1 body] -\
          *           |false                              |
@@ -569,459 +563,460 @@ impl<'f> FuncCodegen<'f> {
          */
         let end_bb = self.new_bb();
 
-        self.compile_expr(stmt.cond.as_ref(), bb_id, scope)?;
+        let mut bb_id = bb_id;
+        self.compile_expr(cond, &mut bb_id);
 
         let bb_true = self.new_bb();
-        let bb_true_end = self.compile_block(stmt.if_block.as_ref(), bb_true, scope)?;
+        let bb_false = match else_block {
+            None => end_bb,
+            Some(_) => self.new_bb(),
+        };
+        self.set_jump(bb_id, JumpInst::JumpIf(bb_true, bb_false));
 
+        let bb_true_end = self.compile_block(if_block, bb_true);
         // body -> end
         self.set_jump(bb_true_end, JumpInst::Jump(end_bb));
 
-        let bb_false = match &stmt.else_block {
-            ast::IfElseBlock::None => end_bb,
-            ast::IfElseBlock::If(stmt) => {
-                let else_bb = self.new_bb();
-                let else_end = self.compile_if(stmt.as_ref(), else_bb, scope)?;
-                self.set_jump(else_end, JumpInst::Jump(end_bb));
-                else_bb
-            }
-            ast::IfElseBlock::Block(block) => {
-                let else_bb = self.new_bb();
-                let else_end = self.compile_block(block.as_ref(), else_bb, scope)?;
-                self.set_jump(else_end, JumpInst::Jump(end_bb));
-                else_bb
-            }
-        };
-
-        self.set_jump(bb_id, JumpInst::JumpIf(bb_true, bb_false));
+        if let Some(else_block) = else_block {
+            let else_end = self.compile_block(else_block, bb_false);
+            self.set_jump(else_end, JumpInst::Jump(end_bb));
+        }
 
-        Ok(end_bb)
+        end_bb
     }
 
-    fn compile_decl(
+    fn compile_switch(
         &mut self,
-        stmt: &ast::DeclStmt,
+        id: LoopId,
+        expr: &hir::Expr,
+        cases: &[hir::SwitchCase],
         bb_id: BB,
-        scope: &mut Scope,
-    ) -> CompileResult<BB> {
-        let (val_id, ty) = add_decl_scope(stmt, scope)?;
-        // add value to stack
-        self.place_mapping.insert(val_id, Place::Loc(self.loc_top));
-        let var_size = ty.size_slot();
-        self.loc_top += var_size as u32;
-
-        if let Some(val) = stmt.val.clone() {
-            let assign_expr = ast::AssignExpr {
-                span: Span::default(),
-                allow_assign_const: true,
-                lhs: P::new(ast::Expr::Ident(stmt.name.clone())),
-                rhs: val,
-            };
-            self.compile_assign_expr(&assign_expr, bb_id, scope)?;
+    ) -> BB {
+        /*
+         * begin [tmp = expr] --> [dispatch] -+-> [case 1]
+         *                                    |       |
+         *                                    +-> [case 2]
+         *                                    |       |
+         *                                    \-> [default] --> [next]
+         *
+         * break -> next
+         */
+        let mut bb_id = bb_id;
+        let slot = self.new_temp_slot();
+        self.append_code(bb_id, Op::LocA(slot));
+        self.compile_expr(expr, &mut bb_id);
+        self.append_code(bb_id, Op::Store64);
+
+        let next_bb = self.new_bb();
+        let case_bbs = cases.iter().map(|_| self.new_bb()).collect::<Vec<_>>();
+        let mut targets = vec![];
+        let mut default_bb = next_bb;
+        for (case, case_bb) in cases.iter().zip(&case_bbs) {
+            targets.extend(case.values.iter().map(|value| (*value, *case_bb)));
+            if case.is_default {
+                default_bb = *case_bb;
+            }
+        }
+        targets.sort_by_key(|(value, _)| *value);
+        self.compile_switch_dispatch(slot, &targets, default_bb, bb_id);
+        // the cases never read the scrutinee again
+        self.release_temp_slot(slot);
+
+        self.jump_targets.insert(id, (None, next_bb));
+        // each case falls through into the one after it
+        for (idx, case) in cases.iter().enumerate() {
+            let case_end_bb = self.compile_block(&case.body, case_bbs[idx]);
+            let fall_bb = case_bbs.get(idx + 1).copied().unwrap_or(next_bb);
+            self.set_jump(case_end_bb, JumpInst::Jump(fall_bb));
         }
-        Ok(bb_id)
+
+        next_bb
     }
 
-    fn compile_return(
+    /// Jump from `bb_id` to the block in `targets` whose value equals the
+    /// value in local `slot`, or to `default_bb` if there's none. `targets`
+    /// must be sorted by value.
+    fn compile_switch_dispatch(
         &mut self,
-        stmt: &ast::ReturnStmt,
+        slot: u32,
+        targets: &[(i64, BB)],
+        default_bb: BB,
         bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<BB> {
-        let func_ty = self
-            .global_scope
-            .find(&self.func.name.name)
-            .expect("Function type")
-            .ty
-            .get_func()
-            .unwrap();
-        let ret_ty = &*func_ty.ret;
-
-        if matches!(ret_ty, Ty::Void) {
-            // void return
-            if stmt.val.is_some() {
-                return Err(CompileError {
-                    kind: CompileErrorKind::TypeMismatch {
-                        expected: "void".into(),
-                        got: None,
-                    },
-                    span: Some(stmt.span),
-                });
+    ) {
+        if targets.len() <= SWITCH_LINEAR_CASES {
+            let mut bb_id = bb_id;
+            for (value, target) in targets {
+                let next_bb = self.new_bb();
+                for code in &[
+                    Op::LocA(slot),
+                    Op::Load64,
+                    Op::Push(*value as u64),
+                    Op::CmpI,
+                ] {
+                    self.append_code(bb_id, *code);
+                }
+                // the comparison is zero only when they're equal
+                self.set_jump(bb_id, JumpInst::JumpIf(next_bb, *target));
+                bb_id = next_bb;
             }
+            self.set_jump(bb_id, JumpInst::Jump(default_bb));
         } else {
-            // non-void return
-            if stmt.val.is_none() {
-                return Err(CompileError {
-                    kind: CompileErrorKind::TypeMismatch {
-                        expected: ret_ty.to_string(),
-                        got: Some(Ty::Void.to_string()),
-                    },
-                    span: Some(stmt.span),
-                });
-            } else {
-                let ret_id = scope.find(RET_VAL_KEY).unwrap().id;
-                let offset = self.get_place(ret_id).unwrap();
-
-                self.append_code(bb_id, op_load_address(offset));
-                let ty = self.compile_expr(stmt.val.as_deref().unwrap(), bb_id, scope)?;
-                if ty != *ret_ty {
-                    return Err(CompileError(
-                        CompileErrorKind::TypeMismatch {
-                            expected: ret_ty.to_string(),
-                            got: ty.to_string().into(),
-                        },
-                        Some(stmt.span),
-                    ));
-                }
-                self.append_code(bb_id, store_ty(ret_ty));
+            let (lower, upper) = targets.split_at(targets.len() / 2);
+            let lower_bb = self.new_bb();
+            let upper_bb = self.new_bb();
+            for code in &[
+                Op::LocA(slot),
+                Op::Load64,
+                Op::Push(upper[0].0 as u64),
+                Op::CmpI,
+                Op::SetLt,
+            ] {
+                self.append_code(bb_id, *code);
             }
+            self.set_jump(bb_id, JumpInst::JumpIf(lower_bb, upper_bb));
+            self.compile_switch_dispatch(slot, lower, default_bb, lower_bb);
+            self.compile_switch_dispatch(slot, upper, default_bb, upper_bb);
         }
-
-        self.set_jump(bb_id, JumpInst::Return);
-        Ok(self.new_bb())
     }
 
-    fn compile_break(&mut self, span: Span, bb_id: BB, _scope: &Scope) -> CompileResult<BB> {
-        let (_, next) = self
-            .break_continue_positions
-            .last()
-            .ok_or_else(|| CompileError(CompileErrorKind::NoBreakContext, Some(span)))?;
-        let break_target = *next;
-        self.set_jump(bb_id, JumpInst::Jump(break_target));
-        Ok(self.new_bb())
-    }
-
-    fn compile_continue(&mut self, span: Span, bb_id: BB, _scope: &Scope) -> CompileResult<BB> {
-        let (next, _) = self
-            .break_continue_positions
-            .last()
-            .ok_or_else(|| CompileError(CompileErrorKind::NoContinueContext, Some(span)))?;
-        let continue_target = *next;
-        self.set_jump(bb_id, JumpInst::Jump(continue_target));
-        Ok(self.new_bb())
-    }
+    fn compile_decl(&mut self, var: &hir::Var, init: Option<&hir::Expr>, bb_id: BB) -> BB {
+        let place = Place::Loc(self.loc_top);
+        self.place_mapping.insert(var.id, place);
+        self.loc_top += var.ty.size_slot() as u32;
 
-    fn compile_expr(&mut self, expr: &ast::Expr, bb_id: BB, scope: &Scope) -> CompileResult<Ty> {
-        match expr {
-            ast::Expr::Ident(expr) => self.compile_ident_expr(expr, bb_id, scope),
-            ast::Expr::Assign(expr) => self.compile_assign_expr(expr, bb_id, scope),
-            ast::Expr::As(expr) => self.compile_as_expr(expr, bb_id, scope),
-            ast::Expr::Literal(expr) => self.compile_literal_expr(expr, bb_id, scope),
-            ast::Expr::Unary(expr) => self.compile_unary_expr(expr, bb_id, scope),
-            ast::Expr::Binary(expr) => self.compile_binary_expr(expr, bb_id, scope),
-            ast::Expr::Call(expr) => self.compile_call_expr(expr, bb_id, scope),
+        let mut bb_id = bb_id;
+        if let Some(init) = init {
+            self.append_code(bb_id, op_load_address(place));
+            self.compile_expr(init, &mut bb_id);
+            self.append_code(bb_id, store_ty(&var.ty));
         }
+        bb_id
     }
 
-    fn get_l_value_addr(
-        &mut self,
-        expr: &ast::Expr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<(Ty, bool)> {
-        match expr {
-            ast::Expr::Ident(i) => self.gen_ident_addr(i, bb_id, scope),
-            _ => Err(CompileError(CompileErrorKind::NotLValue, Some(expr.span()))),
+    fn compile_return(&mut self, val: Option<&hir::Expr>, bb_id: BB) -> BB {
+        let mut bb_id = bb_id;
+        if let Some(hir::Expr {
+            kind: hir::ExprKind::Call { func, args },
+            ..
+        }) = val
+        {
+            if func.id == self.func.id && self.options.opt_level >= 1 {
+                return self.compile_tail_call(args, bb_id);
+            }
         }
-    }
-
-    fn gen_ident_addr(
-        &mut self,
-        i: &ast::Ident,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<(Ty, bool)> {
-        let (sym, is_global) = scope.find_is_global(&i.name).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::NoSuchSymbol(i.name.to_string()),
-                Some(i.span),
-            )
-        })?;
-
-        if is_global {
-            let global_val_id = self
-                .global_entries
-                .borrow()
-                .value_id(sym.id)
-                .expect("Reference to non-existent global value");
-
-            self.append_code(bb_id, Op::GlobA(global_val_id));
-        } else {
-            let var_id = sym.id;
-            self.append_code(bb_id, op_load_address(self.get_place(var_id).unwrap()));
+        if let Some(val) = val {
+            // the return value is the first argument slot
+            self.append_code(bb_id, op_load_address(Place::Arg(0)));
+            self.compile_expr(val, &mut bb_id);
+            self.append_code(bb_id, store_ty(&self.func.ret_ty));
         }
-        Ok((sym.ty.clone(), sym.is_const))
+
+        self.set_jump(bb_id, JumpInst::Return);
+        self.new_bb()
     }
 
-    fn compile_assign_expr(
-        &mut self,
-        expr: &ast::AssignExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let (lhs_ty, is_const) = self.get_l_value_addr(expr.lhs.as_ref(), bb_id, scope)?;
-        let rhs_ty = self.compile_expr(expr.rhs.as_ref(), bb_id, scope)?;
-
-        check_type_eq!(lhs_ty, rhs_ty, expr.rhs.span());
-        if !expr.allow_assign_const && is_const {
-            return Err(CompileError(
-                CompileErrorKind::AssignToConst,
-                Some(expr.lhs.span()),
-            ));
+    /// Compile `return f(args);` inside `f` itself by passing `args` in place
+    /// of the current arguments and starting over, so that the recursion runs
+    /// in a constant amount of stack
+    fn compile_tail_call(&mut self, args: &[hir::Expr], bb_id: BB) -> BB {
+        let mut bb_id = bb_id;
+        // every argument is evaluated before any parameter is overwritten
+        for (param, arg) in self.func.params.iter().zip(args) {
+            self.append_code(bb_id, op_load_address(self.place_mapping[&param.id]));
+            self.compile_expr(arg, &mut bb_id);
+        }
+        for param in self.func.params.iter().rev() {
+            self.append_code(bb_id, store_ty(&param.ty));
         }
 
-        self.append_code(bb_id, store_ty(&lhs_ty));
-
-        Ok(Ty::Void)
+        self.set_jump(bb_id, JumpInst::Jump(self.start_bb));
+        self.new_bb()
     }
 
-    fn compile_binary_expr(
-        &mut self,
-        expr: &ast::BinaryExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let lhs_ty = self.compile_expr(expr.lhs.as_ref(), bb_id, scope)?;
-        let rhs_ty = self.compile_expr(expr.rhs.as_ref(), bb_id, scope)?;
-
-        check_type_eq!(lhs_ty, rhs_ty, expr.rhs.span());
-
-        let code = binary_op_op(expr.op, &lhs_ty).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
-                Some(expr.rhs.span()),
-            )
-        })?;
-
-        for code in code {
-            self.append_code(bb_id, *code);
+    fn compile_expr(&mut self, expr: &hir::Expr, bb_id: &mut BB) {
+        match &expr.kind {
+            hir::ExprKind::Int(i) => self.append_code(*bb_id, Op::Push(*i)),
+            hir::ExprKind::Float(f) => self.append_code(*bb_id, Op::Push(f.to_bits())),
+            hir::ExprKind::Bool(b) => self.append_code(*bb_id, Op::Push(*b as u64)),
+            hir::ExprKind::Str(s) => {
+                let glob_id = self.global_entries.insert_value(s.as_bytes().into());
+                self.append_code(*bb_id, Op::Push(glob_id as u64));
+            }
+            hir::ExprKind::Load(place) => {
+                self.compile_place_addr(place, bb_id);
+                self.append_code(*bb_id, load_ty(&place.ty));
+            }
+            hir::ExprKind::Assign { place, op, rhs } => {
+                self.compile_place_addr(place, bb_id);
+                if let Some(op) = op {
+                    // `lhs op= rhs` keeps the address of `lhs` below its
+                    // value, so `lhs` is only evaluated once
+                    self.append_code(*bb_id, Op::Dup);
+                    self.append_code(*bb_id, load_ty(&place.ty));
+                    self.compile_compound_op(*op, &place.ty, rhs, bb_id);
+                } else {
+                    self.compile_expr(rhs, bb_id);
+                }
+                self.append_code(*bb_id, store_ty(&place.ty));
+            }
+            hir::ExprKind::Unary { op, expr } => {
+                self.compile_expr(expr, bb_id);
+                let code = unary_op_op(*op, &expr.ty).expect("Checked when lowering");
+                for code in code {
+                    self.append_code(*bb_id, *code);
+                }
+            }
+            hir::ExprKind::Binary { op, lhs, rhs } => match op {
+                ast::BinaryOp::And | ast::BinaryOp::Or => {
+                    self.compile_logical_expr(*op, lhs, rhs, bb_id)
+                }
+                ast::BinaryOp::Mod => self.compile_mod_expr(lhs, rhs, bb_id),
+                _ => {
+                    self.compile_expr(lhs, bb_id);
+                    self.normalise_bool(&lhs.ty, *bb_id);
+                    self.compile_expr(rhs, bb_id);
+                    self.normalise_bool(&rhs.ty, *bb_id);
+                    let code = binary_op_op(*op, &lhs.ty).expect("Checked when lowering");
+                    for code in code {
+                        self.append_code(*bb_id, *code);
+                    }
+                }
+            },
+            hir::ExprKind::Cond {
+                cond,
+                if_val,
+                else_val,
+            } => self.compile_cond_expr(cond, if_val, else_val, bb_id),
+            hir::ExprKind::Cast(val) => {
+                self.compile_expr(val, bb_id);
+                let code = as_expr_op(&val.ty, &expr.ty).expect("Checked when lowering");
+                for code in code {
+                    self.append_code(*bb_id, *code);
+                }
+            }
+            hir::ExprKind::Call { func, args } => {
+                self.append_code(*bb_id, Op::StackAlloc(expr.ty.size_slot() as u32));
+                for arg in args {
+                    self.compile_expr(arg, bb_id);
+                }
+                // library functions are called by name
+                let func_id = self.global_entries.function_id(&func.name);
+                let call = if let Some(id) = func_id {
+                    Op::Call(id)
+                } else {
+                    let glob_id = self
+                        .global_entries
+                        .insert_value(func.name.as_bytes().into());
+                    Op::CallName(glob_id)
+                };
+                self.append_code(*bb_id, call);
+                if !self.calls.iter().any(|(op, ..)| *op == call) {
+                    let param_slots = args.iter().map(|arg| arg.ty.size_slot() as u32).sum();
+                    self.calls
+                        .push((call, param_slots, expr.ty.size_slot() as u32));
+                }
+            }
+            hir::ExprKind::Error => unreachable!("Code is only generated without errors"),
         }
-
-        let result_ty = binary_op_result_ty(expr.op, &lhs_ty).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
-                Some(expr.rhs.span()),
-            )
-        })?;
-
-        Ok(result_ty)
     }
 
-    fn compile_unary_expr(
-        &mut self,
-        expr: &ast::UnaryExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let lhs_ty = self.compile_expr(expr.expr.as_ref(), bb_id, scope)?;
-
-        let code = unary_op_op(expr.op, &lhs_ty).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
-                Some(expr.expr.span()),
-            )
-        })?;
+    /// Push the address of `place`
+    fn compile_place_addr(&mut self, place: &hir::Place, bb_id: &mut BB) {
+        match &place.kind {
+            hir::PlaceKind::Var(var) if var.is_global => {
+                let global_val_id = *self
+                    .global_entries
+                    .global_vars
+                    .get(&var.id)
+                    .expect("Reference to non-existent global value");
+                self.append_code(*bb_id, Op::GlobA(global_val_id));
+            }
+            hir::PlaceKind::Var(var) => {
+                self.append_code(*bb_id, op_load_address(self.place_mapping[&var.id]));
+            }
+            hir::PlaceKind::Index { base, index } => {
+                self.compile_place_addr(base, bb_id);
+                self.compile_expr(index, bb_id);
+
+                if self.options.bounds_check {
+                    // negative indices are rejected too, since they compare as
+                    // unsigned
+                    let len = base.ty.get_array().expect("Indexing an array").len;
+                    let ok_bb = self.new_bb();
+                    let panic_bb = self.get_panic_bb();
+                    self.append_code(*bb_id, Op::Dup);
+                    self.append_code(*bb_id, Op::Push(len as u64));
+                    self.append_code(*bb_id, Op::CmpU);
+                    self.append_code(*bb_id, Op::SetLt);
+                    self.set_jump(*bb_id, JumpInst::JumpIf(ok_bb, panic_bb));
+                    *bb_id = ok_bb;
+                }
 
-        for code in code {
-            self.append_code(bb_id, *code);
+                self.append_code(*bb_id, Op::Push(place.ty.size_slot() as u64 * 8));
+                self.append_code(*bb_id, Op::MulI);
+                self.append_code(*bb_id, Op::AddI);
+            }
+            hir::PlaceKind::Field { base, offset, .. } => {
+                self.compile_place_addr(base, bb_id);
+                if *offset != 0 {
+                    self.append_code(*bb_id, Op::Push(*offset as u64 * 8));
+                    self.append_code(*bb_id, Op::AddI);
+                }
+            }
         }
-
-        let result_ty = unary_op_result_ty(expr.op, &lhs_ty).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
-                Some(expr.expr.span()),
-            )
-        })?;
-
-        Ok(result_ty)
     }
 
-    fn compile_as_expr(
+    /// Compile `rhs` and apply `op` to it and the value of type `lhs_ty` on
+    /// top of the stack, for a compound assignment
+    fn compile_compound_op(
         &mut self,
-        expr: &ast::AsExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let lhs_ty = self.compile_expr(expr.val.as_ref(), bb_id, scope)?;
-        let rhs_ty = get_ty_nonvoid(&expr.ty)?;
-
-        let code = as_expr_op(&lhs_ty, &rhs_ty).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
-                Some(expr.ty.span),
-            )
-        })?;
+        op: ast::BinaryOp,
+        lhs_ty: &Ty,
+        rhs: &hir::Expr,
+        bb_id: &mut BB,
+    ) {
+        if let ast::BinaryOp::Mod = op {
+            // same as `compile_mod_expr`, but the left operand is already on
+            // the stack
+            let rhs_slot = self.new_temp_slot();
+            self.append_code(*bb_id, Op::Dup);
+            self.append_code(*bb_id, Op::LocA(rhs_slot));
+            self.compile_expr(rhs, bb_id);
+            self.append_code(*bb_id, Op::Store64);
+            for code in &[
+                Op::LocA(rhs_slot),
+                Op::Load64,
+                Op::DivI,
+                Op::LocA(rhs_slot),
+                Op::Load64,
+                Op::MulI,
+                Op::SubI,
+            ] {
+                self.append_code(*bb_id, *code);
+            }
+            self.release_temp_slot(rhs_slot);
+            return;
+        }
 
+        self.compile_expr(rhs, bb_id);
+        let code = binary_op_op(op, lhs_ty).expect("Checked when lowering");
         for code in code {
-            self.append_code(bb_id, *code);
+            self.append_code(*bb_id, *code);
         }
+    }
 
-        Ok(rhs_ty)
+    /// Turn the value of type `ty` on top of the stack into 0 or 1 if it's a
+    /// bool, before an operator that works on its bits. Comparisons can leave
+    /// other truthy values, such as -1 from `CmpI`.
+    fn normalise_bool(&mut self, ty: &Ty, bb_id: BB) {
+        if *ty == Ty::Bool {
+            self.append_code(bb_id, Op::Not);
+            self.append_code(bb_id, Op::Not);
+        }
     }
 
-    fn compile_literal_expr(
-        &mut self,
-        expr: &ast::LiteralExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        match &expr.kind {
-            ast::LiteralKind::Integer(i) => {
-                self.append_code(bb_id, Op::Push(*i));
-                Ok(Ty::Int)
-            }
-            ast::LiteralKind::Float(f) => {
-                self.append_code(bb_id, Op::Push(unsafe { std::mem::transmute_copy(f) }));
-                Ok(Ty::Double)
-            }
-            ast::LiteralKind::String(s) => {
-                let val_id = scope.get_new_id();
-                let glob_id = self
-                    .global_entries
-                    .borrow_mut()
-                    .insert_string_literal(s, val_id);
-                self.append_code(bb_id, Op::Push(glob_id as u64));
-                Ok(Ty::Int)
-            }
-            ast::LiteralKind::Char(c) => {
-                self.append_code(bb_id, Op::Push(*c as u64));
-                Ok(Ty::Int)
-            }
+    fn compile_mod_expr(&mut self, lhs: &hir::Expr, rhs: &hir::Expr, bb_id: &mut BB) {
+        // There's no remainder instruction, so `lhs % rhs` is lowered into
+        // `lhs - lhs / rhs * rhs`, with both operands stored in temporaries.
+        let lhs_slot = self.new_temp_slot();
+        let rhs_slot = self.new_temp_slot();
+
+        self.append_code(*bb_id, Op::LocA(lhs_slot));
+        self.compile_expr(lhs, bb_id);
+        self.append_code(*bb_id, Op::Store64);
+
+        self.append_code(*bb_id, Op::LocA(rhs_slot));
+        self.compile_expr(rhs, bb_id);
+        self.append_code(*bb_id, Op::Store64);
+
+        for code in &[
+            Op::LocA(lhs_slot),
+            Op::Load64,
+            Op::LocA(lhs_slot),
+            Op::Load64,
+            Op::LocA(rhs_slot),
+            Op::Load64,
+            Op::DivI,
+            Op::LocA(rhs_slot),
+            Op::Load64,
+            Op::MulI,
+            Op::SubI,
+        ] {
+            self.append_code(*bb_id, *code);
         }
+        self.release_temp_slot(rhs_slot);
+        self.release_temp_slot(lhs_slot);
     }
 
-    fn compile_call_expr(
+    fn compile_logical_expr(
         &mut self,
-        expr: &ast::CallExpr,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let mut expr_tys = vec![];
-
-        let func_name = &expr.func.name;
-        let func_sig = self.global_scope.find(&func_name).ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::NoSuchSymbol(func_name.to_string()),
-                Some(expr.func.span),
-            )
-        })?;
-
-        let func_ty = func_sig.ty.get_func().ok_or_else(|| {
-            CompileError(
-                CompileErrorKind::TypeMismatch {
-                    expected: "function".into(),
-                    got: Some(func_sig.ty.to_string()),
-                },
-                Some(expr.func.span),
-            )
-        })?;
-
-        self.append_code(bb_id, Op::StackAlloc(func_ty.ret.size_slot() as u32));
-
-        for sub in &expr.params {
-            let ty = self.compile_expr(sub, bb_id, scope)?;
-            expr_tys.push(ty);
-        }
+        op: ast::BinaryOp,
+        lhs: &hir::Expr,
+        rhs: &hir::Expr,
+        bb_id: &mut BB,
+    ) {
+        /*
+         * lhs && rhs:
+         *
+         * begin --> [lhs] -true--> [bb:A rhs] -----> [bb:C end]
+         *             \-false--> [bb:B push 0] --/
+         *
+         * lhs || rhs jumps to B when lhs is true and pushes 1 instead.
+         */
+        self.compile_expr(lhs, bb_id);
 
-        if expr_tys.len() != func_ty.params.len() {
-            return Err(CompileError(
-                CompileErrorKind::FuncParamSizeMismatch(expr_tys.len(), func_ty.params.len()),
-                Some(expr.span),
-            ));
-        }
+        let rhs_bb = self.new_bb();
+        let short_bb = self.new_bb();
+        let end_bb = self.new_bb();
 
-        for ((expr_ty, param_ty), expr_span) in expr_tys
-            .iter()
-            .zip(func_ty.params.iter().map(|x| x.as_ref()))
-            .zip(expr.params.iter().map(|x| x.span()))
-        {
-            check_type_eq!(expr_ty, param_ty, expr_span);
-        }
+        let (jump, short_val) = match op {
+            ast::BinaryOp::And => (JumpInst::JumpIf(rhs_bb, short_bb), 0),
+            ast::BinaryOp::Or => (JumpInst::JumpIf(short_bb, rhs_bb), 1),
+            _ => unreachable!("Only logical operators are compiled here"),
+        };
+        self.set_jump(*bb_id, jump);
 
-        let ret_ty = func_ty.ret.as_ref().clone();
+        self.append_code(short_bb, Op::Push(short_val));
+        self.set_jump(short_bb, JumpInst::Jump(end_bb));
 
-        let func_id = self.global_entries.borrow().function_id(func_name);
-        if let Some(id) = func_id {
-            self.append_code(bb_id, Op::Call(id));
-        } else {
-            let val_id = scope.get_new_id();
-            let glob_id = self
-                .global_entries
-                .borrow_mut()
-                .insert_string_literal(func_name, val_id);
-            self.append_code(bb_id, Op::CallName(glob_id));
+        let mut rhs_end_bb = rhs_bb;
+        self.compile_expr(rhs, &mut rhs_end_bb);
+        if rhs.ty != Ty::Bool {
+            // normalize integers to 0 or 1
+            self.append_code(rhs_end_bb, Op::Not);
+            self.append_code(rhs_end_bb, Op::Not);
         }
+        self.set_jump(rhs_end_bb, JumpInst::Jump(end_bb));
 
-        Ok(ret_ty)
+        *bb_id = end_bb;
     }
 
-    fn compile_ident_expr(
+    fn compile_cond_expr(
         &mut self,
-        expr: &ast::Ident,
-        bb_id: BB,
-        scope: &Scope,
-    ) -> CompileResult<Ty> {
-        let (ty, _) = self.gen_ident_addr(expr, bb_id, scope)?;
-        self.append_code(bb_id, load_ty(&ty));
-        Ok(ty)
-    }
-}
+        cond: &hir::Expr,
+        if_val: &hir::Expr,
+        else_val: &hir::Expr,
+        bb_id: &mut BB,
+    ) {
+        self.compile_expr(cond, bb_id);
+
+        let if_bb = self.new_bb();
+        let else_bb = self.new_bb();
+        let end_bb = self.new_bb();
+        self.set_jump(*bb_id, JumpInst::JumpIf(if_bb, else_bb));
 
-fn add_decl_scope(decl: &ast::DeclStmt, scope: &mut Scope) -> CompileResult<(u64, Ty)> {
-    let ty = get_ty(&decl.ty)?;
-    if matches!(ty, Ty::Void) {
-        return Err(CompileError(
-            CompileErrorKind::VoidTypeVariable,
-            Some(decl.span),
-        ));
-    }
-    let name = decl.name.name.clone();
+        let mut if_end_bb = if_bb;
+        self.compile_expr(if_val, &mut if_end_bb);
+        self.set_jump(if_end_bb, JumpInst::Jump(end_bb));
 
-    let symbol = Symbol::new(ty.clone(), decl.is_const);
+        let mut else_end_bb = else_bb;
+        self.compile_expr(else_val, &mut else_end_bb);
+        self.set_jump(else_end_bb, JumpInst::Jump(end_bb));
 
-    let symbol = scope.insert(name, symbol);
-    match symbol {
-        Some(u) => Ok((u, ty)),
-        None => Err(CompileError {
-            kind: CompileErrorKind::DuplicateSymbol(decl.name.name.as_str().into()),
-            span: Some(decl.name.span),
-        }),
+        *bb_id = end_bb;
     }
 }
 
-fn get_ty(ty: &ast::TyDef) -> CompileResult<Ty> {
-    Ok(match ty.name.as_str() {
-        "int" => Ty::Int,
-        "double" => Ty::Double,
-        "void" => Ty::Void,
-        _ => {
-            return Err(CompileError {
-                kind: CompileErrorKind::UnknownType(ty.name.as_str().into()),
-                span: Some(ty.span),
-            })
-        }
-    })
-}
-
-fn get_ty_nonvoid(ty: &ast::TyDef) -> CompileResult<Ty> {
-    Ok(match ty.name.as_str() {
-        "int" => Ty::Int,
-        "double" => Ty::Double,
-        "void" => {
-            return Err(CompileError {
-                kind: CompileErrorKind::VoidTypeVariable,
-                span: Some(ty.span),
-            })
-        }
-        _ => {
-            return Err(CompileError {
-                kind: CompileErrorKind::UnknownType(ty.name.as_str().into()),
-                span: Some(ty.span),
-            })
-        }
-    })
-}
-
 fn op_load_address(place: Place) -> Op {
     match place {
         Place::Arg(x) => Op::ArgA(x),
@@ -1035,7 +1030,8 @@ fn load_ty(ty: &Ty) -> Op {
         Ty::Double => Op::Load64,
         Ty::Bool => Op::Load64,
         Ty::Addr => Op::Load64,
-        Ty::Func(_) => panic!("Invalid type"),
+        Ty::Error => Op::Load64,
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) => panic!("Invalid type"),
         Ty::Void => Op::Pop,
     }
 }
@@ -1046,7 +1042,8 @@ fn store_ty(ty: &Ty) -> Op {
         Ty::Double => Op::Store64,
         Ty::Bool => Op::Store64,
         Ty::Addr => Op::Store64,
-        Ty::Func(_) => panic!("Invalid type"),
+        Ty::Error => Op::Store64,
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) => panic!("Invalid type"),
         Ty::Void => Op::Pop,
     }
 }
@@ -1058,12 +1055,20 @@ fn binary_op_op(op: ast::BinaryOp, ty: &Ty) -> Option<&[Op]> {
             ast::BinaryOp::Sub => &[Op::SubI],
             ast::BinaryOp::Mul => &[Op::MulI],
             ast::BinaryOp::Div => &[Op::DivI],
+            ast::BinaryOp::Shl => &[Op::Shl],
+            ast::BinaryOp::Shr => &[Op::Shr],
+            ast::BinaryOp::BitAnd => &[Op::And],
+            ast::BinaryOp::BitOr => &[Op::Or],
+            ast::BinaryOp::Xor => &[Op::Xor],
             ast::BinaryOp::Gt => &[Op::CmpI, Op::SetGt],
             ast::BinaryOp::Lt => &[Op::CmpI, Op::SetLt],
             ast::BinaryOp::Ge => &[Op::CmpI, Op::SetLt, Op::Not],
             ast::BinaryOp::Le => &[Op::CmpI, Op::SetGt, Op::Not],
             ast::BinaryOp::Eq => &[Op::CmpI, Op::Not],
             ast::BinaryOp::Neq => &[Op::CmpI],
+            // these operators need more than a fixed sequence of instructions,
+            // see `compile_mod_expr` and `compile_logical_expr`
+            ast::BinaryOp::Mod | ast::BinaryOp::And | ast::BinaryOp::Or => return None,
         }),
         Ty::Double => Some(match op {
             ast::BinaryOp::Add => &[Op::AddF],
@@ -1076,8 +1081,24 @@ fn binary_op_op(op: ast::BinaryOp, ty: &Ty) -> Option<&[Op]> {
             ast::BinaryOp::Le => &[Op::CmpF, Op::SetGt, Op::Not],
             ast::BinaryOp::Eq => &[Op::CmpF, Op::Not],
             ast::BinaryOp::Neq => &[Op::CmpF],
+            ast::BinaryOp::Mod
+            | ast::BinaryOp::Shl
+            | ast::BinaryOp::Shr
+            | ast::BinaryOp::BitAnd
+            | ast::BinaryOp::BitOr
+            | ast::BinaryOp::Xor
+            | ast::BinaryOp::And
+            | ast::BinaryOp::Or => return None,
         }),
-        Ty::Bool | Ty::Func(_) | Ty::Void => None,
+        Ty::Bool => match op {
+            ast::BinaryOp::BitAnd => Some(&[Op::And]),
+            ast::BinaryOp::BitOr => Some(&[Op::Or]),
+            ast::BinaryOp::Xor => Some(&[Op::Xor]),
+            ast::BinaryOp::Eq => Some(&[Op::CmpI, Op::Not]),
+            ast::BinaryOp::Neq => Some(&[Op::CmpI]),
+            _ => None,
+        },
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) | Ty::Void | Ty::Error => None,
     }
 }
 
@@ -1086,54 +1107,45 @@ fn unary_op_op(op: ast::UnaryOp, ty: &Ty) -> Option<&[Op]> {
         Ty::Int => Some(match op {
             ast::UnaryOp::Neg => &[Op::NegI],
             ast::UnaryOp::Pos => &[],
+            ast::UnaryOp::Not => &[Op::Not],
+            ast::UnaryOp::BitNot => &[Op::Push(!0), Op::Xor],
         }),
-        Ty::Double => Some(match op {
-            ast::UnaryOp::Neg => &[Op::NegF],
-            ast::UnaryOp::Pos => &[],
-        }),
-        Ty::Addr | Ty::Bool | Ty::Func(_) | Ty::Void => None,
+        Ty::Double => match op {
+            ast::UnaryOp::Neg => Some(&[Op::NegF]),
+            ast::UnaryOp::Pos => Some(&[]),
+            ast::UnaryOp::Not | ast::UnaryOp::BitNot => None,
+        },
+        Ty::Bool => match op {
+            ast::UnaryOp::Not => Some(&[Op::Not]),
+            _ => None,
+        },
+        Ty::Addr | Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) | Ty::Void | Ty::Error => None,
     }
 }
 
+/// Any non-zero value is a true `bool`, so converting from `bool` needs to
+/// normalize it to 0 or 1
 fn as_expr_op(from_ty: &Ty, to_ty: &Ty) -> Option<&'static [Op]> {
     match from_ty {
         Ty::Int | Ty::Addr => match to_ty {
             Ty::Int | Ty::Addr => Some(&[]),
             Ty::Double => Some(&[Op::IToF]),
-            Ty::Bool => Some(&[]),
+            // bools are kept as 0 or 1, see `normalise_bool`
+            Ty::Bool => Some(&[Op::Not, Op::Not]),
             _ => None,
         },
         Ty::Double => match to_ty {
             Ty::Int => Some(&[Op::FToI]),
             Ty::Double => Some(&[]),
-            Ty::Bool => Some(&[]),
+            // -0.0 is not all zeros, so compare with 0.0 instead
+            Ty::Bool => Some(&[Op::Push(0), Op::CmpF]),
             _ => None,
         },
-        Ty::Bool | Ty::Func(_) | Ty::Void => None,
-    }
-}
-
-fn binary_op_result_ty(op: ast::BinaryOp, ty: &Ty) -> Option<Ty> {
-    match ty {
-        Ty::Int | Ty::Double | Ty::Addr => match op {
-            ast::BinaryOp::Add => Some(ty.clone()),
-            ast::BinaryOp::Sub => Some(ty.clone()),
-            ast::BinaryOp::Mul => Some(ty.clone()),
-            ast::BinaryOp::Div => Some(ty.clone()),
-            ast::BinaryOp::Gt => Some(Ty::Bool),
-            ast::BinaryOp::Lt => Some(Ty::Bool),
-            ast::BinaryOp::Ge => Some(Ty::Bool),
-            ast::BinaryOp::Le => Some(Ty::Bool),
-            ast::BinaryOp::Eq => Some(Ty::Bool),
-            ast::BinaryOp::Neq => Some(Ty::Bool),
+        Ty::Bool => match to_ty {
+            Ty::Int => Some(&[Op::Not, Op::Not]),
+            Ty::Bool => Some(&[]),
+            _ => None,
         },
-        Ty::Bool | Ty::Func(_) | Ty::Void => None,
-    }
-}
-
-fn unary_op_result_ty(_op: ast::UnaryOp, ty: &Ty) -> Option<Ty> {
-    match ty {
-        Ty::Int | Ty::Double => Some(ty.clone()),
-        Ty::Addr | Ty::Bool | Ty::Func(_) | Ty::Void => None,
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) | Ty::Void | Ty::Error => None,
     }
 }
@@ -1,8 +1,9 @@
 use bit_set::BitSet;
 use r0syntax::span::Span;
+use std::cmp::Reverse;
 
 use crate::{
-    code::BasicBlock,
+    code::{BasicBlock, JumpInst},
     err::{CompileError, CompileErrorKind},
 };
 
@@ -12,6 +13,7 @@ use super::CompileResult;
 #[derive(Debug)]
 pub struct BBArranger<'st> {
     bb: &'st [BasicBlock],
+    start: usize,
     path: BitSet,
     vis: BitSet,
     in_degree: Vec<usize>,
@@ -22,6 +24,7 @@ impl<'st> BBArranger<'st> {
     pub fn new(bb: &'st [BasicBlock]) -> BBArranger<'st> {
         BBArranger {
             bb,
+            start: 0,
             path: BitSet::new(),
             vis: BitSet::new(),
             in_degree: vec![0; bb.len()],
@@ -30,6 +33,7 @@ impl<'st> BBArranger<'st> {
     }
 
     pub fn construct_arrangement(&mut self, start: usize) -> CompileResult<()> {
+        self.start = start;
         self.vis(start);
         self.arr(start)
     }
@@ -82,11 +86,8 @@ impl<'st> BBArranger<'st> {
                 self.arr(bb1)?;
                 self.arr(bb2)?;
             }
-            crate::code::JumpInst::Return => {}
-            crate::code::JumpInst::Unreachable => panic!(
-                "Unreachable basic block {} being visited; block map:\n {:#?}",
-                id, self.bb
-            ),
+            // control never leaves an unreachable-terminated block
+            crate::code::JumpInst::Return | crate::code::JumpInst::Unreachable => {}
             crate::code::JumpInst::Undefined => {
                 return Err(CompileError(CompileErrorKind::NotAllRoutesReturn, None))
             }
@@ -95,7 +96,105 @@ impl<'st> BBArranger<'st> {
         Ok(())
     }
 
+    /// Lay out the blocks so that as many jumps as possible go to the block
+    /// right after them, and can be left out. Blocks are joined into chains
+    /// along the edges most likely to be taken first, i.e. those in the
+    /// deepest loops, preferring unconditional jumps since leaving one out
+    /// saves a whole instruction.
     pub fn arrange(self) -> Vec<usize> {
-        self.arr
+        // `arr` is in topological order, ignoring back edges, and starts with
+        // the start block
+        let order = &self.arr;
+        let mut index = vec![usize::MAX; self.bb.len()];
+        for (idx, id) in order.iter().enumerate() {
+            index[*id] = idx;
+        }
+        let depth = self.loop_depth(&index);
+
+        let mut edges = vec![];
+        for &from in order {
+            let conditional = matches!(self.bb[from].jump, JumpInst::JumpIf(..));
+            for (nth, to) in self.bb[from].jump.successors().enumerate() {
+                edges.push((from, to, conditional, nth));
+            }
+        }
+        edges.sort_by_key(|&(from, to, conditional, nth)| {
+            (
+                Reverse(depth[from].min(depth[to])),
+                conditional,
+                index[from],
+                nth,
+            )
+        });
+
+        let mut next = vec![None; self.bb.len()];
+        let mut prev = vec![None; self.bb.len()];
+        for (from, to, ..) in edges {
+            if next[from].is_some() || prev[to].is_some() || to == self.start {
+                continue;
+            }
+            // joining the end of a chain to its own head would make a cycle
+            let mut head = from;
+            while let Some(p) = prev[head] {
+                head = p;
+            }
+            if head == to {
+                continue;
+            }
+            next[from] = Some(to);
+            prev[to] = Some(from);
+        }
+
+        // the chain of the start block goes first, then the others in the
+        // order of their heads
+        let mut arrangement = vec![];
+        for &id in order.iter().filter(|id| prev[**id].is_none()) {
+            let mut cur = Some(id);
+            while let Some(id) = cur {
+                arrangement.push(id);
+                cur = next[id];
+            }
+        }
+        arrangement
+    }
+
+    /// The number of loops each block is in. `index` is the position of each
+    /// block in topological order, so edges going backwards in it close loops.
+    fn loop_depth(&self, index: &[usize]) -> Vec<usize> {
+        let mut preds = vec![vec![]; self.bb.len()];
+        for &from in &self.arr {
+            for to in self.bb[from].jump.successors() {
+                preds[to].push(from);
+            }
+        }
+
+        // the blocks in the loop headed by each block
+        let mut loops: Vec<Option<BitSet>> = vec![None; self.bb.len()];
+        for &from in &self.arr {
+            for head in self.bb[from].jump.successors() {
+                if index[head] > index[from] {
+                    continue;
+                }
+                let body = loops[head].get_or_insert_with(|| {
+                    let mut body = BitSet::new();
+                    body.insert(head);
+                    body
+                });
+                let mut stack = vec![from];
+                while let Some(id) = stack.pop() {
+                    if body.insert(id) {
+                        stack.extend(&preds[id]);
+                    }
+                }
+            }
+        }
+
+        let mut depth = vec![0; self.bb.len()];
+        for body in loops.iter().flatten() {
+            for id in body.iter() {
+                depth[id] += 1;
+            }
+        }
+        depth
     }
 }
@@ -0,0 +1,1326 @@
+//! Lowering from the syntax tree into HIR, resolving names and checking types
+
+use super::*;
+use crate::{
+    err::CompileErrorKind,
+    scope::{Scope, Symbol, SymbolIdGenerator},
+    ty::{ArrayTy, FuncTy, StructFieldTy, StructTy},
+};
+use indexmap::IndexSet;
+use std::cell::RefCell;
+
+type CompileResult<T> = std::result::Result<T, CompileError>;
+
+/// Lower `tree`, reporting every error found to `errors`. The result is
+/// meaningless if any error is reported.
+pub(crate) fn lower_program(tree: &ast::Program, errors: &ErrorSink) -> Program {
+    let global_sym_gen = RefCell::new(SymbolIdGenerator::new());
+    let mut global_scope = Scope::new(&global_sym_gen);
+
+    create_lib_func(&mut global_scope);
+
+    for (idx, struct_stmt) in tree.structs.iter().enumerate() {
+        add_struct_scope(struct_stmt, &tree.structs[..idx], &mut global_scope, errors);
+    }
+
+    // Globals whose initializer failed to lower while inferring their type,
+    // which aren't lowered again in `_start`
+    let mut failed = IndexSet::new();
+    let mut globals = vec![];
+    for (idx, decl) in tree.decls.iter().enumerate() {
+        let (id, ty) = match (&decl.ty, &decl.val) {
+            (None, Some(val)) => {
+                let errors_before = errors.len();
+                let ty = infer_global_ty(val, &global_scope, errors);
+                if errors.len() != errors_before {
+                    failed.insert(idx);
+                }
+                let id = insert_decl_scope(decl, ty.clone(), &mut global_scope, errors);
+                (id, ty)
+            }
+            _ => add_decl_scope(decl, &mut global_scope, errors),
+        };
+        globals.push(Var {
+            id,
+            name: decl.name.name.clone(),
+            ty,
+            is_const: decl.is_const,
+            span: decl.name.span,
+        });
+    }
+
+    // the functions defined so far, and where
+    let mut func_names = indexmap::indexmap! {SmolStr::from("_start") => None};
+    let mut funcs = vec![];
+    for func in &tree.funcs {
+        if let Some(previous) = func_names.get(&func.name.name) {
+            errors.report(CompileError(
+                CompileErrorKind::DuplicateSymbol {
+                    name: func.name.name.as_str().into(),
+                    previous: *previous,
+                },
+                Some(func.name.span),
+            ));
+            continue;
+        }
+        func_names.insert(func.name.name.clone(), Some(func.name.span));
+        funcs.push(lower_func(func, &mut global_scope, errors));
+    }
+
+    let start = lower_func(&start_func(tree, &failed), &mut global_scope, errors);
+
+    Program {
+        globals,
+        funcs,
+        start,
+    }
+}
+
+fn create_lib_func(scope: &mut Scope) {
+    let lib_funcs: [(&str, Vec<Ty>, Ty); 8] = [
+        ("putint", vec![Ty::Int], Ty::Void),
+        ("putstr", vec![Ty::Int], Ty::Void),
+        ("putdouble", vec![Ty::Double], Ty::Void),
+        ("putchar", vec![Ty::Int], Ty::Void),
+        ("putln", vec![], Ty::Void),
+        ("getchar", vec![], Ty::Int),
+        ("getint", vec![], Ty::Int),
+        ("getdouble", vec![], Ty::Double),
+    ];
+    for (name, params, ret) in lib_funcs.iter().cloned() {
+        scope.insert(
+            name.into(),
+            Symbol::new(
+                Ty::Func(FuncTy {
+                    params: params.into_iter().map(P::new).collect(),
+                    ret: P::new(ret),
+                }),
+                true,
+                None,
+            ),
+        );
+    }
+}
+
+/// `_start`, which assigns the initial values of global variables, then calls
+/// `main`. The declarations at the indices in `skipped` are left out.
+fn start_func(tree: &ast::Program, skipped: &IndexSet<usize>) -> ast::FuncStmt {
+    ast::FuncStmt {
+        name: ast::Ident {
+            name: "_start".into(),
+            span: Span::default(),
+        },
+        params: vec![],
+        ret_ty: ast::TyDef {
+            kind: ast::TyDefKind::Named("void".into()),
+            span: Span::default(),
+        },
+        body: ast::BlockStmt {
+            span: Span::default(),
+            stmts: tree
+                .decls
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| !skipped.contains(idx))
+                .map(|(_, decl)| decl.clone())
+                .filter_map(|decl: ast::DeclStmt| {
+                    Some(ast::Stmt::Expr(ast::Expr::Assign(ast::AssignExpr {
+                        span: decl.span,
+                        allow_assign_const: true,
+                        op: None,
+                        lhs: P::new(ast::Expr::Ident(decl.name)),
+                        rhs: decl.val?,
+                    })))
+                })
+                .chain(vec![
+                    ast::Stmt::Expr(ast::Expr::Call(ast::CallExpr {
+                        span: Span::default(),
+                        func: ast::Ident {
+                            span: Span::default(),
+                            name: "main".into(),
+                        },
+                        params: vec![],
+                    })),
+                    ast::Stmt::Return(ast::ReturnStmt {
+                        val: None,
+                        span: Span::default(),
+                    }),
+                ])
+                .collect(),
+        },
+        span: Span::default(),
+    }
+}
+
+macro_rules! check_type_eq {
+    ($lhs:expr, $rhs:expr, $span:expr) => {
+        if !$lhs.matches(&$rhs) {
+            return Err(CompileError(
+                CompileErrorKind::TypeMismatch {
+                    expected: $lhs.to_string(),
+                    got: Some($rhs.to_string()),
+                },
+                Some($span),
+            ));
+        }
+    };
+}
+
+macro_rules! check_logical_operand {
+    ($ty:expr, $span:expr) => {
+        if !matches!($ty, Ty::Int | Ty::Bool | Ty::Error) {
+            return Err(CompileError(
+                CompileErrorKind::InvalidCalculation($ty.to_string()),
+                Some($span),
+            ));
+        }
+    };
+}
+
+/// Add the signature of `func` to `global_scope` and lower its body
+fn lower_func(func: &ast::FuncStmt, global_scope: &mut Scope, errors: &ErrorSink) -> Func {
+    let ret_ty = errors
+        .ok_or_report(get_value_ty(&func.ret_ty, global_scope))
+        .unwrap_or(Ty::Error);
+
+    let param_tys = func
+        .params
+        .iter()
+        .map(|param| {
+            let ty = get_value_ty(&param.ty, global_scope).and_then(|ty| match ty {
+                Ty::Void => Err(CompileError(
+                    CompileErrorKind::VoidTypeVariable,
+                    Some(param.ty.span),
+                )),
+                ty => Ok(ty),
+            });
+            errors.ok_or_report(ty).unwrap_or(Ty::Error)
+        })
+        .collect::<Vec<_>>();
+    let func_ty = FuncTy {
+        params: param_tys.iter().cloned().map(P::new).collect(),
+        ret: P::new(ret_ty.clone()),
+    };
+
+    // a function may call itself, but not the ones after it
+    let id = global_scope
+        .insert(
+            func.name.name.clone(),
+            Symbol::new(Ty::Func(func_ty), true, Some(func.name.span)),
+        )
+        .unwrap_or_else(|| global_scope.get_new_id());
+
+    let mut lowerer = FuncLowerer {
+        global_scope,
+        errors,
+        ret_ty: ret_ty.clone(),
+        jump_targets: vec![],
+        next_loop_id: 0,
+        live: true,
+        jumped_to: IndexSet::new(),
+    };
+    let errors_before = errors.len();
+    let mut scope = Scope::new_with_parent(global_scope);
+
+    let params = func
+        .params
+        .iter()
+        .zip(param_tys)
+        .map(|(param, ty)| {
+            let id = scope
+                .insert(
+                    param.name.name.clone(),
+                    Symbol::new(ty.clone(), param.is_const, Some(param.name.span)),
+                )
+                .unwrap_or_else(|| {
+                    let previous = scope
+                        .find_in_self(&param.name.name)
+                        .and_then(|sym| sym.span);
+                    errors.report(CompileError(
+                        CompileErrorKind::DuplicateSymbol {
+                            name: param.name.name.as_str().into(),
+                            previous,
+                        },
+                        Some(param.name.span),
+                    ));
+                    scope.get_new_id()
+                });
+            Var {
+                id,
+                name: param.name.name.clone(),
+                ty,
+                is_const: param.is_const,
+                span: param.name.span,
+            }
+        })
+        .collect();
+
+    let body = lowerer.lower_block_without_scope(&func.body, &mut scope);
+
+    // checked only without errors, since a statement that failed to lower may
+    // have returned
+    if lowerer.live && ret_ty != Ty::Void && errors.len() == errors_before {
+        errors.report(CompileError(
+            CompileErrorKind::NotAllRoutesReturn,
+            Some(func.name.span),
+        ));
+    }
+
+    Func {
+        id,
+        name: func.name.name.clone(),
+        span: func.name.span,
+        params,
+        ret_ty,
+        body,
+    }
+}
+
+/// A loop or switch that `break` or `continue` can refer to
+struct JumpTarget {
+    id: LoopId,
+    label: Option<SmolStr>,
+    is_loop: bool,
+}
+
+struct FuncLowerer<'f> {
+    global_scope: &'f Scope<'f>,
+    errors: &'f ErrorSink,
+    ret_ty: Ty,
+    /// The enclosing loops and switches, innermost last
+    jump_targets: Vec<JumpTarget>,
+    next_loop_id: LoopId,
+    /// Whether control can reach the statement being lowered
+    live: bool,
+    /// Loops and switches that a reachable `break` leaves, and loops that a
+    /// reachable `continue` continues, by whether it's a `continue`
+    jumped_to: IndexSet<(LoopId, bool)>,
+}
+
+impl<'f> FuncLowerer<'f> {
+    fn new_loop_id(&mut self) -> LoopId {
+        let id = self.next_loop_id;
+        self.next_loop_id += 1;
+        id
+    }
+
+    fn lower_block(&mut self, blk: &ast::BlockStmt, scope: &Scope) -> Block {
+        let mut block_scope = Scope::new_with_parent(scope);
+        self.lower_block_without_scope(blk, &mut block_scope)
+    }
+
+    /// Lower the statements in `blk`. Errors are reported and the statement
+    /// is left out, so that every statement gets checked.
+    fn lower_block_without_scope(&mut self, blk: &ast::BlockStmt, scope: &mut Scope) -> Block {
+        let mut stmts = vec![];
+        for stmt in &blk.stmts {
+            if let ast::Stmt::Empty(_) = stmt {
+                continue;
+            }
+            let reachable = self.live;
+            let res = self.lower_stmt(stmt, scope);
+            if let Some(kind) = self.errors.ok_or_report(res) {
+                stmts.push(Stmt {
+                    kind,
+                    span: stmt.span(),
+                    reachable,
+                });
+            }
+        }
+        Block {
+            stmts,
+            span: blk.span,
+        }
+    }
+
+    fn lower_stmt(&mut self, stmt: &ast::Stmt, scope: &mut Scope) -> CompileResult<StmtKind> {
+        match stmt {
+            ast::Stmt::Block(blk) => Ok(StmtKind::Block(self.lower_block(blk, scope))),
+            ast::Stmt::While(stmt) => self.lower_while(stmt, scope),
+            ast::Stmt::For(stmt) => self.lower_for(stmt, scope),
+            ast::Stmt::DoWhile(stmt) => self.lower_do_while(stmt, scope),
+            ast::Stmt::If(stmt) => self.lower_if(stmt, scope),
+            ast::Stmt::Switch(stmt) => self.lower_switch(stmt, scope),
+            ast::Stmt::Expr(expr) => Ok(StmtKind::Expr(self.lower_expr(expr, scope))),
+            ast::Stmt::Decl(stmt) => self.lower_decl(stmt, scope),
+            ast::Stmt::Return(stmt) => self.lower_return(stmt, scope),
+            ast::Stmt::Break(stmt) => self.lower_break(stmt),
+            ast::Stmt::Continue(stmt) => self.lower_continue(stmt),
+            ast::Stmt::Empty(_) => unreachable!("Empty statements are skipped"),
+        }
+    }
+
+    /// Lower the body of a loop or switch `id`, which `break` and `continue`
+    /// may refer to
+    fn lower_jump_target_body(
+        &mut self,
+        target: JumpTarget,
+        body: &ast::BlockStmt,
+        scope: &Scope,
+    ) -> Block {
+        self.jump_targets.push(target);
+        let body = self.lower_block(body, scope);
+        self.jump_targets.pop();
+        body
+    }
+
+    fn lower_while(&mut self, stmt: &ast::WhileStmt, scope: &Scope) -> CompileResult<StmtKind> {
+        let id = self.new_loop_id();
+        let cond = self.lower_expr(stmt.cond.as_ref(), scope);
+        let target = JumpTarget {
+            id,
+            label: loop_label(&stmt.label),
+            is_loop: true,
+        };
+        // the loop is left when the condition is false
+        let live = self.live;
+        let body = self.lower_jump_target_body(target, stmt.body.as_ref(), scope);
+        self.live = live;
+        Ok(StmtKind::While { id, cond, body })
+    }
+
+    fn lower_for(&mut self, stmt: &ast::ForStmt, scope: &Scope) -> CompileResult<StmtKind> {
+        let id = self.new_loop_id();
+        // variables declared in `init` are only visible inside the loop
+        let mut for_scope = Scope::new_with_parent(scope);
+        let init = match &stmt.init {
+            Some(init) => Some(Box::new(Stmt {
+                reachable: self.live,
+                kind: self.lower_stmt(init.as_ref(), &mut for_scope)?,
+                span: init.span(),
+            })),
+            None => None,
+        };
+        let cond = stmt
+            .cond
+            .as_ref()
+            .map(|cond| self.lower_expr(cond.as_ref(), &for_scope));
+
+        let target = JumpTarget {
+            id,
+            label: loop_label(&stmt.label),
+            is_loop: true,
+        };
+        let live = self.live;
+        let body = self.lower_jump_target_body(target, stmt.body.as_ref(), &for_scope);
+        let step = stmt
+            .step
+            .as_ref()
+            .map(|step| self.lower_expr(step.as_ref(), &for_scope));
+        // without a condition, the loop can only be left by `break`
+        self.live = (live && cond.is_some()) || self.jumped_to.contains(&(id, false));
+
+        Ok(StmtKind::For {
+            id,
+            init,
+            cond,
+            step,
+            body,
+        })
+    }
+
+    fn lower_do_while(
+        &mut self,
+        stmt: &ast::DoWhileStmt,
+        scope: &Scope,
+    ) -> CompileResult<StmtKind> {
+        let id = self.new_loop_id();
+        let target = JumpTarget {
+            id,
+            label: loop_label(&stmt.label),
+            is_loop: true,
+        };
+        let body = self.lower_jump_target_body(target, stmt.body.as_ref(), scope);
+        let cond = self.lower_expr(stmt.cond.as_ref(), scope);
+        // the condition is reached from the end of the body or `continue`
+        self.live = self.live
+            || self.jumped_to.contains(&(id, true))
+            || self.jumped_to.contains(&(id, false));
+        Ok(StmtKind::DoWhile { id, body, cond })
+    }
+
+    fn lower_if(&mut self, stmt: &ast::IfStmt, scope: &Scope) -> CompileResult<StmtKind> {
+        let cond = self.lower_expr(stmt.cond.as_ref(), scope);
+
+        let live = self.live;
+        let if_block = self.lower_block(stmt.if_block.as_ref(), scope);
+        let if_end_live = std::mem::replace(&mut self.live, live);
+
+        let else_block = match &stmt.else_block {
+            ast::IfElseBlock::None => None,
+            ast::IfElseBlock::If(stmt) => Some(Block {
+                stmts: vec![Stmt {
+                    kind: self.lower_if(stmt.as_ref(), scope)?,
+                    span: stmt.span,
+                    reachable: live,
+                }],
+                span: stmt.span,
+            }),
+            ast::IfElseBlock::Block(block) => Some(self.lower_block(block.as_ref(), scope)),
+        };
+        self.live |= if_end_live;
+
+        Ok(StmtKind::If {
+            cond,
+            if_block,
+            else_block,
+        })
+    }
+
+    fn lower_switch(&mut self, stmt: &ast::SwitchStmt, scope: &Scope) -> CompileResult<StmtKind> {
+        let id = self.new_loop_id();
+        let expr = self.lower_expr(stmt.expr.as_ref(), scope);
+        if !matches!(expr.ty, Ty::Int | Ty::Error) {
+            return Err(CompileError(
+                CompileErrorKind::TypeMismatch {
+                    expected: Ty::Int.to_string(),
+                    got: Some(expr.ty.to_string()),
+                },
+                Some(stmt.expr.span()),
+            ));
+        }
+
+        let live = self.live;
+        // without a default case, nothing may match
+        let mut end_live = !stmt
+            .cases
+            .iter()
+            .any(|case| case.labels.iter().any(|label| label.value.is_none()))
+            && live;
+        let mut cases = vec![];
+        for case in &stmt.cases {
+            // a case is reached from the dispatch, or by falling through from
+            // the one before it
+            self.live |= live;
+            let target = JumpTarget {
+                id,
+                label: None,
+                is_loop: false,
+            };
+            let body = self.lower_jump_target_body(target, &case.body, scope);
+            cases.push(SwitchCase {
+                values: case.labels.iter().filter_map(|label| label.value).collect(),
+                is_default: case.labels.iter().any(|label| label.value.is_none()),
+                body,
+            });
+        }
+        // only the last case falls through to the end
+        end_live |= self.live;
+        self.live = end_live || self.jumped_to.contains(&(id, false));
+
+        Ok(StmtKind::Switch { id, expr, cases })
+    }
+
+    fn lower_decl(&mut self, stmt: &ast::DeclStmt, scope: &mut Scope) -> CompileResult<StmtKind> {
+        if let (None, Some(val)) = (&stmt.ty, &stmt.val) {
+            return self.lower_inferred_decl(stmt, val, scope);
+        }
+
+        let (id, ty) = add_decl_scope(stmt, scope, self.errors);
+        let init = match &stmt.val {
+            Some(val) => {
+                // the variable is already in scope in its own initializer
+                let val = self.lower_expr(val.as_ref(), scope);
+                check_type_eq!(ty, val.ty, val.span);
+                Some(val)
+            }
+            None => None,
+        };
+        Ok(StmtKind::Decl {
+            var: Var {
+                id,
+                name: stmt.name.name.clone(),
+                ty,
+                is_const: stmt.is_const,
+                span: stmt.name.span,
+            },
+            init,
+        })
+    }
+
+    /// Lower a declaration without a type annotation, which gets the type of
+    /// its initializer `val`
+    fn lower_inferred_decl(
+        &mut self,
+        stmt: &ast::DeclStmt,
+        val: &ast::Expr,
+        scope: &mut Scope,
+    ) -> CompileResult<StmtKind> {
+        // the variable isn't in scope in its own initializer
+        let val = self.lower_expr(val, scope);
+        let ty = self
+            .errors
+            .ok_or_report(inferred_ty(&val.ty, val.span))
+            .unwrap_or(Ty::Error);
+
+        let id = insert_decl_scope(stmt, ty.clone(), scope, self.errors);
+        Ok(StmtKind::Decl {
+            var: Var {
+                id,
+                name: stmt.name.name.clone(),
+                ty,
+                is_const: stmt.is_const,
+                span: stmt.name.span,
+            },
+            init: Some(val),
+        })
+    }
+
+    fn lower_return(&mut self, stmt: &ast::ReturnStmt, scope: &Scope) -> CompileResult<StmtKind> {
+        let val = match (&self.ret_ty, &stmt.val) {
+            (Ty::Void, Some(_)) => {
+                return Err(CompileError(
+                    CompileErrorKind::TypeMismatch {
+                        expected: "void".into(),
+                        got: None,
+                    },
+                    Some(stmt.span),
+                ));
+            }
+            (Ty::Void, None) => None,
+            (ret_ty, None) => {
+                return Err(CompileError(
+                    CompileErrorKind::TypeMismatch {
+                        expected: ret_ty.to_string(),
+                        got: Some(Ty::Void.to_string()),
+                    },
+                    Some(stmt.span),
+                ));
+            }
+            (_, Some(val)) => {
+                let val = self.lower_expr(val.as_ref(), scope);
+                if !val.ty.matches(&self.ret_ty) {
+                    return Err(CompileError(
+                        CompileErrorKind::TypeMismatch {
+                            expected: self.ret_ty.to_string(),
+                            got: Some(val.ty.to_string()),
+                        },
+                        Some(stmt.span),
+                    ));
+                }
+                Some(val)
+            }
+        };
+
+        self.live = false;
+        Ok(StmtKind::Return(val))
+    }
+
+    /// Find the loop labelled `label`, or else the innermost loop, or switch
+    /// for `break`
+    fn find_jump_target(
+        &self,
+        label: &Option<ast::Ident>,
+        is_continue: bool,
+        span: Span,
+    ) -> CompileResult<LoopId> {
+        let mut targets = self
+            .jump_targets
+            .iter()
+            .rev()
+            .filter(|target| !is_continue || target.is_loop);
+        let target = match label {
+            None => targets.next().ok_or_else(|| {
+                let kind = if is_continue {
+                    CompileErrorKind::NoContinueContext
+                } else {
+                    CompileErrorKind::NoBreakContext
+                };
+                CompileError(kind, Some(span))
+            })?,
+            Some(label) => targets
+                .find(|target| target.label.as_ref() == Some(&label.name))
+                .ok_or_else(|| {
+                    CompileError(
+                        CompileErrorKind::UnknownLabel(label.name.as_str().into()),
+                        Some(label.span),
+                    )
+                })?,
+        };
+        Ok(target.id)
+    }
+
+    /// Record a jump to `id`, after which nothing is reachable
+    fn jump_to(&mut self, id: LoopId, is_continue: bool) {
+        if self.live {
+            self.jumped_to.insert((id, is_continue));
+        }
+        self.live = false;
+    }
+
+    fn lower_break(&mut self, stmt: &ast::BreakStmt) -> CompileResult<StmtKind> {
+        let id = self.find_jump_target(&stmt.label, false, stmt.span)?;
+        self.jump_to(id, false);
+        Ok(StmtKind::Break(id))
+    }
+
+    fn lower_continue(&mut self, stmt: &ast::ContinueStmt) -> CompileResult<StmtKind> {
+        let id = self.find_jump_target(&stmt.label, true, stmt.span)?;
+        self.jump_to(id, true);
+        Ok(StmtKind::Continue(id))
+    }
+
+    /// Lower `expr`. If it fails to type check, the error is reported and the
+    /// result is `ExprKind::Error` of type `Ty::Error`, so this never fails.
+    fn lower_expr(&mut self, expr: &ast::Expr, scope: &Scope) -> Expr {
+        let res = self.lower_expr_inner(expr, scope);
+        self.errors.ok_or_report(res).unwrap_or_else(|| Expr {
+            kind: ExprKind::Error,
+            ty: Ty::Error,
+            span: expr.span(),
+        })
+    }
+
+    fn lower_expr_inner(&mut self, expr: &ast::Expr, scope: &Scope) -> CompileResult<Expr> {
+        let (kind, ty) = match expr {
+            ast::Expr::Ident(_) | ast::Expr::Index(_) | ast::Expr::Field(_) => {
+                let (place, _) = self.lower_place(expr, scope)?;
+                check_not_aggregate(&place.ty, expr.span())?;
+                let ty = place.ty.clone();
+                (ExprKind::Load(place), ty)
+            }
+            ast::Expr::Assign(expr) => self.lower_assign_expr(expr, scope)?,
+            ast::Expr::As(expr) => self.lower_as_expr(expr, scope)?,
+            ast::Expr::Literal(expr) => lower_literal_expr(expr)?,
+            ast::Expr::Unary(expr) => self.lower_unary_expr(expr, scope)?,
+            ast::Expr::Binary(expr) => self.lower_binary_expr(expr, scope)?,
+            ast::Expr::Cond(expr) => self.lower_cond_expr(expr, scope)?,
+            ast::Expr::Call(expr) => self.lower_call_expr(expr, scope)?,
+        };
+        Ok(Expr {
+            kind,
+            ty,
+            span: expr.span(),
+        })
+    }
+
+    /// Lower an expression that can be assigned to, also returning whether
+    /// it's constant
+    fn lower_place(&mut self, expr: &ast::Expr, scope: &Scope) -> CompileResult<(Place, bool)> {
+        match expr {
+            ast::Expr::Ident(i) => {
+                let (sym, is_global) = scope.find_is_global(&i.name).ok_or_else(|| {
+                    CompileError(
+                        CompileErrorKind::NoSuchSymbol(i.name.to_string()),
+                        Some(i.span),
+                    )
+                })?;
+                let var = SymbolRef {
+                    id: sym.id,
+                    name: i.name.clone(),
+                    is_global,
+                };
+                let place = Place {
+                    kind: PlaceKind::Var(var),
+                    ty: sym.ty.clone(),
+                    span: i.span,
+                };
+                Ok((place, sym.is_const))
+            }
+            ast::Expr::Index(i) => self.lower_index_place(i, scope),
+            ast::Expr::Field(i) => self.lower_field_place(i, scope),
+            _ => Err(CompileError(CompileErrorKind::NotLValue, Some(expr.span()))),
+        }
+    }
+
+    fn lower_index_place(
+        &mut self,
+        expr: &ast::IndexExpr,
+        scope: &Scope,
+    ) -> CompileResult<(Place, bool)> {
+        let (base, is_const) = self.lower_place(expr.base.as_ref(), scope)?;
+        if base.ty.is_error() {
+            let index = self.lower_expr(expr.index.as_ref(), scope);
+            let place = Place {
+                kind: PlaceKind::Index {
+                    base: P::new(base),
+                    index: P::new(index),
+                },
+                ty: Ty::Error,
+                span: expr.span,
+            };
+            return Ok((place, is_const));
+        }
+        let ArrayTy { elem, .. } = base.ty.get_array().cloned().ok_or_else(|| {
+            CompileError(
+                CompileErrorKind::NotIndexable(base.ty.to_string()),
+                Some(expr.base.span()),
+            )
+        })?;
+
+        let index = self.lower_expr(expr.index.as_ref(), scope);
+        check_type_eq!(Ty::Int, index.ty, index.span);
+
+        let place = Place {
+            kind: PlaceKind::Index {
+                base: P::new(base),
+                index: P::new(index),
+            },
+            ty: elem.as_ref().clone(),
+            span: expr.span,
+        };
+        Ok((place, is_const))
+    }
+
+    fn lower_field_place(
+        &mut self,
+        expr: &ast::FieldExpr,
+        scope: &Scope,
+    ) -> CompileResult<(Place, bool)> {
+        let (base, is_const) = self.lower_place(expr.base.as_ref(), scope)?;
+        let (ty, offset) = if base.ty.is_error() {
+            (Ty::Error, 0)
+        } else {
+            let field = base
+                .ty
+                .get_struct()
+                .and_then(|s| s.get_field(&expr.field.name))
+                .ok_or_else(|| {
+                    CompileError(
+                        CompileErrorKind::NoSuchField {
+                            ty: base.ty.to_string(),
+                            field: expr.field.name.to_string(),
+                        },
+                        Some(expr.field.span),
+                    )
+                })?;
+            (field.ty.as_ref().clone(), field.offset)
+        };
+
+        let place = Place {
+            kind: PlaceKind::Field {
+                base: P::new(base),
+                name: expr.field.name.clone(),
+                offset,
+            },
+            ty,
+            span: expr.span,
+        };
+        Ok((place, is_const))
+    }
+
+    fn lower_assign_expr(
+        &mut self,
+        expr: &ast::AssignExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        let (place, is_const) = self.lower_place(expr.lhs.as_ref(), scope)?;
+        let lhs_ty = &place.ty;
+        if expr.op.is_some() {
+            check_not_aggregate(lhs_ty, expr.lhs.span())?;
+        }
+        let rhs = self.lower_expr(expr.rhs.as_ref(), scope);
+        match expr.op {
+            Some(op) => {
+                check_type_eq!(lhs_ty, rhs.ty, rhs.span);
+                let valid = match op {
+                    ast::BinaryOp::Mod => matches!(lhs_ty, Ty::Int | Ty::Error),
+                    _ => {
+                        lhs_ty.is_error()
+                            || rhs.ty.is_error()
+                            || binary_op_result_ty(op, lhs_ty).as_ref() == Some(lhs_ty)
+                    }
+                };
+                if !valid {
+                    return Err(CompileError(
+                        CompileErrorKind::InvalidCalculation(lhs_ty.to_string()),
+                        Some(rhs.span),
+                    ));
+                }
+            }
+            None => check_type_eq!(lhs_ty, rhs.ty, rhs.span),
+        }
+
+        if !expr.allow_assign_const && is_const {
+            let decl = scope.find(&place.root_var().name).and_then(|sym| sym.span);
+            return Err(CompileError(
+                CompileErrorKind::AssignToConst { decl },
+                Some(expr.lhs.span()),
+            ));
+        }
+
+        let kind = ExprKind::Assign {
+            place,
+            op: expr.op,
+            rhs: P::new(rhs),
+        };
+        Ok((kind, Ty::Void))
+    }
+
+    fn lower_binary_expr(
+        &mut self,
+        expr: &ast::BinaryExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        let lhs = self.lower_expr(expr.lhs.as_ref(), scope);
+        let is_logical = matches!(expr.op, ast::BinaryOp::And | ast::BinaryOp::Or);
+        if is_logical {
+            check_logical_operand!(lhs.ty, lhs.span);
+        }
+        let rhs = self.lower_expr(expr.rhs.as_ref(), scope);
+
+        let ty = if is_logical {
+            check_logical_operand!(rhs.ty, rhs.span);
+            Ty::Bool
+        } else {
+            check_type_eq!(lhs.ty, rhs.ty, rhs.span);
+            if lhs.ty.is_error() || rhs.ty.is_error() {
+                Ty::Error
+            } else {
+                let ty = match expr.op {
+                    // there's no remainder of doubles
+                    ast::BinaryOp::Mod => Some(Ty::Int).filter(|_| lhs.ty == Ty::Int),
+                    op => binary_op_result_ty(op, &lhs.ty),
+                };
+                ty.ok_or_else(|| {
+                    CompileError(
+                        CompileErrorKind::InvalidCalculation(lhs.ty.to_string()),
+                        Some(rhs.span),
+                    )
+                })?
+            }
+        };
+
+        let kind = ExprKind::Binary {
+            op: expr.op,
+            lhs: P::new(lhs),
+            rhs: P::new(rhs),
+        };
+        Ok((kind, ty))
+    }
+
+    fn lower_cond_expr(
+        &mut self,
+        expr: &ast::CondExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        let cond = self.lower_expr(expr.cond.as_ref(), scope);
+        check_logical_operand!(cond.ty, cond.span);
+        let if_val = self.lower_expr(expr.if_val.as_ref(), scope);
+        let else_val = self.lower_expr(expr.else_val.as_ref(), scope);
+        check_type_eq!(if_val.ty, else_val.ty, else_val.span);
+
+        let ty = if if_val.ty.is_error() {
+            else_val.ty.clone()
+        } else {
+            if_val.ty.clone()
+        };
+        let kind = ExprKind::Cond {
+            cond: P::new(cond),
+            if_val: P::new(if_val),
+            else_val: P::new(else_val),
+        };
+        Ok((kind, ty))
+    }
+
+    fn lower_unary_expr(
+        &mut self,
+        expr: &ast::UnaryExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        // `-9223372036854775808` is only valid as a whole
+        if let (ast::UnaryOp::Neg, ast::Expr::Literal(lit)) = (expr.op, expr.expr.as_ref()) {
+            if let ast::LiteralKind::Integer(i) = lit.kind {
+                check_int_literal(i, true, lit.span)?;
+                return Ok((ExprKind::Int(i.wrapping_neg()), Ty::Int));
+            }
+        }
+
+        let operand = self.lower_expr(expr.expr.as_ref(), scope);
+        let ty = if operand.ty.is_error() {
+            Ty::Error
+        } else {
+            unary_op_result_ty(expr.op, &operand.ty).ok_or_else(|| {
+                CompileError(
+                    CompileErrorKind::InvalidCalculation(operand.ty.to_string()),
+                    Some(operand.span),
+                )
+            })?
+        };
+
+        let kind = ExprKind::Unary {
+            op: expr.op,
+            expr: P::new(operand),
+        };
+        Ok((kind, ty))
+    }
+
+    fn lower_as_expr(
+        &mut self,
+        expr: &ast::AsExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        let val = self.lower_expr(expr.val.as_ref(), scope);
+        let ty = get_ty_nonvoid(&expr.ty, scope)?;
+        if !val.ty.is_error() && !can_cast(&val.ty, &ty) {
+            return Err(CompileError(
+                CompileErrorKind::InvalidCalculation(val.ty.to_string()),
+                Some(expr.ty.span),
+            ));
+        }
+        Ok((ExprKind::Cast(P::new(val)), ty))
+    }
+
+    fn lower_call_expr(
+        &mut self,
+        expr: &ast::CallExpr,
+        scope: &Scope,
+    ) -> CompileResult<(ExprKind, Ty)> {
+        let func_name = &expr.func.name;
+        let func_sig = self.global_scope.find(func_name).ok_or_else(|| {
+            CompileError(
+                CompileErrorKind::NoSuchSymbol(func_name.to_string()),
+                Some(expr.func.span),
+            )
+        })?;
+
+        let func_ty = func_sig.ty.get_func().ok_or_else(|| {
+            CompileError(
+                CompileErrorKind::TypeMismatch {
+                    expected: "function".into(),
+                    got: Some(func_sig.ty.to_string()),
+                },
+                Some(expr.func.span),
+            )
+        })?;
+
+        let args = expr
+            .params
+            .iter()
+            .map(|arg| self.lower_expr(arg, scope))
+            .collect::<Vec<_>>();
+
+        if args.len() != func_ty.params.len() {
+            return Err(CompileError(
+                CompileErrorKind::FuncParamSizeMismatch(args.len(), func_ty.params.len()),
+                Some(expr.span),
+            ));
+        }
+
+        for (arg, param_ty) in args.iter().zip(func_ty.params.iter()) {
+            check_type_eq!(arg.ty, param_ty.as_ref(), arg.span);
+        }
+
+        let func = SymbolRef {
+            id: func_sig.id,
+            name: func_name.clone(),
+            is_global: true,
+        };
+        Ok((ExprKind::Call { func, args }, func_ty.ret.as_ref().clone()))
+    }
+}
+
+fn lower_literal_expr(expr: &ast::LiteralExpr) -> CompileResult<(ExprKind, Ty)> {
+    Ok(match &expr.kind {
+        ast::LiteralKind::Integer(i) => {
+            check_int_literal(*i, false, expr.span)?;
+            (ExprKind::Int(*i), Ty::Int)
+        }
+        ast::LiteralKind::Float(f) => (ExprKind::Float(*f), Ty::Double),
+        // string literals are the global ID of the string
+        ast::LiteralKind::String(s) => (ExprKind::Str(s.as_str().into()), Ty::Int),
+        ast::LiteralKind::Char(c) => (ExprKind::Int(*c as u64), Ty::Int),
+        ast::LiteralKind::Bool(b) => (ExprKind::Bool(*b), Ty::Bool),
+    })
+}
+
+/// Lower `val`, the initializer of a global declared without a type, to find
+/// the global's type. Only the globals before it and library functions are in
+/// scope.
+fn infer_global_ty(val: &ast::Expr, global_scope: &Scope, errors: &ErrorSink) -> Ty {
+    let mut lowerer = FuncLowerer {
+        global_scope,
+        errors,
+        ret_ty: Ty::Void,
+        jump_targets: vec![],
+        next_loop_id: 0,
+        live: true,
+        jumped_to: IndexSet::new(),
+    };
+    let val = lowerer.lower_expr(val, global_scope);
+    errors
+        .ok_or_report(inferred_ty(&val.ty, val.span))
+        .unwrap_or(Ty::Error)
+}
+
+/// Add the variable declared in `decl` to `scope`, returning its ID and type.
+///
+/// Errors are reported to `errors`. The variable gets `Ty::Error` if its type
+/// is invalid or missing, and a fresh ID that's not in scope if its name is
+/// taken.
+fn add_decl_scope(decl: &ast::DeclStmt, scope: &mut Scope, errors: &ErrorSink) -> (u64, Ty) {
+    let ty = match &decl.ty {
+        Some(ty) => get_ty(ty, scope).and_then(|ty| match ty {
+            Ty::Void => Err(CompileError(
+                CompileErrorKind::VoidTypeVariable,
+                Some(decl.span),
+            )),
+            ty => Ok(ty),
+        }),
+        None => Err(CompileError(
+            CompileErrorKind::TypeAnnotationNeeded(decl.name.name.as_str().into()),
+            Some(decl.name.span),
+        )),
+    };
+    let ty = errors.ok_or_report(ty).unwrap_or(Ty::Error);
+    let id = insert_decl_scope(decl, ty.clone(), scope, errors);
+    (id, ty)
+}
+
+/// Add the variable declared in `decl` to `scope` with type `ty`, returning
+/// its ID, or a fresh ID that's not in scope if its name is taken.
+fn insert_decl_scope(decl: &ast::DeclStmt, ty: Ty, scope: &mut Scope, errors: &ErrorSink) -> u64 {
+    let name = decl.name.name.clone();
+    let symbol = Symbol::new(ty, decl.is_const, Some(decl.name.span));
+
+    match scope.insert(name, symbol) {
+        Some(u) => u,
+        None => {
+            let previous = scope.find_in_self(&decl.name.name).and_then(|sym| sym.span);
+            errors.report(CompileError {
+                kind: CompileErrorKind::DuplicateSymbol {
+                    name: decl.name.name.as_str().into(),
+                    previous,
+                },
+                span: Some(decl.name.span),
+            });
+            scope.get_new_id()
+        }
+    }
+}
+
+/// Add the struct type declared in `stmt` to `scope`, reporting errors to
+/// `errors`. Fields with invalid types get `Ty::Error`. `earlier` are the
+/// structs declared before it.
+fn add_struct_scope(
+    stmt: &ast::StructStmt,
+    earlier: &[ast::StructStmt],
+    scope: &mut Scope,
+    errors: &ErrorSink,
+) {
+    let mut fields: Vec<StructFieldTy> = vec![];
+    let mut offset = 0;
+    for (idx, field) in stmt.fields.iter().enumerate() {
+        let previous = stmt.fields[..idx]
+            .iter()
+            .find(|f| f.name.name == field.name.name);
+        if let Some(previous) = previous {
+            errors.report(CompileError(
+                CompileErrorKind::DuplicateSymbol {
+                    name: field.name.name.as_str().into(),
+                    previous: Some(previous.name.span),
+                },
+                Some(field.name.span),
+            ));
+            continue;
+        }
+        // fields may only use types declared before this struct, so a struct
+        // can never contain itself
+        let ty = get_ty_nonvoid(&field.ty, scope);
+        let ty = errors.ok_or_report(ty).unwrap_or(Ty::Error);
+        let size = ty.size_slot();
+        fields.push(StructFieldTy {
+            name: field.name.name.clone(),
+            ty: P::new(ty),
+            offset,
+        });
+        offset += size;
+    }
+
+    let ty = Ty::Struct(StructTy {
+        name: stmt.name.name.clone(),
+        fields,
+    });
+    if !scope.insert_type(stmt.name.name.clone(), ty) {
+        let previous = earlier
+            .iter()
+            .find(|s| s.name.name == stmt.name.name)
+            .map(|s| s.name.span);
+        errors.report(CompileError(
+            CompileErrorKind::DuplicateSymbol {
+                name: stmt.name.name.as_str().into(),
+                previous,
+            },
+            Some(stmt.name.span),
+        ));
+    }
+}
+
+fn get_ty(ty: &ast::TyDef, scope: &Scope) -> CompileResult<Ty> {
+    match &ty.kind {
+        ast::TyDefKind::Named(name) => Ok(match name.as_str() {
+            "int" => Ty::Int,
+            "double" => Ty::Double,
+            "bool" => Ty::Bool,
+            "void" => Ty::Void,
+            _ => {
+                return scope.find_type(name).cloned().ok_or_else(|| CompileError {
+                    kind: CompileErrorKind::UnknownType(name.as_str().into()),
+                    span: Some(ty.span),
+                })
+            }
+        }),
+        // every index into an empty array would fail the bounds check
+        ast::TyDefKind::Array { len: 0, .. } => Err(CompileError {
+            kind: CompileErrorKind::ZeroLengthArray,
+            span: Some(ty.span),
+        }),
+        ast::TyDefKind::Array { elem, len } => Ok(Ty::Array(ArrayTy {
+            elem: P::new(get_ty_nonvoid(elem, scope)?),
+            len: *len as usize,
+        })),
+    }
+}
+
+fn get_ty_nonvoid(ty: &ast::TyDef, scope: &Scope) -> CompileResult<Ty> {
+    match get_ty(ty, scope)? {
+        Ty::Void => Err(CompileError {
+            kind: CompileErrorKind::VoidTypeVariable,
+            span: Some(ty.span),
+        }),
+        ty => Ok(ty),
+    }
+}
+
+/// Get the type of a function parameter or return value, which must fit in a
+/// single value.
+fn get_value_ty(ty: &ast::TyDef, scope: &Scope) -> CompileResult<Ty> {
+    let res = get_ty(ty, scope)?;
+    check_not_aggregate(&res, ty.span)?;
+    Ok(res)
+}
+
+/// Check that an integer literal fits in `int`, allowing `i64::MIN` when
+/// it's negated.
+fn check_int_literal(val: u64, negated: bool, span: Span) -> CompileResult<()> {
+    let max = if negated {
+        i64::MIN.unsigned_abs()
+    } else {
+        i64::MAX as u64
+    };
+    if val > max {
+        Err(CompileError(
+            CompileErrorKind::IntegerOutOfRange,
+            Some(span),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The type of a variable declared without a type annotation, whose
+/// initializer at `span` has type `ty`. Only values can be stored in it.
+fn inferred_ty(ty: &Ty, span: Span) -> CompileResult<Ty> {
+    match ty {
+        Ty::Int | Ty::Double | Ty::Bool | Ty::Error => Ok(ty.clone()),
+        Ty::Void => Err(CompileError(CompileErrorKind::VoidTypeVariable, Some(span))),
+        Ty::Array(_) | Ty::Struct(_) => Err(CompileError(
+            CompileErrorKind::AggregateAsValue(ty.to_string()),
+            Some(span),
+        )),
+        Ty::Func(_) | Ty::Addr => Err(CompileError(
+            CompileErrorKind::TypeMismatch {
+                expected: "int, double or bool".into(),
+                got: Some(ty.to_string()),
+            },
+            Some(span),
+        )),
+    }
+}
+
+fn check_not_aggregate(ty: &Ty, span: Span) -> CompileResult<()> {
+    if ty.is_aggregate() {
+        Err(CompileError(
+            CompileErrorKind::AggregateAsValue(ty.to_string()),
+            Some(span),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn loop_label(label: &Option<ast::Ident>) -> Option<SmolStr> {
+    label.as_ref().map(|label| label.name.clone())
+}
+
+/// Whether `as` converts values of `from_ty` into `to_ty`
+fn can_cast(from_ty: &Ty, to_ty: &Ty) -> bool {
+    match from_ty {
+        Ty::Int | Ty::Addr => matches!(to_ty, Ty::Int | Ty::Addr | Ty::Double | Ty::Bool),
+        Ty::Double => matches!(to_ty, Ty::Int | Ty::Double | Ty::Bool),
+        Ty::Bool => matches!(to_ty, Ty::Int | Ty::Bool),
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) | Ty::Void | Ty::Error => false,
+    }
+}
+
+fn binary_op_result_ty(op: ast::BinaryOp, ty: &Ty) -> Option<Ty> {
+    match ty {
+        Ty::Int | Ty::Double | Ty::Addr => match op {
+            ast::BinaryOp::Add => Some(ty.clone()),
+            ast::BinaryOp::Sub => Some(ty.clone()),
+            ast::BinaryOp::Mul => Some(ty.clone()),
+            ast::BinaryOp::Div => Some(ty.clone()),
+            ast::BinaryOp::Mod
+            | ast::BinaryOp::Shl
+            | ast::BinaryOp::Shr
+            | ast::BinaryOp::BitAnd
+            | ast::BinaryOp::BitOr
+            | ast::BinaryOp::Xor => match ty {
+                Ty::Double => None,
+                _ => Some(ty.clone()),
+            },
+            ast::BinaryOp::Gt => Some(Ty::Bool),
+            ast::BinaryOp::Lt => Some(Ty::Bool),
+            ast::BinaryOp::Ge => Some(Ty::Bool),
+            ast::BinaryOp::Le => Some(Ty::Bool),
+            ast::BinaryOp::Eq => Some(Ty::Bool),
+            ast::BinaryOp::Neq => Some(Ty::Bool),
+            ast::BinaryOp::And | ast::BinaryOp::Or => None,
+        },
+        Ty::Bool => match op {
+            ast::BinaryOp::BitAnd
+            | ast::BinaryOp::BitOr
+            | ast::BinaryOp::Xor
+            | ast::BinaryOp::Eq
+            | ast::BinaryOp::Neq => Some(Ty::Bool),
+            _ => None,
+        },
+        Ty::Func(_) | Ty::Array(_) | Ty::Struct(_) | Ty::Void | Ty::Error => None,
+    }
+}
+
+fn unary_op_result_ty(op: ast::UnaryOp, ty: &Ty) -> Option<Ty> {
+    match op {
+        ast::UnaryOp::Neg | ast::UnaryOp::Pos => match ty {
+            Ty::Int | Ty::Double => Some(ty.clone()),
+            Ty::Addr
+            | Ty::Bool
+            | Ty::Func(_)
+            | Ty::Array(_)
+            | Ty::Struct(_)
+            | Ty::Void
+            | Ty::Error => None,
+        },
+        ast::UnaryOp::BitNot => match ty {
+            Ty::Int => Some(Ty::Int),
+            Ty::Addr
+            | Ty::Double
+            | Ty::Bool
+            | Ty::Func(_)
+            | Ty::Array(_)
+            | Ty::Struct(_)
+            | Ty::Void
+            | Ty::Error => None,
+        },
+        ast::UnaryOp::Not => match ty {
+            Ty::Int | Ty::Bool => Some(Ty::Bool),
+            Ty::Addr
+            | Ty::Double
+            | Ty::Func(_)
+            | Ty::Array(_)
+            | Ty::Struct(_)
+            | Ty::Void
+            | Ty::Error => None,
+        },
+    }
+}
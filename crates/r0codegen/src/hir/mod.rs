@@ -0,0 +1,469 @@
+//! The typed intermediate representation between the syntax tree and code
+//! generation. Every name is resolved to the symbol it refers to and every
+//! expression carries its type, so later stages never look anything up.
+
+mod lower;
+
+use crate::{err::CompileError, err::ErrorSink, ty::Ty};
+use r0syntax::{
+    ast::{self, BinaryOp, UnaryOp},
+    span::Span,
+    util::P,
+};
+use smol_str::SmolStr;
+use std::fmt::{self, Display, Formatter};
+
+pub(crate) use lower::lower_program;
+
+/// The ID of a variable or function, unique in the whole program
+pub type SymbolId = u64;
+
+/// The ID of a loop or switch, which `break` and `continue` refer to
+pub type LoopId = u32;
+
+/// Lower `tree` into HIR, returning every error found if it fails to type
+/// check
+pub fn lower(tree: &ast::Program) -> Result<Program, Vec<CompileError>> {
+    let errors = ErrorSink::new();
+    let program = lower_program(tree, &errors);
+    let (errors, _) = errors.into_parts();
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub globals: Vec<Var>,
+    pub funcs: Vec<Func>,
+    /// `_start`, which initializes the globals and calls `main`
+    pub start: Func,
+}
+
+/// A declared variable or parameter
+#[derive(Debug, Clone)]
+pub struct Var {
+    pub id: SymbolId,
+    pub name: SmolStr,
+    pub ty: Ty,
+    pub is_const: bool,
+    /// The span of the name
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Func {
+    pub id: SymbolId,
+    pub name: SmolStr,
+    /// The span of the name
+    pub span: Span,
+    pub params: Vec<Var>,
+    pub ret_ty: Ty,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+    /// Whether control can get to this statement at all
+    pub reachable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Block(Block),
+    While {
+        id: LoopId,
+        cond: Expr,
+        body: Block,
+    },
+    For {
+        id: LoopId,
+        init: Option<Box<Stmt>>,
+        cond: Option<Expr>,
+        step: Option<Expr>,
+        body: Block,
+    },
+    DoWhile {
+        id: LoopId,
+        body: Block,
+        cond: Expr,
+    },
+    /// `else if` is an `else` block holding just another `if`
+    If {
+        cond: Expr,
+        if_block: Block,
+        else_block: Option<Block>,
+    },
+    Switch {
+        id: LoopId,
+        expr: Expr,
+        cases: Vec<SwitchCase>,
+    },
+    Expr(Expr),
+    Decl {
+        var: Var,
+        init: Option<Expr>,
+    },
+    Return(Option<Expr>),
+    Break(LoopId),
+    Continue(LoopId),
+}
+
+#[derive(Debug, Clone)]
+pub struct SwitchCase {
+    pub values: Vec<i64>,
+    pub is_default: bool,
+    pub body: Block,
+}
+
+/// A reference to a variable or function
+#[derive(Debug, Clone)]
+pub struct SymbolRef {
+    pub id: SymbolId,
+    pub name: SmolStr,
+    pub is_global: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub ty: Ty,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    /// Integer and char literals, and negated integer literals
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+    Str(SmolStr),
+    /// The value stored in `place`
+    Load(Place),
+    /// `place = rhs`, or `place op= rhs`
+    Assign {
+        place: Place,
+        op: Option<BinaryOp>,
+        rhs: P<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: P<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: P<Expr>,
+        rhs: P<Expr>,
+    },
+    Cond {
+        cond: P<Expr>,
+        if_val: P<Expr>,
+        else_val: P<Expr>,
+    },
+    /// Conversion into the type of the expression
+    Cast(P<Expr>),
+    Call {
+        func: SymbolRef,
+        args: Vec<Expr>,
+    },
+    /// An expression that failed to type check
+    Error,
+}
+
+/// Something that can be assigned to
+#[derive(Debug, Clone)]
+pub struct Place {
+    pub kind: PlaceKind,
+    pub ty: Ty,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaceKind {
+    Var(SymbolRef),
+    /// An element of an array, whose length is in the type of `base`
+    Index {
+        base: P<Place>,
+        index: P<Expr>,
+    },
+    /// A field of a struct, `offset` slots from its start
+    Field {
+        base: P<Place>,
+        name: SmolStr,
+        offset: usize,
+    },
+}
+
+impl Place {
+    /// The variable this place is in
+    pub fn root_var(&self) -> &SymbolRef {
+        match &self.kind {
+            PlaceKind::Var(var) => var,
+            PlaceKind::Index { base, .. } | PlaceKind::Field { base, .. } => base.root_var(),
+        }
+    }
+
+    /// The variable this place is, if it's a whole variable
+    pub fn as_var(&self) -> Option<&SymbolRef> {
+        match &self.kind {
+            PlaceKind::Var(var) => Some(var),
+            _ => None,
+        }
+    }
+}
+
+impl Block {
+    /// The variables declared directly in this block
+    pub fn decls(&self) -> impl Iterator<Item = &Var> {
+        self.stmts.iter().filter_map(|stmt| match &stmt.kind {
+            StmtKind::Decl { var, .. } => Some(var),
+            _ => None,
+        })
+    }
+}
+
+fn binary_op_str(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Shl => "<<",
+        BinaryOp::Shr => ">>",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::Xor => "^",
+        BinaryOp::Gt => ">",
+        BinaryOp::Lt => "<",
+        BinaryOp::Ge => ">=",
+        BinaryOp::Le => "<=",
+        BinaryOp::Eq => "==",
+        BinaryOp::Neq => "!=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+    }
+}
+
+fn unary_op_str(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "-",
+        UnaryOp::Pos => "+",
+        UnaryOp::Not => "!",
+        UnaryOp::BitNot => "~",
+    }
+}
+
+// The text form looks like the source, with symbols written as `name#id` and
+// compound expressions written as `(expr: ty)`.
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for var in &self.globals {
+            writeln!(f, "{} {};", var.keyword(), var)?;
+        }
+        for func in self.funcs.iter().chain(std::iter::once(&self.start)) {
+            writeln!(f)?;
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+impl Var {
+    /// The keyword that declares this variable
+    fn keyword(&self) -> &'static str {
+        if self.is_const {
+            "const"
+        } else {
+            "let"
+        }
+    }
+}
+
+impl Display for Var {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}: {}", self.name, self.id, self.ty)
+    }
+}
+
+impl Display for Func {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "fn {}#{}(", self.name, self.id)?;
+        for (idx, param) in self.params.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            if param.is_const {
+                write!(f, "const ")?;
+            }
+            write!(f, "{}", param)?;
+        }
+        write!(f, ") -> {} ", self.ret_ty)?;
+        self.body.fmt_indented(f, 0)?;
+        writeln!(f)
+    }
+}
+
+impl Block {
+    fn fmt_indented(&self, f: &mut Formatter<'_>, indent: usize) -> fmt::Result {
+        writeln!(f, "{{")?;
+        for stmt in &self.stmts {
+            write!(f, "{:1$}", "", (indent + 1) * 4)?;
+            stmt.fmt_indented(f, indent + 1)?;
+            writeln!(f)?;
+        }
+        write!(f, "{:1$}}}", "", indent * 4)
+    }
+}
+
+impl Stmt {
+    fn fmt_indented(&self, f: &mut Formatter<'_>, indent: usize) -> fmt::Result {
+        match &self.kind {
+            StmtKind::Block(block) => block.fmt_indented(f, indent),
+            StmtKind::While { id, cond, body } => {
+                write!(f, "'L{}: while {} ", id, cond)?;
+                body.fmt_indented(f, indent)
+            }
+            StmtKind::For {
+                id,
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                write!(f, "'L{}: for (", id)?;
+                if let Some(init) = init {
+                    init.fmt_indented(f, indent)?;
+                } else {
+                    write!(f, ";")?;
+                }
+                if let Some(cond) = cond {
+                    write!(f, " {}", cond)?;
+                }
+                write!(f, ";")?;
+                if let Some(step) = step {
+                    write!(f, " {}", step)?;
+                }
+                write!(f, ") ")?;
+                body.fmt_indented(f, indent)
+            }
+            StmtKind::DoWhile { id, body, cond } => {
+                write!(f, "'L{}: do ", id)?;
+                body.fmt_indented(f, indent)?;
+                write!(f, " while {};", cond)
+            }
+            StmtKind::If {
+                cond,
+                if_block,
+                else_block,
+            } => {
+                write!(f, "if {} ", cond)?;
+                if_block.fmt_indented(f, indent)?;
+                if let Some(else_block) = else_block {
+                    write!(f, " else ")?;
+                    else_block.fmt_indented(f, indent)?;
+                }
+                Ok(())
+            }
+            StmtKind::Switch { id, expr, cases } => {
+                writeln!(f, "'L{}: switch {} {{", id, expr)?;
+                for case in cases {
+                    write!(f, "{:1$}", "", (indent + 1) * 4)?;
+                    for value in &case.values {
+                        write!(f, "case {}: ", value)?;
+                    }
+                    if case.is_default {
+                        write!(f, "default: ")?;
+                    }
+                    case.body.fmt_indented(f, indent + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{:1$}}}", "", indent * 4)
+            }
+            StmtKind::Expr(expr) => write!(f, "{};", expr),
+            StmtKind::Decl { var, init } => {
+                write!(f, "{} {}", var.keyword(), var)?;
+                if let Some(init) = init {
+                    write!(f, " = {}", init)?;
+                }
+                write!(f, ";")
+            }
+            StmtKind::Return(Some(val)) => write!(f, "return {};", val),
+            StmtKind::Return(None) => write!(f, "return;"),
+            StmtKind::Break(id) => write!(f, "break 'L{};", id),
+            StmtKind::Continue(id) => write!(f, "continue 'L{};", id),
+        }
+    }
+}
+
+impl Display for SymbolRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", self.name, self.id)
+    }
+}
+
+impl Display for Place {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            PlaceKind::Var(var) => write!(f, "{}", var),
+            PlaceKind::Index { base, index } => write!(f, "{}[{}]", base, index),
+            PlaceKind::Field { base, name, .. } => write!(f, "{}.{}", base, name),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Int(i) => write!(f, "{}", *i as i64),
+            ExprKind::Float(x) => write!(f, "{:?}", x),
+            ExprKind::Bool(b) => write!(f, "{}", b),
+            ExprKind::Str(s) => write!(f, "{:?}", s),
+            ExprKind::Load(place) => write!(f, "{}", place),
+            ExprKind::Assign {
+                place,
+                op: Some(op),
+                rhs,
+            } => write!(f, "{} {}= {}", place, binary_op_str(*op), rhs),
+            ExprKind::Assign {
+                place,
+                op: None,
+                rhs,
+            } => write!(f, "{} = {}", place, rhs),
+            ExprKind::Unary { op, expr } => {
+                write!(f, "({}{}: {})", unary_op_str(*op), expr, self.ty)
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                write!(f, "({} {} {}: {})", lhs, binary_op_str(*op), rhs, self.ty)
+            }
+            ExprKind::Cond {
+                cond,
+                if_val,
+                else_val,
+            } => write!(f, "({} ? {} : {}: {})", cond, if_val, else_val, self.ty),
+            ExprKind::Cast(expr) => write!(f, "({} as {})", expr, self.ty),
+            ExprKind::Call { func, args } => {
+                write!(f, "({}(", func)?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, "): {})", self.ty)
+            }
+            ExprKind::Error => write!(f, "{{error}}"),
+        }
+    }
+}
@@ -1,7 +1,11 @@
 mod code;
 pub mod err;
 pub mod generator;
+pub mod hir;
+pub mod lint;
+pub mod opt;
 pub mod scope;
+mod ssa;
 pub mod ty;
 
 pub trait Codegen {}
@@ -0,0 +1,408 @@
+use crate::{
+    err::ErrorSink,
+    hir::{self, SymbolId},
+};
+use indexmap::{IndexMap, IndexSet};
+use r0syntax::{diagnostic::Diagnostic, span::Span};
+use smol_str::SmolStr;
+use std::str::FromStr;
+
+/// A kind of warning, which can be allowed (turned off) on its own
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Lint {
+    /// Local variables and parameters that are never read
+    UnusedVariables,
+    /// Functions that can't be reached from `main`
+    UnusedFunctions,
+    /// Statements that can never run
+    UnreachableCode,
+    /// Assignments whose value is overwritten or dropped before being read
+    DeadAssignments,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::UnusedVariables,
+        Lint::UnusedFunctions,
+        Lint::UnreachableCode,
+        Lint::DeadAssignments,
+    ];
+
+    /// The name of this lint on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused-variables",
+            Lint::UnusedFunctions => "unused-functions",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::DeadAssignments => "dead-assignments",
+        }
+    }
+
+    /// The stable code of warnings from this lint
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "W0001",
+            Lint::UnusedFunctions => "W0002",
+            Lint::UnreachableCode => "W0003",
+            Lint::DeadAssignments => "W0004",
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL
+            .iter()
+            .find(|lint| lint.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names = Lint::ALL.iter().map(|l| l.name()).collect::<Vec<_>>();
+                format!(
+                    "Expected one of: warnings, {}; got: {}",
+                    names.join(", "),
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Warning {
+    pub fn new(lint: Lint, message: impl Into<String>, span: Option<Span>) -> Warning {
+        Warning {
+            lint,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diag =
+            Diagnostic::warning(self.lint.code(), self.message.as_str()).with_span(self.span);
+        match self.lint {
+            Lint::UnusedVariables => {
+                diag.with_note("prefix the name with an underscore to silence this warning")
+            }
+            _ => diag,
+        }
+    }
+}
+
+/// Run every lint over `program`, which must have type checked
+pub(crate) fn check_program(program: &hir::Program, errors: &ErrorSink) {
+    let mut calls = IndexMap::new();
+    for func in program.funcs.iter().chain(std::iter::once(&program.start)) {
+        let mut lints = FuncLints::new(errors);
+        lints.check_func(func);
+        calls.insert(func.name.clone(), lints.calls);
+    }
+    check_unused_funcs(program, &calls, errors);
+}
+
+/// Warn about functions that can't be reached from `_start`, which calls
+/// `main`.
+fn check_unused_funcs(
+    program: &hir::Program,
+    calls: &IndexMap<SmolStr, IndexSet<SmolStr>>,
+    errors: &ErrorSink,
+) {
+    if errors.is_allowed(Lint::UnusedFunctions) {
+        return;
+    }
+    let mut reachable = IndexSet::<&str>::new();
+    let mut stack = vec![program.start.name.as_str()];
+    while let Some(func) = stack.pop() {
+        if !reachable.insert(func) {
+            continue;
+        }
+        if let Some(callees) = calls.get(func) {
+            stack.extend(callees.iter().map(|x| x.as_str()));
+        }
+    }
+
+    for func in &program.funcs {
+        let name = func.name.as_str();
+        if !reachable.contains(name) && !name.starts_with('_') {
+            errors.warn(Warning::new(
+                Lint::UnusedFunctions,
+                format!("function `{}` is never called from `main`", name),
+                Some(func.span),
+            ));
+        }
+    }
+}
+
+/// The lints inside one function, found in a single walk over its body
+struct FuncLints<'a> {
+    errors: &'a ErrorSink,
+    /// Local variables and parameters that are read somewhere
+    read_vars: IndexSet<SymbolId>,
+    /// Assignments to local variables not read yet in the current straight
+    /// line of code, by variable
+    pending_stores: IndexMap<SymbolId, Span>,
+    /// Assignments to local variables that are never read
+    dead_stores: Vec<(SymbolId, Span)>,
+    /// The functions called
+    calls: IndexSet<SmolStr>,
+}
+
+impl<'a> FuncLints<'a> {
+    fn new(errors: &'a ErrorSink) -> FuncLints<'a> {
+        FuncLints {
+            errors,
+            read_vars: IndexSet::new(),
+            pending_stores: IndexMap::new(),
+            dead_stores: vec![],
+            calls: IndexSet::new(),
+        }
+    }
+
+    fn warn(&self, lint: Lint, message: String, span: Span) {
+        self.errors.warn(Warning::new(lint, message, Some(span)));
+    }
+
+    fn check_func(&mut self, func: &hir::Func) {
+        self.walk_stmts(&func.body, true);
+        self.check_unused_vars(func.params.iter().chain(func.body.decls()));
+    }
+
+    /// Record that local variable `var` is read
+    fn mark_read(&mut self, var: &hir::SymbolRef) {
+        if !var.is_global {
+            self.read_vars.insert(var.id);
+            self.pending_stores.remove(&var.id);
+        }
+    }
+
+    /// Record an assignment to `var`, which is dead if the previous one is
+    /// still not read
+    fn record_store(&mut self, var: SymbolId, span: Span) {
+        if let Some(prev) = self.pending_stores.insert(var, span) {
+            self.dead_stores.push((var, prev));
+        }
+    }
+
+    /// Forget assignments not read yet, at a point where control flow splits
+    /// or merges and they may be read elsewhere
+    fn flush_stores(&mut self) {
+        self.pending_stores.clear();
+    }
+
+    /// Warn about the variables in `vars`, which just went out of scope, that
+    /// are never read, and about dead assignments to the ones that are.
+    fn check_unused_vars<'v>(&mut self, vars: impl Iterator<Item = &'v hir::Var>) {
+        for var in vars {
+            // the last assignment in scope is dead if it's never read
+            if let Some(store) = self.pending_stores.remove(&var.id) {
+                self.dead_stores.push((var.id, store));
+            }
+            if var.name.starts_with('_') {
+                continue;
+            }
+            if !self.read_vars.contains(&var.id) {
+                self.warn(
+                    Lint::UnusedVariables,
+                    format!("`{}` is never read", var.name),
+                    var.span,
+                );
+                continue;
+            }
+            for (_, store) in self.dead_stores.iter().filter(|(id, _)| *id == var.id) {
+                self.warn(
+                    Lint::DeadAssignments,
+                    format!("value assigned to `{}` is never read", var.name),
+                    *store,
+                );
+            }
+        }
+    }
+
+    fn check_block(&mut self, block: &hir::Block, reachable: bool) {
+        self.walk_stmts(block, reachable);
+        self.check_unused_vars(block.decls());
+    }
+
+    /// Walk the statements in `block`, which is `reachable` as a whole. Only
+    /// the first statement that can't be reached is warned about.
+    fn walk_stmts(&mut self, block: &hir::Block, reachable: bool) {
+        let mut warned_unreachable = !reachable;
+        for stmt in &block.stmts {
+            if !warned_unreachable && !stmt.reachable {
+                self.warn(
+                    Lint::UnreachableCode,
+                    "unreachable statement".into(),
+                    stmt.span,
+                );
+                warned_unreachable = true;
+            }
+            self.walk_stmt(stmt);
+        }
+    }
+
+    fn walk_stmt(&mut self, stmt: &hir::Stmt) {
+        use hir::StmtKind;
+        // assignments before and inside branches and loops may be read on
+        // another path
+        let splits_control_flow = matches!(
+            stmt.kind,
+            StmtKind::While { .. }
+                | StmtKind::For { .. }
+                | StmtKind::DoWhile { .. }
+                | StmtKind::If { .. }
+                | StmtKind::Switch { .. }
+        );
+        if splits_control_flow {
+            self.flush_stores();
+        }
+        match &stmt.kind {
+            StmtKind::Block(block) => self.check_block(block, stmt.reachable),
+            StmtKind::While { cond, body, .. } => {
+                self.walk_expr(cond);
+                self.check_block(body, stmt.reachable);
+            }
+            StmtKind::For {
+                init,
+                cond,
+                step,
+                body,
+                ..
+            } => {
+                if let Some(init) = init {
+                    self.walk_stmt(init);
+                }
+                if let Some(cond) = cond {
+                    self.walk_expr(cond);
+                }
+                self.check_block(body, stmt.reachable);
+                if let Some(step) = step {
+                    self.walk_expr(step);
+                }
+                self.flush_stores();
+                if let Some(init) = init {
+                    if let StmtKind::Decl { var, .. } = &init.kind {
+                        self.check_unused_vars(std::iter::once(var));
+                    }
+                }
+            }
+            StmtKind::DoWhile { body, cond, .. } => {
+                self.check_block(body, stmt.reachable);
+                self.walk_expr(cond);
+            }
+            StmtKind::If {
+                cond,
+                if_block,
+                else_block,
+            } => {
+                self.walk_expr(cond);
+                self.check_block(if_block, stmt.reachable);
+                // only one of the branches is run
+                self.flush_stores();
+                if let Some(else_block) = else_block {
+                    self.check_block(else_block, stmt.reachable);
+                }
+            }
+            StmtKind::Switch { expr, cases, .. } => {
+                self.walk_expr(expr);
+                for case in cases {
+                    // only one of the cases is run
+                    self.flush_stores();
+                    self.check_block(&case.body, stmt.reachable);
+                }
+            }
+            StmtKind::Expr(expr) => self.walk_expr(expr),
+            StmtKind::Decl { var, init } => {
+                if let Some(init) = init {
+                    self.walk_expr(init);
+                    self.record_store(var.id, stmt.span);
+                }
+            }
+            StmtKind::Return(val) => {
+                if let Some(val) = val {
+                    self.walk_expr(val);
+                }
+                // nothing can be read after returning
+                let pending_stores = std::mem::take(&mut self.pending_stores);
+                self.dead_stores.extend(pending_stores);
+            }
+            StmtKind::Break(_) | StmtKind::Continue(_) => self.flush_stores(),
+        }
+        if splits_control_flow {
+            self.flush_stores();
+        }
+    }
+
+    /// Walk `place`, where the whole variables that elements or fields are
+    /// taken from count as read
+    /// Walk the index expressions of `place`. Accessing the place itself is
+    /// up to the caller, since writing to an element doesn't read the array.
+    fn walk_place(&mut self, place: &hir::Place) {
+        match &place.kind {
+            hir::PlaceKind::Var(_) => {}
+            hir::PlaceKind::Index { base, index } => {
+                self.walk_place(base);
+                self.walk_expr(index);
+            }
+            hir::PlaceKind::Field { base, .. } => self.walk_place(base),
+        }
+    }
+
+    fn walk_expr(&mut self, expr: &hir::Expr) {
+        use hir::ExprKind;
+        match &expr.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::Error => {}
+            ExprKind::Load(place) => {
+                self.walk_place(place);
+                self.mark_read(place.root_var());
+            }
+            ExprKind::Assign { place, op, rhs } => {
+                self.walk_place(place);
+                self.walk_expr(rhs);
+                // `x op= y` reads `x` first
+                if op.is_some() {
+                    self.mark_read(place.root_var());
+                }
+                if let Some(var) = place.as_var() {
+                    if !var.is_global {
+                        self.record_store(var.id, expr.span);
+                    }
+                }
+            }
+            ExprKind::Unary { expr, .. } | ExprKind::Cast(expr) => self.walk_expr(expr),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.walk_expr(lhs);
+                self.walk_expr(rhs);
+            }
+            ExprKind::Cond {
+                cond,
+                if_val,
+                else_val,
+            } => {
+                self.walk_expr(cond);
+                // only one of the arms is run
+                self.flush_stores();
+                self.walk_expr(if_val);
+                self.flush_stores();
+                self.walk_expr(else_val);
+                self.flush_stores();
+            }
+            ExprKind::Call { func, args } => {
+                for arg in args {
+                    self.walk_expr(arg);
+                }
+                self.calls.insert(func.name.clone());
+            }
+        }
+    }
+}
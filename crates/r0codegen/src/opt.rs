@@ -0,0 +1,303 @@
+//! Optimisation passes over the basic blocks of a function, run before they
+//! are arranged into the final code.
+
+use bit_set::BitSet;
+use r0vm::opcodes::Op;
+
+use crate::code::{BasicBlock, JumpInst};
+
+/// The highest meaningful optimisation level
+pub const MAX_OPT_LEVEL: u8 = 3;
+
+/// Passes are repeated until nothing changes, but at most this many times
+const MAX_ROUNDS: usize = 8;
+
+/// A transformation over the basic blocks of one function
+pub trait Pass {
+    /// Run the pass on `blocks`, where control enters at `start`. Returns
+    /// whether anything changed.
+    fn run(&self, blocks: &mut Vec<BasicBlock>, start: &mut usize) -> bool;
+}
+
+/// Runs a list of passes until none of them changes anything
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager::default()
+    }
+
+    /// The passes run at optimisation level `level`:
+    ///
+    /// - 0: none
+    /// - 1: constant folding and peephole rewrites inside each block
+    /// - 2: also jump threading and dead block removal
+    /// - 3: the same, before and after the function goes through SSA form for
+    ///   value numbering, loop-invariant code motion and slot allocation
+    pub fn for_level(level: u8) -> PassManager {
+        let mut manager = PassManager::new();
+        if level >= 1 {
+            manager.add_pass(ConstFold);
+            manager.add_pass(Peephole);
+        }
+        if level >= 2 {
+            manager.add_pass(JumpThreading);
+            manager.add_pass(DeadBlocks);
+        }
+        manager
+    }
+
+    pub fn add_pass(&mut self, pass: impl Pass + 'static) {
+        self.passes.push(Box::new(pass));
+    }
+
+    /// Run every pass on `blocks`, and return the new start block. Every block
+    /// reachable from `start` must have its jump set.
+    pub fn run(&self, blocks: &mut Vec<BasicBlock>, start: usize) -> usize {
+        let mut start = start;
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for pass in &self.passes {
+                changed |= pass.run(blocks, &mut start);
+            }
+            if !changed {
+                break;
+            }
+        }
+        start
+    }
+}
+
+fn reachable(blocks: &[BasicBlock], start: usize) -> BitSet {
+    let mut visited = BitSet::with_capacity(blocks.len());
+    let mut stack = vec![start];
+    while let Some(id) = stack.pop() {
+        if visited.insert(id) {
+            stack.extend(blocks[id].jump.successors());
+        }
+    }
+    visited
+}
+
+/// Rebuild `code` one instruction at a time, letting `rule` replace the end
+/// of what's been built so far. `rule` returns how many instructions it
+/// replaces and the instruction to put in their place, which must be fewer.
+/// Returns whether anything was replaced.
+fn rewrite(code: &mut Vec<Op>, rule: impl Fn(&[Op]) -> Option<(usize, Option<Op>)>) -> bool {
+    let mut changed = false;
+    let mut out = Vec::with_capacity(code.len());
+    for op in code.drain(..) {
+        out.push(op);
+        while let Some((len, replacement)) = rule(&out) {
+            out.truncate(out.len() - len);
+            out.extend(replacement);
+            changed = true;
+        }
+    }
+    *code = out;
+    changed
+}
+
+/// Evaluate instructions whose operands are all pushed constants, and
+/// conditional jumps on constants
+pub struct ConstFold;
+
+impl ConstFold {
+    pub(crate) fn fold_unary(op: Op, x: u64) -> Option<u64> {
+        Some(match op {
+            Op::NegI => x.wrapping_neg(),
+            Op::Not => (x == 0) as u64,
+            Op::SetLt => ((x as i64) < 0) as u64,
+            Op::SetGt => ((x as i64) > 0) as u64,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn fold_binary(op: Op, lhs: u64, rhs: u64) -> Option<u64> {
+        // shift amounts are taken modulo 64, like the VM does
+        let shift = rhs as u32;
+        Some(match op {
+            Op::AddI => lhs.wrapping_add(rhs),
+            Op::SubI => lhs.wrapping_sub(rhs),
+            Op::MulI => lhs.wrapping_mul(rhs),
+            // leave division by zero to fail at runtime
+            Op::DivI => (lhs as i64).checked_div(rhs as i64)? as u64,
+            Op::DivU => lhs.checked_div(rhs)?,
+            Op::Shl => lhs.wrapping_shl(shift),
+            Op::Shr => (lhs as i64).wrapping_shr(shift) as u64,
+            Op::ShrL => lhs.wrapping_shr(shift),
+            Op::And => lhs & rhs,
+            Op::Or => lhs | rhs,
+            Op::Xor => lhs ^ rhs,
+            Op::CmpI => (lhs as i64).cmp(&(rhs as i64)) as i64 as u64,
+            Op::CmpU => lhs.cmp(&rhs) as i64 as u64,
+            _ => return None,
+        })
+    }
+}
+
+impl Pass for ConstFold {
+    fn run(&self, blocks: &mut Vec<BasicBlock>, _start: &mut usize) -> bool {
+        let mut changed = false;
+        for bb in blocks.iter_mut() {
+            changed |= rewrite(&mut bb.code, |code| match *code {
+                [.., Op::Push(lhs), Op::Push(rhs), op] => {
+                    Some((3, Some(Op::Push(Self::fold_binary(op, lhs, rhs)?))))
+                }
+                [.., Op::Push(x), op] => Some((2, Some(Op::Push(Self::fold_unary(op, x)?)))),
+                _ => None,
+            });
+
+            if let (Some(Op::Push(cond)), JumpInst::JumpIf(t, f)) = (bb.code.last(), bb.jump) {
+                bb.jump = JumpInst::Jump(if *cond != 0 { t } else { f });
+                bb.code.pop();
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Remove instruction sequences that have no effect
+pub struct Peephole;
+
+impl Pass for Peephole {
+    fn run(&self, blocks: &mut Vec<BasicBlock>, _start: &mut usize) -> bool {
+        let mut changed = false;
+        for bb in blocks.iter_mut() {
+            changed |= rewrite(&mut bb.code, |code| match *code {
+                [.., Op::Nop] | [.., Op::StackAlloc(0)] | [.., Op::PopN(0)] => Some((1, None)),
+                // values that are pushed and dropped right away
+                [.., Op::Push(_), Op::Pop]
+                | [.., Op::Dup, Op::Pop]
+                | [.., Op::LocA(_), Op::Pop]
+                | [.., Op::ArgA(_), Op::Pop]
+                | [.., Op::GlobA(_), Op::Pop] => Some((2, None)),
+                // identity operations
+                [.., Op::Push(0), Op::AddI]
+                | [.., Op::Push(0), Op::SubI]
+                | [.., Op::Push(0), Op::Or]
+                | [.., Op::Push(0), Op::Xor]
+                | [.., Op::Push(0), Op::Shl]
+                | [.., Op::Push(0), Op::Shr]
+                | [.., Op::Push(0), Op::ShrL]
+                | [.., Op::Push(1), Op::MulI]
+                | [.., Op::NegI, Op::NegI] => Some((2, None)),
+                // storing a variable into itself
+                [.., Op::LocA(a), Op::LocA(b), Op::Load64, Op::Store64]
+                | [.., Op::ArgA(a), Op::ArgA(b), Op::Load64, Op::Store64]
+                | [.., Op::GlobA(a), Op::GlobA(b), Op::Load64, Op::Store64]
+                    if a == b =>
+                {
+                    Some((4, None))
+                }
+                _ => None,
+            });
+        }
+        changed
+    }
+}
+
+/// Skip blocks that do nothing but jump elsewhere, and merge blocks into
+/// their only predecessor when it jumps straight to them
+pub struct JumpThreading;
+
+impl JumpThreading {
+    /// Where control really ends up after jumping to `id`
+    fn resolve(blocks: &[BasicBlock], id: usize) -> usize {
+        let mut id = id;
+        // bounded, since empty blocks may jump around in a loop
+        for _ in 0..blocks.len() {
+            match blocks[id].jump {
+                JumpInst::Jump(to) if blocks[id].code.is_empty() && to != id => id = to,
+                _ => break,
+            }
+        }
+        id
+    }
+}
+
+impl Pass for JumpThreading {
+    fn run(&self, blocks: &mut Vec<BasicBlock>, start: &mut usize) -> bool {
+        let mut changed = false;
+
+        let resolved = (0..blocks.len())
+            .map(|id| Self::resolve(blocks, id))
+            .collect::<Vec<_>>();
+        for bb in blocks.iter_mut() {
+            let jump = match bb.jump {
+                JumpInst::Jump(to) => JumpInst::Jump(resolved[to]),
+                JumpInst::JumpIf(t, f) if resolved[t] == resolved[f] => {
+                    // the condition is still computed, so drop it
+                    bb.code.push(Op::Pop);
+                    JumpInst::Jump(resolved[t])
+                }
+                JumpInst::JumpIf(t, f) => JumpInst::JumpIf(resolved[t], resolved[f]),
+                jump => jump,
+            };
+            changed |= jump.successors().ne(bb.jump.successors());
+            bb.jump = jump;
+        }
+        if resolved[*start] != *start {
+            *start = resolved[*start];
+            changed = true;
+        }
+
+        let live = reachable(blocks, *start);
+        let mut preds = vec![0usize; blocks.len()];
+        for id in live.iter() {
+            for to in blocks[id].jump.successors() {
+                preds[to] += 1;
+            }
+        }
+        for id in live.iter() {
+            while let JumpInst::Jump(to) = blocks[id].jump {
+                if to == id || to == *start || preds[to] != 1 {
+                    break;
+                }
+                let next = std::mem::replace(&mut blocks[to], BasicBlock::new());
+                blocks[id].code.extend(next.code);
+                blocks[id].jump = next.jump;
+                // the merged block is now unreachable
+                blocks[to].jump = JumpInst::Unreachable;
+                preds[to] = 0;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Remove blocks that can't be reached from the start block
+pub struct DeadBlocks;
+
+impl Pass for DeadBlocks {
+    fn run(&self, blocks: &mut Vec<BasicBlock>, start: &mut usize) -> bool {
+        let live = reachable(blocks, *start);
+        if live.len() == blocks.len() {
+            return false;
+        }
+
+        let mut new_ids = vec![usize::MAX; blocks.len()];
+        for (new_id, old_id) in live.iter().enumerate() {
+            new_ids[old_id] = new_id;
+        }
+        let old_blocks = std::mem::take(blocks);
+        for (old_id, mut bb) in old_blocks.into_iter().enumerate() {
+            if !live.contains(old_id) {
+                continue;
+            }
+            bb.jump = match bb.jump {
+                JumpInst::Jump(to) => JumpInst::Jump(new_ids[to]),
+                JumpInst::JumpIf(t, f) => JumpInst::JumpIf(new_ids[t], new_ids[f]),
+                jump => jump,
+            };
+            blocks.push(bb);
+        }
+        *start = new_ids[*start];
+        true
+    }
+}
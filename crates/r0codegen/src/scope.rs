@@ -4,6 +4,7 @@ use indexmap::IndexMap;
 use smol_str::SmolStr;
 
 use crate::ty::Ty;
+use r0syntax::span::Span;
 
 #[derive(Debug)]
 pub struct SymbolIdGenerator {
@@ -27,6 +28,7 @@ pub struct Scope<'p> {
     symbol_gen: &'p RefCell<SymbolIdGenerator>,
     pub parent: Option<&'p Scope<'p>>,
     pub vars: IndexMap<SmolStr, Symbol>,
+    pub types: IndexMap<SmolStr, Ty>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -36,6 +38,7 @@ impl<'p> Scope<'p> {
             symbol_gen: parent.symbol_gen,
             parent: Some(parent),
             vars: IndexMap::new(),
+            types: IndexMap::new(),
         }
     }
 
@@ -44,6 +47,7 @@ impl<'p> Scope<'p> {
             symbol_gen,
             parent: None,
             vars: IndexMap::new(),
+            types: IndexMap::new(),
         }
     }
 
@@ -91,6 +95,28 @@ impl<'p> Scope<'p> {
         }
     }
 
+    pub fn find_type<'s>(&'s self, name: &str) -> Option<&'s Ty> {
+        let self_res = self.types.get(name);
+
+        if self_res.is_none() {
+            if let Some(p) = self.parent {
+                return p.find_type(name);
+            }
+        }
+        self_res
+    }
+
+    /// Insert a user-defined type. Returns `false` if the name is taken.
+    pub fn insert_type(&mut self, name: SmolStr, ty: Ty) -> bool {
+        match self.types.entry(name) {
+            indexmap::map::Entry::Occupied(_) => false,
+            indexmap::map::Entry::Vacant(v) => {
+                v.insert(ty);
+                true
+            }
+        }
+    }
+
     pub fn get_new_id(&self) -> u64 {
         self.symbol_gen.borrow_mut().next()
     }
@@ -101,13 +127,16 @@ pub struct Symbol {
     pub id: u64,
     pub ty: Ty,
     pub is_const: bool,
+    /// Where the symbol is defined, if it's in the source
+    pub span: Option<Span>,
 }
 
 impl Symbol {
-    pub fn new(ty: Ty, is_const: bool) -> Symbol {
+    pub fn new(ty: Ty, is_const: bool, span: Option<Span>) -> Symbol {
         Symbol {
             ty,
             is_const,
+            span,
             id: 0,
         }
     }
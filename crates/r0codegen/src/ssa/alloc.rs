@@ -0,0 +1,477 @@
+//! Lowering SSA form back into basic blocks of stack code.
+//!
+//! Instructions run in the order they're in, and a value used once, by the
+//! instruction that finds it on top of the operand stack, stays there. Every
+//! other value lives in a slot, and values that are never live at the same
+//! time share one. Phis are assigned by copies at the end of each predecessor.
+
+use super::{BlockId, Func, Slot, Terminator, Value, ValueKind};
+use crate::code::{BasicBlock, JumpInst};
+use bit_set::BitSet;
+use r0vm::opcodes::Op;
+use std::collections::HashMap;
+
+/// How an instruction finds its operands
+#[derive(Debug, Clone, Copy, Default)]
+struct Shape {
+    /// How many rematerializable operands are pushed before the stacked ones
+    lead: usize,
+    /// How many operands, after the leading ones, are already on the stack
+    stacked: usize,
+    /// The first instruction of the expression tree this one ends
+    tree_start: Value,
+}
+
+/// Where a copy at the end of a block gets its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Slot(Slot),
+    Remat(Value),
+}
+
+struct Lowering {
+    func: Func,
+    rpo: Vec<BlockId>,
+    uses: Vec<usize>,
+    shapes: Vec<Shape>,
+    /// Values consumed from the operand stack
+    on_stack: BitSet,
+    slots: Vec<Option<Slot>>,
+}
+
+impl Func {
+    /// Lower into basic blocks, returning them, the start block and the number
+    /// of local slots used
+    pub(crate) fn lower(mut self) -> (Vec<BasicBlock>, usize, u32) {
+        self.split_critical_edges();
+        let rpo = self.reverse_postorder();
+        let mut lowering = Lowering {
+            uses: vec![0; self.values.len()],
+            shapes: vec![Shape::default(); self.values.len()],
+            on_stack: BitSet::with_capacity(self.values.len()),
+            slots: vec![None; self.values.len()],
+            func: self,
+            rpo,
+        };
+        lowering.count_uses();
+        lowering.shape();
+        let loc_slots = lowering.allocate();
+        lowering.emit(loc_slots)
+    }
+
+    /// Split the edges from blocks that branch to blocks with phis, so the
+    /// copies for the phis can go at the end of a block with one successor
+    fn split_critical_edges(&mut self) {
+        for id in self.reverse_postorder() {
+            if let Terminator::Branch(_, t, f) = self.blocks[id].term {
+                for to in [t, f].iter() {
+                    if !self.blocks[*to].phis.is_empty() {
+                        self.split_edge(id, *to);
+                    }
+                }
+            }
+        }
+    }
+
+    fn has_result(&self, value: Value) -> bool {
+        match self.values[value] {
+            ValueKind::Inst(op, _) => super::op_stack_effect(op).is_some_and(|(_, n)| n > 0),
+            ValueKind::Call { ret_slots, .. } => ret_slots > 0,
+            _ => true,
+        }
+    }
+}
+
+impl Lowering {
+    fn count_uses(&mut self) {
+        for id in &self.rpo {
+            let block = &self.func.blocks[*id];
+            for value in block.phis.iter().chain(&block.insts) {
+                for arg in self.func.operands(*value) {
+                    self.uses[*arg] += 1;
+                }
+            }
+            if let Terminator::Branch(cond, ..) = block.term {
+                self.uses[cond] += 1;
+            }
+        }
+    }
+
+    /// Decide which values stay on the operand stack, by keeping track of the
+    /// ones that could as each block runs
+    fn shape(&mut self) {
+        for id in self.rpo.clone() {
+            let block = &self.func.blocks[id];
+
+            // values used once, in this block, apart from by phis
+            let mut local_uses = HashMap::new();
+            for value in &block.insts {
+                for arg in self.func.operands(*value) {
+                    *local_uses.entry(*arg).or_insert(0) += 1;
+                }
+            }
+            if let Terminator::Branch(cond, ..) = block.term {
+                *local_uses.entry(cond).or_insert(0) += 1;
+            }
+            let (func, uses) = (&self.func, &self.uses);
+            let is_candidate = |value: Value| {
+                uses[value] == 1 && local_uses.get(&value) == Some(&1) && func.has_result(value)
+            };
+
+            let mut stack: Vec<Value> = vec![];
+            for value in &block.insts {
+                // Operands from before this block can be pushed below the
+                // stacked ones, when the expression tree starts
+                let args = self.func.operands(*value);
+                let lead_max = args
+                    .iter()
+                    .take_while(|arg| !block.insts.contains(*arg))
+                    .count();
+                let mut shape = Shape {
+                    lead: 0,
+                    stacked: 0,
+                    tree_start: *value,
+                };
+                for lead in (0..=lead_max).rev() {
+                    let rest = &args[lead..];
+                    let stacked = (1..=rest.len().min(stack.len()))
+                        .rev()
+                        .find(|n| stack[stack.len() - n..] == rest[..*n])
+                        .unwrap_or(0);
+                    if stacked > 0 {
+                        shape = Shape {
+                            lead,
+                            stacked,
+                            tree_start: self.shapes[rest[0]].tree_start,
+                        };
+                        break;
+                    }
+                }
+                stack.truncate(stack.len() - shape.stacked);
+                for arg in &args[shape.lead..shape.lead + shape.stacked] {
+                    self.on_stack.insert(*arg);
+                }
+                self.shapes[*value] = shape;
+                if is_candidate(*value) {
+                    stack.push(*value);
+                }
+            }
+            if let Terminator::Branch(cond, ..) = block.term {
+                if stack.last() == Some(&cond) {
+                    self.on_stack.insert(cond);
+                }
+            }
+        }
+    }
+
+    fn needs_slot(&self, value: Value) -> bool {
+        self.uses[value] > 0
+            && !self.on_stack.contains(value)
+            && !self.func.is_rematerializable(value)
+    }
+
+    /// The operands `value` reads from slots. Leading operands are read when
+    /// its expression tree starts, but count as read by `value` itself.
+    fn slot_operands(&self, value: Value) -> impl Iterator<Item = Value> + '_ {
+        let shape = self.shapes[value];
+        let args = self.func.operands(value);
+        args[..shape.lead]
+            .iter()
+            .chain(&args[shape.lead + shape.stacked..])
+            .copied()
+            .filter(move |arg| self.needs_slot(*arg))
+    }
+
+    /// The values copied into the phis of the successor of `id`, if it has
+    /// any, paired with the phis
+    fn phi_copies(&self, id: BlockId) -> Vec<(Value, Value)> {
+        let to = match self.func.blocks[id].term {
+            Terminator::Jump(to) => to,
+            _ => return vec![],
+        };
+        let block = &self.func.blocks[to];
+        let idx = match block.preds.iter().position(|pred| *pred == id) {
+            Some(idx) => idx,
+            None => return vec![],
+        };
+        block
+            .phis
+            .iter()
+            .filter(|phi| self.needs_slot(**phi))
+            .map(|phi| (*phi, self.func.operands(*phi)[idx]))
+            .collect()
+    }
+
+    /// Walk `id` backwards from `live` at its end, calling `def` with each value
+    /// defined and what's live right after it. Leaves what's live at the start
+    /// of the block in `live`.
+    fn walk_block(&self, id: BlockId, live: &mut BitSet, mut def: impl FnMut(Value, &BitSet)) {
+        let block = &self.func.blocks[id];
+        if let Terminator::Branch(cond, ..) = block.term {
+            if self.needs_slot(cond) {
+                live.insert(cond);
+            }
+        }
+
+        // the copies happen at once, so they all count as defined together
+        let copies = self.phi_copies(id);
+        for (phi, _) in &copies {
+            def(*phi, live);
+        }
+        for (phi, _) in &copies {
+            live.remove(*phi);
+        }
+        for (_, arg) in &copies {
+            if self.needs_slot(*arg) {
+                live.insert(*arg);
+            }
+        }
+
+        for value in block.insts.iter().rev() {
+            if self.needs_slot(*value) {
+                def(*value, live);
+                live.remove(*value);
+            }
+            for arg in self.slot_operands(*value) {
+                live.insert(arg);
+            }
+        }
+    }
+
+    /// Give every value that needs one a slot, and return the number of local
+    /// slots used
+    fn allocate(&mut self) -> u32 {
+        let len = self.func.values.len();
+        let mut live_in = vec![BitSet::with_capacity(len); self.func.blocks.len()];
+        let live_out = |live_in: &[BitSet], id: BlockId| {
+            let mut live = BitSet::with_capacity(len);
+            for succ in self.func.blocks[id].term.successors() {
+                live.union_with(&live_in[succ]);
+            }
+            live
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in self.rpo.iter().rev() {
+                let mut live = live_out(&live_in, *id);
+                self.walk_block(*id, &mut live, |_, _| {});
+                if live != live_in[*id] {
+                    live_in[*id] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut interference = vec![BitSet::new(); len];
+        for id in &self.rpo {
+            let mut live = live_out(&live_in, *id);
+            self.walk_block(*id, &mut live, |value, live| {
+                for other in live.iter().filter(|other| *other != value) {
+                    interference[value].insert(other);
+                    interference[other].insert(value);
+                }
+            });
+        }
+        // everything live on entry is a parameter, and they're all different
+        // slots already
+
+        let frame = &self.func.frame;
+        let promoted = &self.func.promoted;
+        let reserved = |x: u32| x < frame.loc_slots && !promoted.contains(&Slot::Loc(x));
+        for value in 0..len {
+            if let ValueKind::Param(x) = self.func.values[value] {
+                self.slots[value] = Some(Slot::Arg(x));
+            }
+        }
+        let args = promoted
+            .iter()
+            .copied()
+            .filter(|slot| matches!(slot, Slot::Arg(_)))
+            .collect::<Vec<_>>();
+        // a phi and the values it merges are tried in the same slot first,
+        // which saves copying between them
+        let mut related = vec![vec![]; len];
+        for id in &self.rpo {
+            for phi in &self.func.blocks[*id].phis {
+                for arg in self.func.operands(*phi) {
+                    related[*phi].push(*arg);
+                    related[*arg].push(*phi);
+                }
+            }
+        }
+        for id in &self.rpo {
+            let block = &self.func.blocks[*id];
+            for value in block.phis.iter().chain(&block.insts) {
+                if !self.needs_slot(*value) {
+                    continue;
+                }
+                let taken = interference[*value]
+                    .iter()
+                    .filter_map(|other| self.slots[other])
+                    .collect::<Vec<_>>();
+                let slot = related[*value]
+                    .iter()
+                    .filter_map(|other| self.slots[*other])
+                    .chain(args.iter().copied())
+                    .chain((0..).filter(|x| !reserved(*x)).map(Slot::Loc))
+                    .find(|slot| !taken.contains(slot))
+                    .unwrap();
+                self.slots[*value] = Some(slot);
+            }
+        }
+
+        let reserved_top = (0..frame.loc_slots)
+            .rev()
+            .find(|x| reserved(*x))
+            .map_or(0, |x| x + 1);
+        self.slots
+            .iter()
+            .filter_map(|slot| match slot {
+                Some(Slot::Loc(x)) => Some(x + 1),
+                _ => None,
+            })
+            .fold(reserved_top, u32::max)
+    }
+
+    /// Emit the code for every block, with one more local slot after
+    /// `loc_slots` for breaking cycles of copies if needed
+    fn emit(self, loc_slots: u32) -> (Vec<BasicBlock>, usize, u32) {
+        let mut blocks = vec![BasicBlock::new(); self.func.blocks.len()];
+        for bb in &mut blocks {
+            bb.jump = JumpInst::Unreachable;
+        }
+        let scratch = Slot::Loc(loc_slots);
+        let mut scratch_used = false;
+
+        for id in &self.rpo {
+            let block = &self.func.blocks[*id];
+            let code = &mut blocks[*id].code;
+
+            let mut starts: HashMap<Value, Vec<Value>> = HashMap::new();
+            for value in &block.insts {
+                starts
+                    .entry(self.shapes[*value].tree_start)
+                    .or_default()
+                    .push(*value);
+            }
+
+            for value in &block.insts {
+                // outer expressions first, since what they push goes below
+                for root in starts.get(value).into_iter().flatten().rev() {
+                    self.emit_prefix(*root, code);
+                }
+
+                let shape = self.shapes[*value];
+                let skip = if shape.stacked > 0 {
+                    shape.lead + shape.stacked
+                } else {
+                    0
+                };
+                for arg in &self.func.operands(*value)[skip..] {
+                    self.push_value(*arg, code);
+                }
+                match self.func.values[*value] {
+                    ValueKind::Inst(op, _) | ValueKind::Call { func: op, .. } => code.push(op),
+                    _ => unreachable!("Only instructions are in blocks"),
+                }
+                if self.slots[*value].is_some() {
+                    code.push(Op::Store64);
+                } else if self.func.has_result(*value) && !self.on_stack.contains(*value) {
+                    code.push(Op::Pop);
+                }
+            }
+
+            let mut moves = self
+                .phi_copies(*id)
+                .into_iter()
+                .map(|(phi, arg)| {
+                    let source = match self.slots[arg] {
+                        Some(slot) => Source::Slot(slot),
+                        None => Source::Remat(arg),
+                    };
+                    (self.slots[phi].unwrap(), source)
+                })
+                .filter(|(to, from)| Source::Slot(*to) != *from)
+                .collect::<Vec<_>>();
+            while !moves.is_empty() {
+                let free = moves
+                    .iter()
+                    .position(|(to, _)| moves.iter().all(|(_, from)| *from != Source::Slot(*to)));
+                if let Some(idx) = free {
+                    let (to, from) = moves.remove(idx);
+                    self.emit_move(to, from, code);
+                } else {
+                    // every slot left to write is still to be read, so save one
+                    let saved = moves[0].0;
+                    self.emit_move(scratch, Source::Slot(saved), code);
+                    scratch_used = true;
+                    for (_, from) in &mut moves {
+                        if *from == Source::Slot(saved) {
+                            *from = Source::Slot(scratch);
+                        }
+                    }
+                }
+            }
+
+            let jump = match block.term {
+                Terminator::Jump(to) => JumpInst::Jump(to),
+                Terminator::Branch(cond, t, f) => {
+                    if !self.on_stack.contains(cond) {
+                        self.push_value(cond, code);
+                    }
+                    JumpInst::JumpIf(t, f)
+                }
+                Terminator::Return => JumpInst::Return,
+                Terminator::Unreachable => JumpInst::Unreachable,
+            };
+            blocks[*id].jump = jump;
+        }
+
+        (blocks, self.func.entry, loc_slots + scratch_used as u32)
+    }
+
+    /// Push what goes below the operands of `value`: the address of its slot,
+    /// the return slot of a call, and leading operands before stacked ones
+    fn emit_prefix(&self, value: Value, code: &mut Vec<Op>) {
+        if let Some(slot) = self.slots[value] {
+            code.push(slot.addr_op());
+        }
+        if let ValueKind::Call { ret_slots, .. } = self.func.values[value] {
+            if ret_slots > 0 {
+                code.push(Op::StackAlloc(ret_slots));
+            }
+        }
+        let shape = self.shapes[value];
+        if shape.stacked > 0 {
+            for arg in &self.func.operands(value)[..shape.lead] {
+                self.push_value(*arg, code);
+            }
+        }
+    }
+
+    fn push_value(&self, value: Value, code: &mut Vec<Op>) {
+        match self.func.values[value] {
+            ValueKind::Undef => code.push(Op::Push(0)),
+            ValueKind::Const(c) => code.push(Op::Push(c)),
+            ValueKind::Addr(op) => code.push(op),
+            _ => {
+                let slot = self.slots[value].expect("Value has a slot");
+                code.push(slot.addr_op());
+                code.push(Op::Load64);
+            }
+        }
+    }
+
+    fn emit_move(&self, to: Slot, from: Source, code: &mut Vec<Op>) {
+        code.push(to.addr_op());
+        match from {
+            Source::Slot(slot) => {
+                code.push(slot.addr_op());
+                code.push(Op::Load64);
+            }
+            Source::Remat(value) => self.push_value(value, code),
+        }
+        code.push(Op::Store64);
+    }
+}
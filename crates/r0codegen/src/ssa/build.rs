@@ -0,0 +1,494 @@
+//! Lifting the basic blocks of a function into SSA form, following Braun et
+//! al., "Simple and Efficient Construction of Static Single Assignment Form".
+//!
+//! Values the stack code leaves on the operand stack at the end of a block, and
+//! the slots whose address is only ever loaded from and stored to, are the
+//! variables that get turned into SSA values.
+
+use super::{opt, Frame, Func, Slot, Terminator, Value, ValueKind};
+use crate::code::{BasicBlock, JumpInst};
+use r0vm::opcodes::Op;
+use std::collections::{HashMap, HashSet};
+
+/// What's known about a value on the operand stack before lifting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Abstract {
+    Slot(Slot),
+    Other,
+}
+
+/// A value on the operand stack while lifting. The addresses of promoted slots
+/// are never turned into values.
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    Slot(Slot),
+    Value(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Var {
+    /// The operand stack, counted from the bottom
+    Stack(usize),
+    Slot(Slot),
+}
+
+/// Lift the blocks reachable from `start` into SSA form. Returns `None` for code
+/// that can't be lifted, like calls to functions returning more than one slot.
+pub(crate) fn build(blocks: &[BasicBlock], start: usize, frame: Frame) -> Option<Func> {
+    let order = reverse_postorder(blocks, start);
+    let (states, promoted) = find_promotable(blocks, &order, &frame)?;
+
+    // Block 0 is a new entry block, so that the first block can be a loop
+    // header like any other
+    let mut new_ids = vec![usize::MAX; blocks.len()];
+    for (idx, id) in order.iter().enumerate() {
+        new_ids[*id] = idx + 1;
+    }
+    let mut func = Func::new(frame);
+    for _ in &order {
+        func.add_block();
+    }
+    func.blocks[0].term = Terminator::Jump(1);
+    func.blocks[1].preds.push(0);
+    for id in &order {
+        for succ in successors(&blocks[*id].jump) {
+            func.blocks[new_ids[succ]].preds.push(new_ids[*id]);
+        }
+    }
+
+    let mut builder = Builder {
+        sealed: vec![false; func.blocks.len()],
+        filled: vec![false; func.blocks.len()],
+        incomplete_phis: vec![vec![]; func.blocks.len()],
+        func,
+        promoted,
+        defs: HashMap::new(),
+        addrs: vec![],
+        params: HashMap::new(),
+    };
+    builder.sealed[0] = true;
+    builder.filled[0] = true;
+    builder.seal_ready(1);
+    for id in &order {
+        let state = states[*id].as_ref()?;
+        builder.fill(&blocks[*id], new_ids[*id], state, &new_ids)?;
+    }
+
+    let mut func = builder.func;
+    let mut promoted = builder.promoted.into_iter().collect::<Vec<_>>();
+    promoted.sort();
+    func.promoted = promoted;
+    opt::propagate_copies(&mut func);
+    Some(func)
+}
+
+fn successors(jump: &JumpInst) -> impl Iterator<Item = usize> {
+    let (first, second) = match *jump {
+        JumpInst::Jump(to) => (Some(to), None),
+        JumpInst::JumpIf(t, f) if t == f => (Some(t), None),
+        JumpInst::JumpIf(t, f) => (Some(t), Some(f)),
+        _ => (None, None),
+    };
+    first.into_iter().chain(second)
+}
+
+fn reverse_postorder(blocks: &[BasicBlock], start: usize) -> Vec<usize> {
+    let mut visited = vec![false; blocks.len()];
+    let mut order = vec![];
+    let mut stack = vec![(start, false)];
+    while let Some((id, expanded)) = stack.pop() {
+        if expanded {
+            order.push(id);
+            continue;
+        }
+        if visited[id] {
+            continue;
+        }
+        visited[id] = true;
+        stack.push((id, true));
+        for succ in successors(&blocks[id].jump) {
+            if !visited[succ] {
+                stack.push((succ, false));
+            }
+        }
+    }
+    order.reverse();
+    order
+}
+
+/// The operand stack at the start of a block, if the block is reachable
+type StackState = Option<Vec<Abstract>>;
+
+/// Find the operand stack at the start of every block, and the slots whose
+/// address is used for nothing but loading and storing 64-bit values
+fn find_promotable(
+    blocks: &[BasicBlock],
+    order: &[usize],
+    frame: &Frame,
+) -> Option<(Vec<StackState>, HashSet<Slot>)> {
+    let mut states: Vec<StackState> = vec![None; blocks.len()];
+    let mut seen = HashSet::new();
+    let mut escaped = HashSet::new();
+    let escape = |entry: Abstract, escaped: &mut HashSet<Slot>| {
+        if let Abstract::Slot(slot) = entry {
+            escaped.insert(slot);
+        }
+    };
+
+    states[order[0]] = Some(vec![]);
+    let mut worklist = vec![order[0]];
+    while let Some(id) = worklist.pop() {
+        let mut stack = states[id].clone().unwrap();
+        for op in &blocks[id].code {
+            match *op {
+                Op::Nop => {}
+                Op::Push(_) | Op::GlobA(_) => stack.push(Abstract::Other),
+                Op::Pop => {
+                    stack.pop()?;
+                }
+                Op::PopN(n) => {
+                    stack.truncate(stack.len().checked_sub(n as usize)?);
+                }
+                Op::Dup => stack.push(*stack.last()?),
+                Op::LocA(x) => {
+                    seen.insert(Slot::Loc(x));
+                    stack.push(Abstract::Slot(Slot::Loc(x)));
+                }
+                Op::ArgA(x) => {
+                    // the return slots are read by the caller
+                    if x < frame.ret_slots || x >= frame.ret_slots + frame.param_slots {
+                        escaped.insert(Slot::Arg(x));
+                    }
+                    seen.insert(Slot::Arg(x));
+                    stack.push(Abstract::Slot(Slot::Arg(x)));
+                }
+                Op::StackAlloc(n) => {
+                    stack.extend((0..n).map(|_| Abstract::Other));
+                }
+                Op::Load64 => {
+                    stack.pop()?;
+                    stack.push(Abstract::Other);
+                }
+                Op::Store64 => {
+                    escape(stack.pop()?, &mut escaped);
+                    stack.pop()?;
+                }
+                Op::Call(_) | Op::CallName(_) => {
+                    let (params, _) = frame.call_slots(*op)?;
+                    for _ in 0..params {
+                        escape(stack.pop()?, &mut escaped);
+                    }
+                }
+                op => {
+                    let (pops, pushes) = super::op_stack_effect(op)?;
+                    for _ in 0..pops {
+                        escape(stack.pop()?, &mut escaped);
+                    }
+                    stack.extend((0..pushes).map(|_| Abstract::Other));
+                }
+            }
+        }
+        match blocks[id].jump {
+            JumpInst::JumpIf(..) => escape(stack.pop()?, &mut escaped),
+            JumpInst::Undefined => return None,
+            _ => {}
+        }
+
+        for succ in successors(&blocks[id].jump) {
+            match &mut states[succ] {
+                None => {
+                    states[succ] = Some(stack.clone());
+                    worklist.push(succ);
+                }
+                Some(state) if state.len() != stack.len() => return None,
+                Some(state) => {
+                    let mut changed = false;
+                    for (old, new) in state.iter_mut().zip(&stack) {
+                        if old != new {
+                            escape(*old, &mut escaped);
+                            escape(*new, &mut escaped);
+                            changed |= *old != Abstract::Other;
+                            *old = Abstract::Other;
+                        }
+                    }
+                    if changed {
+                        worklist.push(succ);
+                    }
+                }
+            }
+        }
+    }
+
+    let promoted = seen.difference(&escaped).copied().collect();
+    Some((states, promoted))
+}
+
+struct Builder {
+    func: Func,
+    promoted: HashSet<Slot>,
+    /// The value of each variable at the end of each block it's set in so far
+    defs: HashMap<(Var, usize), Value>,
+    sealed: Vec<bool>,
+    filled: Vec<bool>,
+    /// Phis made for blocks whose predecessors aren't all known
+    incomplete_phis: Vec<Vec<(Var, Value)>>,
+    addrs: Vec<(Op, Value)>,
+    params: HashMap<u32, Value>,
+}
+
+impl Builder {
+    fn fill(
+        &mut self,
+        bb: &BasicBlock,
+        block: usize,
+        state: &[Abstract],
+        new_ids: &[usize],
+    ) -> Option<()> {
+        let mut stack = state
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| match entry {
+                Abstract::Slot(slot) if self.promoted.contains(slot) => Entry::Slot(*slot),
+                _ => Entry::Value(self.read_var(Var::Stack(idx), block)),
+            })
+            .collect::<Vec<_>>();
+        let pop_value = |stack: &mut Vec<Entry>| match stack.pop()? {
+            Entry::Value(value) => Some(value),
+            Entry::Slot(_) => None,
+        };
+
+        for op in &bb.code {
+            match *op {
+                Op::Nop => {}
+                Op::Push(c) => {
+                    let value = self.func.constant(c);
+                    stack.push(Entry::Value(value));
+                }
+                Op::Pop => {
+                    stack.pop()?;
+                }
+                Op::PopN(n) => {
+                    stack.truncate(stack.len().checked_sub(n as usize)?);
+                }
+                Op::Dup => stack.push(*stack.last()?),
+                Op::LocA(x) | Op::ArgA(x) => {
+                    let slot = match op {
+                        Op::LocA(_) => Slot::Loc(x),
+                        _ => Slot::Arg(x),
+                    };
+                    if self.promoted.contains(&slot) {
+                        stack.push(Entry::Slot(slot));
+                    } else {
+                        stack.push(Entry::Value(self.addr(*op)));
+                    }
+                }
+                Op::GlobA(_) => stack.push(Entry::Value(self.addr(*op))),
+                Op::StackAlloc(n) => {
+                    for _ in 0..n {
+                        stack.push(Entry::Value(self.func.undef()));
+                    }
+                }
+                Op::Load64 => {
+                    let value = match stack.pop()? {
+                        Entry::Slot(slot) => self.read_var(Var::Slot(slot), block),
+                        Entry::Value(addr) => self.add_inst(block, Op::Load64, vec![addr]),
+                    };
+                    stack.push(Entry::Value(value));
+                }
+                Op::Store64 => {
+                    let value = pop_value(&mut stack)?;
+                    match stack.pop()? {
+                        Entry::Slot(slot) => self.write_var(Var::Slot(slot), block, value),
+                        Entry::Value(addr) => {
+                            self.add_inst(block, Op::Store64, vec![addr, value]);
+                        }
+                    }
+                }
+                Op::Call(_) | Op::CallName(_) => {
+                    let (params, ret_slots) = self.func.frame.call_slots(*op)?;
+                    if ret_slots > 1 {
+                        return None;
+                    }
+                    let mut args = (0..params)
+                        .map(|_| pop_value(&mut stack))
+                        .collect::<Option<Vec<_>>>()?;
+                    args.reverse();
+                    stack.truncate(stack.len().checked_sub(ret_slots as usize)?);
+
+                    let value = self.func.add_value(ValueKind::Call {
+                        func: *op,
+                        args,
+                        ret_slots,
+                    });
+                    self.func.blocks[block].insts.push(value);
+                    if ret_slots == 1 {
+                        stack.push(Entry::Value(value));
+                    }
+                }
+                op => {
+                    let (pops, pushes) = super::op_stack_effect(op)?;
+                    let mut args = (0..pops)
+                        .map(|_| pop_value(&mut stack))
+                        .collect::<Option<Vec<_>>>()?;
+                    args.reverse();
+                    let value = self.add_inst(block, op, args);
+                    if pushes == 1 {
+                        stack.push(Entry::Value(value));
+                    }
+                }
+            }
+        }
+
+        self.func.blocks[block].term = match bb.jump {
+            JumpInst::Jump(to) => Terminator::Jump(new_ids[to]),
+            JumpInst::JumpIf(t, f) => {
+                let cond = pop_value(&mut stack)?;
+                if t == f {
+                    Terminator::Jump(new_ids[t])
+                } else {
+                    Terminator::Branch(cond, new_ids[t], new_ids[f])
+                }
+            }
+            JumpInst::Return => Terminator::Return,
+            JumpInst::Unreachable => Terminator::Unreachable,
+            JumpInst::Undefined => return None,
+        };
+        for (idx, entry) in stack.into_iter().enumerate() {
+            if let Entry::Value(value) = entry {
+                self.write_var(Var::Stack(idx), block, value);
+            }
+        }
+
+        self.filled[block] = true;
+        let succs = self.func.blocks[block]
+            .term
+            .successors()
+            .collect::<Vec<_>>();
+        for succ in succs {
+            self.seal_ready(succ);
+        }
+        Some(())
+    }
+
+    fn add_inst(&mut self, block: usize, op: Op, args: Vec<Value>) -> Value {
+        let value = self.func.add_value(ValueKind::Inst(op, args));
+        self.func.blocks[block].insts.push(value);
+        value
+    }
+
+    fn addr(&mut self, op: Op) -> Value {
+        if let Some((_, value)) = self.addrs.iter().find(|(addr, _)| *addr == op) {
+            return *value;
+        }
+        let value = self.func.add_value(ValueKind::Addr(op));
+        self.addrs.push((op, value));
+        value
+    }
+
+    fn param(&mut self, slot: u32) -> Value {
+        if let Some(value) = self.params.get(&slot) {
+            return *value;
+        }
+        let value = self.func.add_value(ValueKind::Param(slot));
+        self.params.insert(slot, value);
+        value
+    }
+
+    fn write_var(&mut self, var: Var, block: usize, value: Value) {
+        self.defs.insert((var, block), value);
+    }
+
+    fn read_var(&mut self, var: Var, block: usize) -> Value {
+        // Walk up through blocks with only one predecessor without recursing,
+        // since there may be long chains of them
+        let mut path = vec![];
+        let mut block = block;
+        let value = loop {
+            if let Some(value) = self.defs.get(&(var, block)) {
+                break *value;
+            }
+            let preds = &self.func.blocks[block].preds;
+            if self.sealed[block] && preds.len() == 1 {
+                path.push(block);
+                block = preds[0];
+                continue;
+            }
+            break self.read_var_at_merge(var, block);
+        };
+        for block in path {
+            self.write_var(var, block, value);
+        }
+        value
+    }
+
+    fn read_var_at_merge(&mut self, var: Var, block: usize) -> Value {
+        let value = if !self.sealed[block] {
+            let phi = self.add_phi(block);
+            self.incomplete_phis[block].push((var, phi));
+            phi
+        } else if self.func.blocks[block].preds.is_empty() {
+            match var {
+                Var::Slot(Slot::Arg(x)) => self.param(x),
+                _ => self.func.undef(),
+            }
+        } else {
+            let phi = self.add_phi(block);
+            self.write_var(var, block, phi);
+            self.add_phi_operands(var, block, phi)
+        };
+        self.write_var(var, block, value);
+        value
+    }
+
+    fn add_phi(&mut self, block: usize) -> Value {
+        let phi = self.func.add_value(ValueKind::Phi(vec![]));
+        self.func.blocks[block].phis.push(phi);
+        phi
+    }
+
+    fn add_phi_operands(&mut self, var: Var, block: usize, phi: Value) -> Value {
+        let preds = self.func.blocks[block].preds.clone();
+        for pred in preds {
+            let value = self.read_var(var, pred);
+            if let ValueKind::Phi(args) = &mut self.func.values[phi] {
+                args.push(value);
+            }
+        }
+        self.try_remove_trivial_phi(block, phi)
+    }
+
+    /// Replace `phi` with the only value other than itself it merges, if
+    /// there's one. Phis using it are left to copy propagation.
+    fn try_remove_trivial_phi(&mut self, block: usize, phi: Value) -> Value {
+        let mut same = None;
+        for arg in self.func.operands(phi) {
+            let arg = self.func.resolve(*arg);
+            if Some(arg) == same || arg == phi {
+                continue;
+            }
+            if same.is_some() {
+                return phi;
+            }
+            same = Some(arg);
+        }
+        let same = same.unwrap_or_else(|| self.func.undef());
+        self.func.values[phi] = ValueKind::Copy(same);
+        self.func.blocks[block].phis.retain(|value| *value != phi);
+        same
+    }
+
+    /// Seal `block` if all of its predecessors are filled
+    fn seal_ready(&mut self, block: usize) {
+        if self.sealed[block] {
+            return;
+        }
+        let preds = &self.func.blocks[block].preds;
+        if !preds.iter().all(|pred| self.filled[*pred]) {
+            return;
+        }
+        for (var, phi) in std::mem::take(&mut self.incomplete_phis[block]) {
+            self.add_phi_operands(var, block, phi);
+        }
+        self.sealed[block] = true;
+    }
+}
@@ -0,0 +1,453 @@
+//! A mid-level IR in SSA form, lifted from the basic blocks of one function.
+//!
+//! Values that the stack code keeps in scalar local and argument slots, or
+//! leaves on the operand stack across blocks, become SSA values joined by phi
+//! nodes. Slots whose address is used for anything but loading and storing
+//! them (arrays, structs, the return value) stay in memory. After optimising,
+//! the function is lowered back into basic blocks, with every value that
+//! can't stay on the operand stack given a slot by a liveness-driven
+//! allocator.
+
+mod alloc;
+mod build;
+mod opt;
+
+use r0vm::opcodes::Op;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+pub(crate) use build::build;
+pub use opt::optimise;
+
+/// The index of a value in `Func::values`
+pub type Value = usize;
+
+/// The index of a block in `Func::blocks`
+pub type BlockId = usize;
+
+/// A 64-bit slot in the frame of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Slot {
+    Arg(u32),
+    Loc(u32),
+}
+
+impl Slot {
+    /// The instruction that pushes the address of this slot
+    pub fn addr_op(self) -> Op {
+        match self {
+            Slot::Arg(x) => Op::ArgA(x),
+            Slot::Loc(x) => Op::LocA(x),
+        }
+    }
+}
+
+/// The slots in the frame of the function being lifted, and the slots taken by
+/// the functions it calls
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    pub ret_slots: u32,
+    pub param_slots: u32,
+    pub loc_slots: u32,
+    /// The parameter and return slots of every `Call` or `CallName` target
+    pub calls: Vec<(Op, u32, u32)>,
+}
+
+impl Frame {
+    fn call_slots(&self, op: Op) -> Option<(u32, u32)> {
+        self.calls
+            .iter()
+            .find(|(call, ..)| *call == op)
+            .map(|(_, params, ret)| (*params, *ret))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ValueKind {
+    /// A value never written, like a local read before it's assigned
+    Undef,
+    Const(u64),
+    /// The address pushed by `LocA`, `ArgA` or `GlobA`
+    Addr(Op),
+    /// The value of a promoted argument slot when the function is entered
+    Param(u32),
+    Phi(Vec<Value>),
+    /// Replaced by another value, and removed by copy propagation
+    Copy(Value),
+    /// `op` applied to `args`, which are pushed in order
+    Inst(Op, Vec<Value>),
+    /// A call that leaves `ret_slots` slots, which is 0 or 1
+    Call {
+        func: Op,
+        args: Vec<Value>,
+        ret_slots: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch(Value, BlockId, BlockId),
+    Return,
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> impl Iterator<Item = BlockId> {
+        let (first, second) = match *self {
+            Terminator::Jump(to) => (Some(to), None),
+            Terminator::Branch(_, t, f) => (Some(t), Some(f)),
+            _ => (None, None),
+        };
+        first.into_iter().chain(second)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub phis: Vec<Value>,
+    /// `Inst` and `Call` values, in the order they run
+    pub insts: Vec<Value>,
+    pub term: Terminator,
+    /// The operands of every phi are in the same order as these
+    pub preds: Vec<BlockId>,
+}
+
+impl Block {
+    fn new() -> Block {
+        Block {
+            phis: vec![],
+            insts: vec![],
+            term: Terminator::Unreachable,
+            preds: vec![],
+        }
+    }
+}
+
+/// A function in SSA form. Constants, addresses and parameters belong to no
+/// block.
+#[derive(Debug, Clone)]
+pub struct Func {
+    pub values: Vec<ValueKind>,
+    pub blocks: Vec<Block>,
+    pub entry: BlockId,
+    pub frame: Frame,
+    /// The slots turned into SSA values, which are free to reuse
+    pub promoted: Vec<Slot>,
+    consts: HashMap<u64, Value>,
+    undef: Option<Value>,
+}
+
+impl Func {
+    fn new(frame: Frame) -> Func {
+        Func {
+            values: vec![],
+            blocks: vec![Block::new()],
+            entry: 0,
+            frame,
+            promoted: vec![],
+            consts: HashMap::new(),
+            undef: None,
+        }
+    }
+
+    fn add_value(&mut self, kind: ValueKind) -> Value {
+        self.values.push(kind);
+        self.values.len() - 1
+    }
+
+    fn add_block(&mut self) -> BlockId {
+        self.blocks.push(Block::new());
+        self.blocks.len() - 1
+    }
+
+    /// The value of constant `c`, shared by every use
+    fn constant(&mut self, c: u64) -> Value {
+        if let Some(value) = self.consts.get(&c) {
+            return *value;
+        }
+        let value = self.add_value(ValueKind::Const(c));
+        self.consts.insert(c, value);
+        value
+    }
+
+    /// The undefined value, shared by every use
+    fn undef(&mut self) -> Value {
+        if let Some(value) = self.undef {
+            return value;
+        }
+        let value = self.add_value(ValueKind::Undef);
+        self.undef = Some(value);
+        value
+    }
+
+    /// Follow copies to the value `value` stands for
+    pub fn resolve(&self, value: Value) -> Value {
+        let mut value = value;
+        while let ValueKind::Copy(to) = self.values[value] {
+            value = to;
+        }
+        value
+    }
+
+    /// The values `value` is computed from
+    pub fn operands(&self, value: Value) -> &[Value] {
+        match &self.values[value] {
+            ValueKind::Phi(args) | ValueKind::Inst(_, args) | ValueKind::Call { args, .. } => args,
+            _ => &[],
+        }
+    }
+
+    fn operands_mut(&mut self, value: Value) -> &mut [Value] {
+        match &mut self.values[value] {
+            ValueKind::Phi(args) | ValueKind::Inst(_, args) | ValueKind::Call { args, .. } => args,
+            _ => &mut [],
+        }
+    }
+
+    /// Whether `value` can be computed anywhere, any number of times, without
+    /// changing what the program does
+    pub fn is_pure(&self, value: Value) -> bool {
+        match self.values[value] {
+            ValueKind::Inst(op, _) => op_is_pure(op),
+            ValueKind::Call { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Whether `value` is pushed straight where it's used instead of living in
+    /// a slot
+    pub fn is_rematerializable(&self, value: Value) -> bool {
+        matches!(
+            self.values[value],
+            ValueKind::Undef | ValueKind::Const(_) | ValueKind::Addr(_)
+        )
+    }
+
+    /// Blocks reachable from the entry, in reverse postorder
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = vec![];
+        // (block, whether its successors have been pushed)
+        let mut stack = vec![(self.entry, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if expanded {
+                order.push(id);
+                continue;
+            }
+            if visited[id] {
+                continue;
+            }
+            visited[id] = true;
+            stack.push((id, true));
+            for succ in self.blocks[id].term.successors() {
+                if !visited[succ] {
+                    stack.push((succ, false));
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// The immediate dominator of every block in `rpo`, with the entry
+    /// dominated by itself
+    pub fn idoms(&self, rpo: &[BlockId]) -> Vec<Option<BlockId>> {
+        let mut order = vec![usize::MAX; self.blocks.len()];
+        for (idx, id) in rpo.iter().enumerate() {
+            order[*id] = idx;
+        }
+
+        // Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm"
+        let mut idoms = vec![None; self.blocks.len()];
+        idoms[self.entry] = Some(self.entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in &self.blocks[*id].preds {
+                    if idoms[*pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(mut other) => {
+                            let mut pred = *pred;
+                            while pred != other {
+                                while order[pred] > order[other] {
+                                    pred = idoms[pred].unwrap();
+                                }
+                                while order[other] > order[pred] {
+                                    other = idoms[other].unwrap();
+                                }
+                            }
+                            pred
+                        }
+                    });
+                }
+                if new_idom != idoms[*id] {
+                    idoms[*id] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idoms
+    }
+
+    /// Put a new block on the edge from `from` to `to`, and return it
+    fn split_edge(&mut self, from: BlockId, to: BlockId) -> BlockId {
+        let mid = self.add_block();
+        self.blocks[mid].preds.push(from);
+        self.blocks[mid].term = Terminator::Jump(to);
+        self.blocks[from].term = match self.blocks[from].term {
+            Terminator::Jump(_) => Terminator::Jump(mid),
+            Terminator::Branch(cond, t, f) => Terminator::Branch(
+                cond,
+                if t == to { mid } else { t },
+                if f == to { mid } else { f },
+            ),
+            term => term,
+        };
+        for pred in &mut self.blocks[to].preds {
+            if *pred == from {
+                *pred = mid;
+            }
+        }
+        mid
+    }
+}
+
+/// The number of values `op` pops and pushes, for instructions that are lifted
+/// as they are
+fn op_stack_effect(op: Op) -> Option<(usize, usize)> {
+    use Op::*;
+    Some(match op {
+        Load8 | Load16 | Load32 | Load64 | Alloc => (1, 1),
+        Store8 | Store16 | Store32 | Store64 => (2, 0),
+        Free => (1, 0),
+        AddI | SubI | MulI | DivI | AddF | SubF | MulF | DivF | DivU | Shl | Shr | ShrL | And
+        | Or | Xor | CmpI | CmpU | CmpF => (2, 1),
+        Not | NegI | NegF | IToF | FToI | SetLt | SetGt => (1, 1),
+        ScanI | ScanC | ScanF => (0, 1),
+        PrintI | PrintC | PrintF | PrintS => (1, 0),
+        PrintLn | Panic => (0, 0),
+        _ => return None,
+    })
+}
+
+/// Whether `op` only computes a value from its operands, and can't fail
+fn op_is_pure(op: Op) -> bool {
+    use Op::*;
+    matches!(
+        op,
+        AddI | SubI
+            | MulI
+            | AddF
+            | SubF
+            | MulF
+            | DivF
+            | Shl
+            | Shr
+            | ShrL
+            | And
+            | Or
+            | Xor
+            | CmpI
+            | CmpU
+            | CmpF
+            | Not
+            | NegI
+            | NegF
+            | IToF
+            | FToI
+            | SetLt
+            | SetGt
+    )
+}
+
+fn op_is_commutative(op: Op) -> bool {
+    use Op::*;
+    matches!(op, AddI | MulI | AddF | MulF | And | Or | Xor)
+}
+
+impl Func {
+    fn fmt_value(&self, f: &mut Formatter<'_>, value: Value) -> fmt::Result {
+        match &self.values[value] {
+            ValueKind::Undef => write!(f, "undef"),
+            ValueKind::Const(c) => write!(f, "{}", *c as i64),
+            ValueKind::Addr(op) => write!(f, "{:?}", op),
+            ValueKind::Param(slot) => write!(f, "arg{}", slot),
+            _ => write!(f, "v{}", value),
+        }
+    }
+
+    fn fmt_values(&self, f: &mut Formatter<'_>, values: &[Value]) -> fmt::Result {
+        for (idx, value) in values.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            self.fmt_value(f, *value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Func {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for id in self.reverse_postorder() {
+            let block = &self.blocks[id];
+            write!(f, "b{}:", id)?;
+            if !block.preds.is_empty() {
+                write!(f, " ; preds:")?;
+                for pred in &block.preds {
+                    write!(f, " b{}", pred)?;
+                }
+            }
+            writeln!(f)?;
+
+            for value in block.phis.iter().chain(&block.insts) {
+                write!(f, "    ")?;
+                let has_result = match &self.values[*value] {
+                    ValueKind::Inst(op, _) => op_stack_effect(*op).is_some_and(|(_, n)| n > 0),
+                    ValueKind::Call { ret_slots, .. } => *ret_slots > 0,
+                    _ => true,
+                };
+                if has_result {
+                    write!(f, "v{} = ", value)?;
+                }
+                match &self.values[*value] {
+                    ValueKind::Phi(args) => {
+                        write!(f, "phi ")?;
+                        self.fmt_values(f, args)?;
+                    }
+                    ValueKind::Inst(op, args) => {
+                        write!(f, "{:?} ", op)?;
+                        self.fmt_values(f, args)?;
+                    }
+                    ValueKind::Call { func, args, .. } => {
+                        write!(f, "{:?}(", func)?;
+                        self.fmt_values(f, args)?;
+                        write!(f, ")")?;
+                    }
+                    _ => self.fmt_value(f, *value)?,
+                }
+                writeln!(f)?;
+            }
+
+            match block.term {
+                Terminator::Jump(to) => writeln!(f, "    jump b{}", to)?,
+                Terminator::Branch(cond, t, e) => {
+                    write!(f, "    branch ")?;
+                    self.fmt_value(f, cond)?;
+                    writeln!(f, ", b{}, b{}", t, e)?;
+                }
+                Terminator::Return => writeln!(f, "    return")?,
+                Terminator::Unreachable => writeln!(f, "    unreachable")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+//! Optimisations on the SSA form of a function
+
+use super::{op_is_commutative, BlockId, Func, Terminator, Value, ValueKind};
+use crate::opt::ConstFold;
+use bit_set::BitSet;
+use std::collections::HashMap;
+
+/// Passes are repeated until nothing changes, but at most this many times
+const MAX_ROUNDS: usize = 4;
+
+/// Run global value numbering, copy propagation, loop-invariant code motion
+/// and dead value removal until none of them changes anything
+pub fn optimise(func: &mut Func) {
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+        changed |= number_values(func);
+        changed |= propagate_copies(func);
+        changed |= hoist_invariants(func);
+        changed |= remove_dead_values(func);
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Replace phis that merge a single value with that value, then make every
+/// use of a copy use what it copies and drop the copies. Returns whether
+/// anything changed.
+pub(super) fn propagate_copies(func: &mut Func) -> bool {
+    let mut changed = false;
+    loop {
+        let mut trivial = false;
+        for block in 0..func.blocks.len() {
+            for phi in func.blocks[block].phis.clone() {
+                if matches!(func.values[phi], ValueKind::Copy(_)) {
+                    continue;
+                }
+                let mut same = Some(None);
+                for arg in func.operands(phi) {
+                    let arg = func.resolve(*arg);
+                    match same {
+                        _ if arg == phi => {}
+                        Some(None) => same = Some(Some(arg)),
+                        Some(Some(other)) if other != arg => same = None,
+                        _ => {}
+                    }
+                }
+                if let Some(same) = same {
+                    let same = same.unwrap_or_else(|| func.undef());
+                    func.values[phi] = ValueKind::Copy(same);
+                    trivial = true;
+                }
+            }
+        }
+        if !trivial {
+            break;
+        }
+        changed = true;
+    }
+
+    for value in 0..func.values.len() {
+        let resolved = func
+            .operands(value)
+            .iter()
+            .map(|arg| func.resolve(*arg))
+            .collect::<Vec<_>>();
+        func.operands_mut(value).copy_from_slice(&resolved);
+    }
+    for block in 0..func.blocks.len() {
+        if let Terminator::Branch(cond, t, f) = func.blocks[block].term {
+            func.blocks[block].term = Terminator::Branch(func.resolve(cond), t, f);
+        }
+        let values = &func.values;
+        let is_copy = |value: &Value| matches!(values[*value], ValueKind::Copy(_));
+        let block = &mut func.blocks[block];
+        let len = block.phis.len() + block.insts.len();
+        block.phis.retain(|value| !is_copy(value));
+        block.insts.retain(|value| !is_copy(value));
+        changed |= len != block.phis.len() + block.insts.len();
+    }
+    changed
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Phi(BlockId, Vec<Value>),
+    Inst(u8, Vec<Value>),
+}
+
+/// Global value numbering: fold pure instructions on constants, and replace
+/// pure instructions and phis with an equal value that dominates them. Returns
+/// whether anything changed.
+fn number_values(func: &mut Func) -> bool {
+    let rpo = func.reverse_postorder();
+    let idoms = func.idoms(&rpo);
+    let mut children = vec![vec![]; func.blocks.len()];
+    for id in rpo.iter().skip(1) {
+        children[idoms[*id].unwrap()].push(*id);
+    }
+
+    let mut changed = false;
+    let mut table = HashMap::new();
+    // Keys added by the blocks being visited, which are dropped on leaving
+    // them, so that only values from dominating blocks are found
+    let mut added = vec![];
+    // (block, how many keys were added before entering it)
+    let mut stack = vec![(func.entry, None)];
+    while let Some((block, entered)) = stack.pop() {
+        if let Some(len) = entered {
+            for key in added.drain(len..) {
+                table.remove(&key);
+            }
+            continue;
+        }
+        stack.push((block, Some(added.len())));
+        stack.extend(children[block].iter().map(|child| (*child, None)));
+
+        for value in func.blocks[block].phis.clone() {
+            let args = func
+                .operands(value)
+                .iter()
+                .map(|arg| func.resolve(*arg))
+                .collect();
+            changed |= number(func, &mut table, &mut added, value, Key::Phi(block, args));
+        }
+        for value in func.blocks[block].insts.clone() {
+            let (op, args) = match &func.values[value] {
+                ValueKind::Inst(op, args) if super::op_is_pure(*op) => (*op, args),
+                _ => continue,
+            };
+            let mut args = args
+                .iter()
+                .map(|arg| func.resolve(*arg))
+                .collect::<Vec<_>>();
+
+            let consts = args
+                .iter()
+                .map(|arg| match func.values[*arg] {
+                    ValueKind::Const(c) => Some(c),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            let folded = match consts.as_deref() {
+                Some([x]) => ConstFold::fold_unary(op, *x),
+                Some([lhs, rhs]) => ConstFold::fold_binary(op, *lhs, *rhs),
+                _ => None,
+            };
+            if let Some(c) = folded {
+                let c = func.constant(c);
+                func.values[value] = ValueKind::Copy(c);
+                changed = true;
+                continue;
+            }
+
+            if op_is_commutative(op) {
+                args.sort_unstable();
+            }
+            changed |= number(
+                func,
+                &mut table,
+                &mut added,
+                value,
+                Key::Inst(op.code(), args),
+            );
+        }
+    }
+    changed
+}
+
+/// Make `value` a copy of the value already computed for `key`, or record it
+/// as that value. Returns whether `value` was replaced.
+fn number(
+    func: &mut Func,
+    table: &mut HashMap<Key, Value>,
+    added: &mut Vec<Key>,
+    value: Value,
+    key: Key,
+) -> bool {
+    if let Some(existing) = table.get(&key) {
+        func.values[value] = ValueKind::Copy(*existing);
+        return true;
+    }
+    table.insert(key.clone(), value);
+    added.push(key);
+    false
+}
+
+/// A loop, found from the edges back to its header
+struct Loop {
+    header: BlockId,
+    body: BitSet,
+}
+
+/// Find every natural loop. Loops sharing a header are merged.
+fn natural_loops(func: &Func) -> Vec<Loop> {
+    let rpo = func.reverse_postorder();
+    let idoms = func.idoms(&rpo);
+    let dominates = |a: BlockId, b: BlockId| {
+        let mut b = b;
+        loop {
+            if a == b {
+                return true;
+            }
+            match idoms[b] {
+                Some(idom) if idom != b => b = idom,
+                _ => return false,
+            }
+        }
+    };
+
+    let mut loops: Vec<Loop> = vec![];
+    for id in &rpo {
+        for header in func.blocks[*id].term.successors() {
+            if !dominates(header, *id) {
+                continue;
+            }
+            let idx = match loops.iter().position(|l| l.header == header) {
+                Some(idx) => idx,
+                None => {
+                    let mut body = BitSet::with_capacity(func.blocks.len());
+                    body.insert(header);
+                    loops.push(Loop { header, body });
+                    loops.len() - 1
+                }
+            };
+            // everything that reaches the back edge without passing the header
+            let mut worklist = vec![*id];
+            while let Some(block) = worklist.pop() {
+                if loops[idx].body.insert(block) {
+                    worklist.extend(&func.blocks[block].preds);
+                }
+            }
+        }
+    }
+    loops
+}
+
+/// The only block outside `l` that jumps into its header
+fn outside_pred(func: &Func, l: &Loop) -> Option<BlockId> {
+    let mut outside = func.blocks[l.header]
+        .preds
+        .iter()
+        .filter(|pred| !l.body.contains(**pred));
+    let pred = *outside.next()?;
+    match outside.next() {
+        Some(_) => None,
+        None => Some(pred),
+    }
+}
+
+/// Loop-invariant code motion: move pure instructions whose operands are all
+/// computed outside a loop into the block before it. Returns whether anything
+/// changed.
+fn hoist_invariants(func: &mut Func) -> bool {
+    let mut changed = false;
+
+    // Give every loop entered from a single block a preheader, which runs
+    // once right before the loop and nothing else
+    for l in natural_loops(func) {
+        if let Some(pred) = outside_pred(func, &l) {
+            if func.blocks[pred].term.successors().count() > 1 {
+                func.split_edge(pred, l.header);
+                changed = true;
+            }
+        }
+    }
+
+    let mut def_block = vec![None; func.values.len()];
+    for (id, block) in func.blocks.iter().enumerate() {
+        for value in block.phis.iter().chain(&block.insts) {
+            def_block[*value] = Some(id);
+        }
+    }
+
+    let rpo = func.reverse_postorder();
+    for l in natural_loops(func) {
+        let preheader = match outside_pred(func, &l) {
+            Some(pred) if func.blocks[pred].term.successors().count() == 1 => pred,
+            _ => continue,
+        };
+        for id in rpo.iter().filter(|id| l.body.contains(**id)) {
+            let mut hoisted = vec![];
+            for value in &func.blocks[*id].insts {
+                let invariant = func
+                    .operands(*value)
+                    .iter()
+                    .all(|arg| match def_block[*arg] {
+                        Some(block) => !l.body.contains(block),
+                        None => true,
+                    });
+                if invariant && func.is_pure(*value) {
+                    hoisted.push(*value);
+                    def_block[*value] = Some(preheader);
+                }
+            }
+            if hoisted.is_empty() {
+                continue;
+            }
+            func.blocks[*id]
+                .insts
+                .retain(|value| def_block[*value] != Some(preheader));
+            func.blocks[preheader].insts.extend(hoisted);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Remove phis and pure instructions whose values are never used. Returns
+/// whether anything changed.
+fn remove_dead_values(func: &mut Func) -> bool {
+    let mut live = BitSet::with_capacity(func.values.len());
+    let mut worklist = vec![];
+    for block in &func.blocks {
+        worklist.extend(block.insts.iter().filter(|value| !func.is_pure(**value)));
+        if let Terminator::Branch(cond, ..) = block.term {
+            worklist.push(cond);
+        }
+    }
+    while let Some(value) = worklist.pop() {
+        if live.insert(value) {
+            worklist.extend(func.operands(value));
+        }
+    }
+
+    let mut changed = false;
+    for block in &mut func.blocks {
+        let len = block.phis.len() + block.insts.len();
+        block.phis.retain(|value| live.contains(*value));
+        block.insts.retain(|value| live.contains(*value));
+        changed |= len != block.phis.len() + block.insts.len();
+    }
+    changed
+}
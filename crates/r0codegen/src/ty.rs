@@ -1,4 +1,5 @@
 use r0syntax::util::P;
+use smol_str::SmolStr;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Ty {
@@ -7,7 +8,12 @@ pub enum Ty {
     Bool,
     Addr,
     Func(FuncTy),
+    Array(ArrayTy),
+    Struct(StructTy),
     Void,
+    /// The type of something that failed to type check. It's compatible with
+    /// every type so that the error isn't reported again.
+    Error,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -16,11 +22,39 @@ pub struct FuncTy {
     pub ret: P<Ty>,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ArrayTy {
+    pub elem: P<Ty>,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StructTy {
+    pub name: SmolStr,
+    pub fields: Vec<StructFieldTy>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StructFieldTy {
+    pub name: SmolStr,
+    pub ty: P<Ty>,
+    /// Offset from the start of the struct, in slots
+    pub offset: usize,
+}
+
+impl StructTy {
+    pub fn get_field(&self, name: &str) -> Option<&StructFieldTy> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
 impl Ty {
     pub fn size(&self) -> usize {
         match self {
-            Ty::Int | Ty::Double | Ty::Addr => 8,
-            Ty::Bool => 1,
+            // bool takes a whole slot so it can be loaded like other values
+            Ty::Int | Ty::Double | Ty::Bool | Ty::Addr | Ty::Error => 8,
+            // array elements are always slot-aligned
+            Ty::Array(_) | Ty::Struct(_) => self.size_slot() * 8,
             Ty::Func(_) => 0,
             Ty::Void => 0,
         }
@@ -28,7 +62,9 @@ impl Ty {
 
     pub fn size_slot(&self) -> usize {
         match self {
-            Ty::Int | Ty::Double | Ty::Bool | Ty::Addr => 1,
+            Ty::Int | Ty::Double | Ty::Bool | Ty::Addr | Ty::Error => 1,
+            Ty::Array(arr) => arr.elem.size_slot() * arr.len,
+            Ty::Struct(s) => s.fields.iter().map(|field| field.ty.size_slot()).sum(),
             Ty::Func(_) => 0,
             Ty::Void => 0,
         }
@@ -40,6 +76,35 @@ impl Ty {
             _ => None,
         }
     }
+
+    pub fn get_array(&self) -> Option<&ArrayTy> {
+        match self {
+            Ty::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn get_struct(&self) -> Option<&StructTy> {
+        match self {
+            Ty::Struct(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Ty::Error)
+    }
+
+    /// Whether values of `self` and `other` can be used in place of each
+    /// other, which is always true if either is an error
+    pub fn matches(&self, other: &Ty) -> bool {
+        self == other || self.is_error() || other.is_error()
+    }
+
+    /// Whether this type spans multiple slots and cannot be used as a value
+    pub fn is_aggregate(&self) -> bool {
+        matches!(self, Ty::Array(_) | Ty::Struct(_))
+    }
 }
 
 impl std::fmt::Display for Ty {
@@ -62,7 +127,10 @@ impl std::fmt::Display for Ty {
 
                 write!(f, ") -> {}", ty.ret)
             }
+            Ty::Array(arr) => write!(f, "[{}; {}]", arr.elem, arr.len),
+            Ty::Struct(s) => write!(f, "{}", s.name),
             Ty::Void => write!(f, "void"),
+            Ty::Error => write!(f, "{{error}}"),
         }
     }
 }
@@ -91,7 +91,8 @@ impl<'src> R0Vm<'src> {
     fn index_globals(
         globals: &[GlobalValue],
     ) -> Result<(BTreeMap<Addr, ManagedMemory>, HashMap<u32, Addr>)> {
-        let mut curr_max_addr = 0u64;
+        // Leave address 0 unused, so no global lives at a null address
+        let mut curr_max_addr = 8u64;
 
         let mut globals_map = BTreeMap::new();
         let mut idx = HashMap::new();
@@ -102,8 +103,9 @@ impl<'src> R0Vm<'src> {
             let len = x.bytes.len();
             let managed = ManagedMemory::from_slice(&x.bytes[..])?;
 
-            let mem_addr = round_up_to_multiple(curr_max_addr + len as u64, 8);
-            curr_max_addr = mem_addr;
+            let mem_addr = round_up_to_multiple(curr_max_addr, 8);
+            // zero-sized globals still need a distinct address
+            curr_max_addr = mem_addr + len.max(1) as u64;
             if mem_addr >= R0Vm::HEAP_START {
                 return Err(Error::OutOfMemory);
             }
@@ -7,9 +7,13 @@ use crate::{span::Span, util::P};
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
+mod visit;
+pub use visit::*;
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct Program {
+    pub structs: Vec<StructStmt>,
     pub decls: Vec<DeclStmt>,
     pub funcs: Vec<FuncStmt>,
 }
@@ -28,6 +32,21 @@ pub struct FuncStmt {
     pub body: BlockStmt,
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct StructStmt {
+    pub span: Span,
+    pub name: Ident,
+    pub fields: Vec<StructField>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct StructField {
+    pub name: Ident,
+    pub ty: TyDef,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct FuncParam {
@@ -41,12 +60,15 @@ pub struct FuncParam {
 pub enum Stmt {
     Block(BlockStmt),
     While(WhileStmt),
+    For(ForStmt),
+    DoWhile(DoWhileStmt),
     If(IfStmt),
+    Switch(SwitchStmt),
     Expr(Expr),
     Decl(DeclStmt),
     Return(ReturnStmt),
-    Break(Span),
-    Continue(Span),
+    Break(BreakStmt),
+    Continue(ContinueStmt),
     Empty(Span),
 }
 
@@ -55,12 +77,15 @@ impl Stmt {
         match self {
             Stmt::Block(i) => i.span,
             Stmt::While(i) => i.span,
+            Stmt::For(i) => i.span,
+            Stmt::DoWhile(i) => i.span,
             Stmt::If(i) => i.span,
+            Stmt::Switch(i) => i.span,
             Stmt::Expr(i) => i.span(),
             Stmt::Decl(i) => i.span,
             Stmt::Return(i) => i.span,
-            Stmt::Break(s) => *s,
-            Stmt::Continue(s) => *s,
+            Stmt::Break(i) => i.span,
+            Stmt::Continue(i) => i.span,
             Stmt::Empty(s) => *s,
         }
     }
@@ -71,7 +96,8 @@ impl Stmt {
 pub struct DeclStmt {
     pub is_const: bool,
     pub name: Ident,
-    pub ty: TyDef,
+    /// The declared type, or `None` if it's inferred from `val`
+    pub ty: Option<TyDef>,
     pub val: Option<P<Expr>>,
     pub span: Span,
 }
@@ -83,12 +109,36 @@ pub struct ReturnStmt {
     pub span: Span,
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct BreakStmt {
+    pub span: Span,
+    /// The label of the loop to break out of, or `None` for the innermost loop
+    pub label: Option<Ident>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct ContinueStmt {
+    pub span: Span,
+    /// The label of the loop to continue, or `None` for the innermost loop
+    pub label: Option<Ident>,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct TyDef {
     pub span: Span,
-    pub name: SmolStr,
-    pub params: Option<Vec<TyDef>>,
+    pub kind: TyDefKind,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub enum TyDefKind {
+    /// A plain type name, like `int`
+    Named(SmolStr),
+    /// A fixed-size array, like `[int; 10]`
+    Array { elem: P<TyDef>, len: u64 },
 }
 
 #[derive(Debug, Clone)]
@@ -102,10 +152,34 @@ pub struct BlockStmt {
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct WhileStmt {
     pub span: Span,
+    /// The label before the loop, like `'outer` in `'outer: while ...`. The
+    /// name doesn't include the quote.
+    pub label: Option<Ident>,
     pub cond: P<Expr>,
     pub body: P<BlockStmt>,
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct ForStmt {
+    pub span: Span,
+    pub label: Option<Ident>,
+    /// Either a declaration or an expression statement
+    pub init: Option<P<Stmt>>,
+    pub cond: Option<P<Expr>>,
+    pub step: Option<P<Expr>>,
+    pub body: P<BlockStmt>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct DoWhileStmt {
+    pub span: Span,
+    pub label: Option<Ident>,
+    pub body: P<BlockStmt>,
+    pub cond: P<Expr>,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct IfStmt {
@@ -115,6 +189,34 @@ pub struct IfStmt {
     pub else_block: IfElseBlock,
 }
 
+/// `switch expr { case 1: ... default: ... }`. As in C, each case falls
+/// through into the next one, and `break` leaves the switch.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct SwitchStmt {
+    pub span: Span,
+    pub expr: P<Expr>,
+    pub cases: Vec<SwitchCase>,
+}
+
+/// One or more case labels, and the statements run when one of them matches
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct SwitchCase {
+    pub span: Span,
+    pub labels: Vec<CaseLabel>,
+    pub body: BlockStmt,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct CaseLabel {
+    /// The span of the value, or of `default`
+    pub span: Span,
+    /// The value of an integer or char literal, or `None` for `default`
+    pub value: Option<i64>,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub enum IfElseBlock {
@@ -132,7 +234,10 @@ pub enum Expr {
     Literal(LiteralExpr),
     Unary(UnaryExpr),
     Binary(BinaryExpr),
+    Cond(CondExpr),
     Call(CallExpr),
+    Index(IndexExpr),
+    Field(FieldExpr),
 }
 
 impl Expr {
@@ -144,7 +249,10 @@ impl Expr {
             Expr::Literal(x) => x.span,
             Expr::Unary(x) => x.span,
             Expr::Binary(x) => x.span,
+            Expr::Cond(x) => x.span,
             Expr::Call(x) => x.span,
+            Expr::Index(x) => x.span,
+            Expr::Field(x) => x.span,
         }
     }
 }
@@ -163,6 +271,7 @@ pub enum LiteralKind {
     Float(f64),
     String(String),
     Char(char),
+    Bool(bool),
 }
 
 #[derive(Debug, Clone)]
@@ -173,11 +282,24 @@ pub struct UnaryExpr {
     pub expr: P<Expr>,
 }
 
+/// `cond ? if_val : else_val`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct CondExpr {
+    pub span: Span,
+    pub cond: P<Expr>,
+    pub if_val: P<Expr>,
+    pub else_val: P<Expr>,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub struct AssignExpr {
     pub span: Span,
     pub allow_assign_const: bool,
+    /// The operator of a compound assignment like `lhs += rhs`, or `None` for
+    /// a plain assignment
+    pub op: Option<BinaryOp>,
     pub lhs: P<Expr>,
     pub rhs: P<Expr>,
 }
@@ -207,11 +329,29 @@ pub struct CallExpr {
     pub params: Vec<Expr>,
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct IndexExpr {
+    pub span: Span,
+    pub base: P<Expr>,
+    pub index: P<Expr>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
+pub struct FieldExpr {
+    pub span: Span,
+    pub base: P<Expr>,
+    pub field: Ident,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "serde_impl", derive(Serialize, Deserialize))]
 pub enum UnaryOp {
     Neg,
     Pos,
+    Not,
+    BitNot,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -221,12 +361,20 @@ pub enum BinaryOp {
     Sub,
     Mul,
     Div,
+    Mod,
+    Shl,
+    Shr,
+    BitAnd,
+    BitOr,
+    Xor,
     Gt,
     Lt,
     Ge,
     Le,
     Eq,
     Neq,
+    And,
+    Or,
 }
 
 #[derive(Debug, Clone)]
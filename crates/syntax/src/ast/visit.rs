@@ -0,0 +1,518 @@
+//! Traversal of the AST.
+//!
+//! `Visitor` walks an AST by shared reference, and `VisitorMut` walks it by
+//! mutable reference. Every `visit_*` method defaults to the matching `walk_*`
+//! function, which visits all children of the node. An implementation only
+//! needs to override the methods of the nodes it cares about, calling the
+//! `walk_*` function itself if it still wants to visit the children.
+//!
+//! Since child nodes are behind `P` (which is `Rc`), `VisitorMut` gets mutable
+//! access with `P::make_mut`, cloning nodes that are shared.
+
+use super::*;
+
+pub trait Visitor<'ast>: Sized {
+    fn visit_program(&mut self, program: &'ast Program) {
+        walk_program(self, program)
+    }
+    fn visit_struct(&mut self, stmt: &'ast StructStmt) {
+        walk_struct(self, stmt)
+    }
+    fn visit_func(&mut self, func: &'ast FuncStmt) {
+        walk_func(self, func)
+    }
+    fn visit_func_param(&mut self, param: &'ast FuncParam) {
+        walk_func_param(self, param)
+    }
+    fn visit_ty(&mut self, _ty: &'ast TyDef) {}
+    fn visit_ident(&mut self, _ident: &'ast Ident) {}
+
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        walk_stmt(self, stmt)
+    }
+    fn visit_block(&mut self, block: &'ast BlockStmt) {
+        walk_block(self, block)
+    }
+    fn visit_while(&mut self, stmt: &'ast WhileStmt) {
+        walk_while(self, stmt)
+    }
+    fn visit_for(&mut self, stmt: &'ast ForStmt) {
+        walk_for(self, stmt)
+    }
+    fn visit_do_while(&mut self, stmt: &'ast DoWhileStmt) {
+        walk_do_while(self, stmt)
+    }
+    fn visit_if(&mut self, stmt: &'ast IfStmt) {
+        walk_if(self, stmt)
+    }
+    fn visit_if_else_block(&mut self, block: &'ast IfElseBlock) {
+        walk_if_else_block(self, block)
+    }
+    fn visit_decl(&mut self, stmt: &'ast DeclStmt) {
+        walk_decl(self, stmt)
+    }
+    fn visit_return(&mut self, stmt: &'ast ReturnStmt) {
+        walk_return(self, stmt)
+    }
+    fn visit_switch(&mut self, stmt: &'ast SwitchStmt) {
+        walk_switch(self, stmt)
+    }
+    fn visit_break(&mut self, _stmt: &'ast BreakStmt) {}
+    fn visit_continue(&mut self, _stmt: &'ast ContinueStmt) {}
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        walk_expr(self, expr)
+    }
+    fn visit_literal(&mut self, _expr: &'ast LiteralExpr) {}
+    fn visit_assign(&mut self, expr: &'ast AssignExpr) {
+        walk_assign(self, expr)
+    }
+    fn visit_as(&mut self, expr: &'ast AsExpr) {
+        walk_as(self, expr)
+    }
+    fn visit_unary(&mut self, expr: &'ast UnaryExpr) {
+        walk_unary(self, expr)
+    }
+    fn visit_binary(&mut self, expr: &'ast BinaryExpr) {
+        walk_binary(self, expr)
+    }
+    fn visit_cond(&mut self, expr: &'ast CondExpr) {
+        walk_cond(self, expr)
+    }
+    fn visit_call(&mut self, expr: &'ast CallExpr) {
+        walk_call(self, expr)
+    }
+    fn visit_index(&mut self, expr: &'ast IndexExpr) {
+        walk_index(self, expr)
+    }
+    fn visit_field(&mut self, expr: &'ast FieldExpr) {
+        walk_field(self, expr)
+    }
+}
+
+pub fn walk_program<'ast, V: Visitor<'ast>>(v: &mut V, program: &'ast Program) {
+    for stmt in &program.structs {
+        v.visit_struct(stmt);
+    }
+    for decl in &program.decls {
+        v.visit_decl(decl);
+    }
+    for func in &program.funcs {
+        v.visit_func(func);
+    }
+}
+
+pub fn walk_struct<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast StructStmt) {
+    v.visit_ident(&stmt.name);
+    for field in &stmt.fields {
+        v.visit_ident(&field.name);
+        v.visit_ty(&field.ty);
+    }
+}
+
+pub fn walk_func<'ast, V: Visitor<'ast>>(v: &mut V, func: &'ast FuncStmt) {
+    v.visit_ident(&func.name);
+    for param in &func.params {
+        v.visit_func_param(param);
+    }
+    v.visit_ty(&func.ret_ty);
+    v.visit_block(&func.body);
+}
+
+pub fn walk_func_param<'ast, V: Visitor<'ast>>(v: &mut V, param: &'ast FuncParam) {
+    v.visit_ident(&param.name);
+    v.visit_ty(&param.ty);
+}
+
+pub fn walk_stmt<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast Stmt) {
+    match stmt {
+        Stmt::Block(x) => v.visit_block(x),
+        Stmt::While(x) => v.visit_while(x),
+        Stmt::For(x) => v.visit_for(x),
+        Stmt::DoWhile(x) => v.visit_do_while(x),
+        Stmt::If(x) => v.visit_if(x),
+        Stmt::Expr(x) => v.visit_expr(x),
+        Stmt::Decl(x) => v.visit_decl(x),
+        Stmt::Return(x) => v.visit_return(x),
+        Stmt::Switch(stmt) => v.visit_switch(stmt),
+        Stmt::Break(stmt) => v.visit_break(stmt),
+        Stmt::Continue(stmt) => v.visit_continue(stmt),
+        Stmt::Empty(_) => {}
+    }
+}
+
+pub fn walk_block<'ast, V: Visitor<'ast>>(v: &mut V, block: &'ast BlockStmt) {
+    for stmt in &block.stmts {
+        v.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_while<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast WhileStmt) {
+    v.visit_expr(&stmt.cond);
+    v.visit_block(&stmt.body);
+}
+
+pub fn walk_for<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast ForStmt) {
+    if let Some(init) = &stmt.init {
+        v.visit_stmt(init);
+    }
+    if let Some(cond) = &stmt.cond {
+        v.visit_expr(cond);
+    }
+    if let Some(step) = &stmt.step {
+        v.visit_expr(step);
+    }
+    v.visit_block(&stmt.body);
+}
+
+pub fn walk_do_while<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast DoWhileStmt) {
+    v.visit_block(&stmt.body);
+    v.visit_expr(&stmt.cond);
+}
+
+pub fn walk_if<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast IfStmt) {
+    v.visit_expr(&stmt.cond);
+    v.visit_block(&stmt.if_block);
+    v.visit_if_else_block(&stmt.else_block);
+}
+
+pub fn walk_if_else_block<'ast, V: Visitor<'ast>>(v: &mut V, block: &'ast IfElseBlock) {
+    match block {
+        IfElseBlock::None => {}
+        IfElseBlock::If(x) => v.visit_if(x),
+        IfElseBlock::Block(x) => v.visit_block(x),
+    }
+}
+
+pub fn walk_decl<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast DeclStmt) {
+    v.visit_ident(&stmt.name);
+    if let Some(ty) = &stmt.ty {
+        v.visit_ty(ty);
+    }
+    if let Some(val) = &stmt.val {
+        v.visit_expr(val);
+    }
+}
+
+pub fn walk_switch<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast SwitchStmt) {
+    v.visit_expr(&stmt.expr);
+    for case in &stmt.cases {
+        v.visit_block(&case.body);
+    }
+}
+
+pub fn walk_return<'ast, V: Visitor<'ast>>(v: &mut V, stmt: &'ast ReturnStmt) {
+    if let Some(val) = &stmt.val {
+        v.visit_expr(val);
+    }
+}
+
+pub fn walk_expr<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast Expr) {
+    match expr {
+        Expr::Ident(x) => v.visit_ident(x),
+        Expr::Assign(x) => v.visit_assign(x),
+        Expr::As(x) => v.visit_as(x),
+        Expr::Literal(x) => v.visit_literal(x),
+        Expr::Unary(x) => v.visit_unary(x),
+        Expr::Binary(x) => v.visit_binary(x),
+        Expr::Cond(x) => v.visit_cond(x),
+        Expr::Call(x) => v.visit_call(x),
+        Expr::Index(x) => v.visit_index(x),
+        Expr::Field(x) => v.visit_field(x),
+    }
+}
+
+pub fn walk_assign<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast AssignExpr) {
+    v.visit_expr(&expr.lhs);
+    v.visit_expr(&expr.rhs);
+}
+
+pub fn walk_as<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast AsExpr) {
+    v.visit_expr(&expr.val);
+    v.visit_ty(&expr.ty);
+}
+
+pub fn walk_unary<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast UnaryExpr) {
+    v.visit_expr(&expr.expr);
+}
+
+pub fn walk_binary<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast BinaryExpr) {
+    v.visit_expr(&expr.lhs);
+    v.visit_expr(&expr.rhs);
+}
+
+pub fn walk_cond<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast CondExpr) {
+    v.visit_expr(&expr.cond);
+    v.visit_expr(&expr.if_val);
+    v.visit_expr(&expr.else_val);
+}
+
+pub fn walk_call<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast CallExpr) {
+    v.visit_ident(&expr.func);
+    for param in &expr.params {
+        v.visit_expr(param);
+    }
+}
+
+pub fn walk_index<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast IndexExpr) {
+    v.visit_expr(&expr.base);
+    v.visit_expr(&expr.index);
+}
+
+pub fn walk_field<'ast, V: Visitor<'ast>>(v: &mut V, expr: &'ast FieldExpr) {
+    v.visit_expr(&expr.base);
+    v.visit_ident(&expr.field);
+}
+
+pub trait VisitorMut: Sized {
+    fn visit_program_mut(&mut self, program: &mut Program) {
+        walk_program_mut(self, program)
+    }
+    fn visit_struct_mut(&mut self, stmt: &mut StructStmt) {
+        walk_struct_mut(self, stmt)
+    }
+    fn visit_func_mut(&mut self, func: &mut FuncStmt) {
+        walk_func_mut(self, func)
+    }
+    fn visit_func_param_mut(&mut self, param: &mut FuncParam) {
+        walk_func_param_mut(self, param)
+    }
+    fn visit_ty_mut(&mut self, _ty: &mut TyDef) {}
+    fn visit_ident_mut(&mut self, _ident: &mut Ident) {}
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt)
+    }
+    fn visit_block_mut(&mut self, block: &mut BlockStmt) {
+        walk_block_mut(self, block)
+    }
+    fn visit_while_mut(&mut self, stmt: &mut WhileStmt) {
+        walk_while_mut(self, stmt)
+    }
+    fn visit_for_mut(&mut self, stmt: &mut ForStmt) {
+        walk_for_mut(self, stmt)
+    }
+    fn visit_do_while_mut(&mut self, stmt: &mut DoWhileStmt) {
+        walk_do_while_mut(self, stmt)
+    }
+    fn visit_if_mut(&mut self, stmt: &mut IfStmt) {
+        walk_if_mut(self, stmt)
+    }
+    fn visit_if_else_block_mut(&mut self, block: &mut IfElseBlock) {
+        walk_if_else_block_mut(self, block)
+    }
+    fn visit_decl_mut(&mut self, stmt: &mut DeclStmt) {
+        walk_decl_mut(self, stmt)
+    }
+    fn visit_return_mut(&mut self, stmt: &mut ReturnStmt) {
+        walk_return_mut(self, stmt)
+    }
+    fn visit_switch_mut(&mut self, stmt: &mut SwitchStmt) {
+        walk_switch_mut(self, stmt)
+    }
+    fn visit_break_mut(&mut self, _stmt: &mut BreakStmt) {}
+    fn visit_continue_mut(&mut self, _stmt: &mut ContinueStmt) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr)
+    }
+    fn visit_literal_mut(&mut self, _expr: &mut LiteralExpr) {}
+    fn visit_assign_mut(&mut self, expr: &mut AssignExpr) {
+        walk_assign_mut(self, expr)
+    }
+    fn visit_as_mut(&mut self, expr: &mut AsExpr) {
+        walk_as_mut(self, expr)
+    }
+    fn visit_unary_mut(&mut self, expr: &mut UnaryExpr) {
+        walk_unary_mut(self, expr)
+    }
+    fn visit_binary_mut(&mut self, expr: &mut BinaryExpr) {
+        walk_binary_mut(self, expr)
+    }
+    fn visit_cond_mut(&mut self, expr: &mut CondExpr) {
+        walk_cond_mut(self, expr)
+    }
+    fn visit_call_mut(&mut self, expr: &mut CallExpr) {
+        walk_call_mut(self, expr)
+    }
+    fn visit_index_mut(&mut self, expr: &mut IndexExpr) {
+        walk_index_mut(self, expr)
+    }
+    fn visit_field_mut(&mut self, expr: &mut FieldExpr) {
+        walk_field_mut(self, expr)
+    }
+}
+
+pub fn walk_program_mut<V: VisitorMut>(v: &mut V, program: &mut Program) {
+    for stmt in &mut program.structs {
+        v.visit_struct_mut(stmt);
+    }
+    for decl in &mut program.decls {
+        v.visit_decl_mut(decl);
+    }
+    for func in &mut program.funcs {
+        v.visit_func_mut(func);
+    }
+}
+
+pub fn walk_struct_mut<V: VisitorMut>(v: &mut V, stmt: &mut StructStmt) {
+    v.visit_ident_mut(&mut stmt.name);
+    for field in &mut stmt.fields {
+        v.visit_ident_mut(&mut field.name);
+        v.visit_ty_mut(&mut field.ty);
+    }
+}
+
+pub fn walk_func_mut<V: VisitorMut>(v: &mut V, func: &mut FuncStmt) {
+    v.visit_ident_mut(&mut func.name);
+    for param in &mut func.params {
+        v.visit_func_param_mut(param);
+    }
+    v.visit_ty_mut(&mut func.ret_ty);
+    v.visit_block_mut(&mut func.body);
+}
+
+pub fn walk_func_param_mut<V: VisitorMut>(v: &mut V, param: &mut FuncParam) {
+    v.visit_ident_mut(&mut param.name);
+    v.visit_ty_mut(&mut param.ty);
+}
+
+pub fn walk_stmt_mut<V: VisitorMut>(v: &mut V, stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Block(x) => v.visit_block_mut(x),
+        Stmt::While(x) => v.visit_while_mut(x),
+        Stmt::For(x) => v.visit_for_mut(x),
+        Stmt::DoWhile(x) => v.visit_do_while_mut(x),
+        Stmt::If(x) => v.visit_if_mut(x),
+        Stmt::Expr(x) => v.visit_expr_mut(x),
+        Stmt::Decl(x) => v.visit_decl_mut(x),
+        Stmt::Return(x) => v.visit_return_mut(x),
+        Stmt::Switch(stmt) => v.visit_switch_mut(stmt),
+        Stmt::Break(stmt) => v.visit_break_mut(stmt),
+        Stmt::Continue(stmt) => v.visit_continue_mut(stmt),
+        Stmt::Empty(_) => {}
+    }
+}
+
+pub fn walk_block_mut<V: VisitorMut>(v: &mut V, block: &mut BlockStmt) {
+    for stmt in &mut block.stmts {
+        v.visit_stmt_mut(stmt);
+    }
+}
+
+pub fn walk_while_mut<V: VisitorMut>(v: &mut V, stmt: &mut WhileStmt) {
+    v.visit_expr_mut(P::make_mut(&mut stmt.cond));
+    v.visit_block_mut(P::make_mut(&mut stmt.body));
+}
+
+pub fn walk_for_mut<V: VisitorMut>(v: &mut V, stmt: &mut ForStmt) {
+    if let Some(init) = &mut stmt.init {
+        v.visit_stmt_mut(P::make_mut(init));
+    }
+    if let Some(cond) = &mut stmt.cond {
+        v.visit_expr_mut(P::make_mut(cond));
+    }
+    if let Some(step) = &mut stmt.step {
+        v.visit_expr_mut(P::make_mut(step));
+    }
+    v.visit_block_mut(P::make_mut(&mut stmt.body));
+}
+
+pub fn walk_do_while_mut<V: VisitorMut>(v: &mut V, stmt: &mut DoWhileStmt) {
+    v.visit_block_mut(P::make_mut(&mut stmt.body));
+    v.visit_expr_mut(P::make_mut(&mut stmt.cond));
+}
+
+pub fn walk_if_mut<V: VisitorMut>(v: &mut V, stmt: &mut IfStmt) {
+    v.visit_expr_mut(P::make_mut(&mut stmt.cond));
+    v.visit_block_mut(P::make_mut(&mut stmt.if_block));
+    v.visit_if_else_block_mut(&mut stmt.else_block);
+}
+
+pub fn walk_if_else_block_mut<V: VisitorMut>(v: &mut V, block: &mut IfElseBlock) {
+    match block {
+        IfElseBlock::None => {}
+        IfElseBlock::If(x) => v.visit_if_mut(P::make_mut(x)),
+        IfElseBlock::Block(x) => v.visit_block_mut(P::make_mut(x)),
+    }
+}
+
+pub fn walk_decl_mut<V: VisitorMut>(v: &mut V, stmt: &mut DeclStmt) {
+    v.visit_ident_mut(&mut stmt.name);
+    if let Some(ty) = &mut stmt.ty {
+        v.visit_ty_mut(ty);
+    }
+    if let Some(val) = &mut stmt.val {
+        v.visit_expr_mut(P::make_mut(val));
+    }
+}
+
+pub fn walk_switch_mut<V: VisitorMut>(v: &mut V, stmt: &mut SwitchStmt) {
+    v.visit_expr_mut(P::make_mut(&mut stmt.expr));
+    for case in &mut stmt.cases {
+        v.visit_block_mut(&mut case.body);
+    }
+}
+
+pub fn walk_return_mut<V: VisitorMut>(v: &mut V, stmt: &mut ReturnStmt) {
+    if let Some(val) = &mut stmt.val {
+        v.visit_expr_mut(P::make_mut(val));
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut>(v: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Ident(x) => v.visit_ident_mut(x),
+        Expr::Assign(x) => v.visit_assign_mut(x),
+        Expr::As(x) => v.visit_as_mut(x),
+        Expr::Literal(x) => v.visit_literal_mut(x),
+        Expr::Unary(x) => v.visit_unary_mut(x),
+        Expr::Binary(x) => v.visit_binary_mut(x),
+        Expr::Cond(x) => v.visit_cond_mut(x),
+        Expr::Call(x) => v.visit_call_mut(x),
+        Expr::Index(x) => v.visit_index_mut(x),
+        Expr::Field(x) => v.visit_field_mut(x),
+    }
+}
+
+pub fn walk_assign_mut<V: VisitorMut>(v: &mut V, expr: &mut AssignExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.lhs));
+    v.visit_expr_mut(P::make_mut(&mut expr.rhs));
+}
+
+pub fn walk_as_mut<V: VisitorMut>(v: &mut V, expr: &mut AsExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.val));
+    v.visit_ty_mut(&mut expr.ty);
+}
+
+pub fn walk_unary_mut<V: VisitorMut>(v: &mut V, expr: &mut UnaryExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.expr));
+}
+
+pub fn walk_binary_mut<V: VisitorMut>(v: &mut V, expr: &mut BinaryExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.lhs));
+    v.visit_expr_mut(P::make_mut(&mut expr.rhs));
+}
+
+pub fn walk_cond_mut<V: VisitorMut>(v: &mut V, expr: &mut CondExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.cond));
+    v.visit_expr_mut(P::make_mut(&mut expr.if_val));
+    v.visit_expr_mut(P::make_mut(&mut expr.else_val));
+}
+
+pub fn walk_call_mut<V: VisitorMut>(v: &mut V, expr: &mut CallExpr) {
+    v.visit_ident_mut(&mut expr.func);
+    for param in &mut expr.params {
+        v.visit_expr_mut(param);
+    }
+}
+
+pub fn walk_index_mut<V: VisitorMut>(v: &mut V, expr: &mut IndexExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.base));
+    v.visit_expr_mut(P::make_mut(&mut expr.index));
+}
+
+pub fn walk_field_mut<V: VisitorMut>(v: &mut V, expr: &mut FieldExpr) {
+    v.visit_expr_mut(P::make_mut(&mut expr.base));
+    v.visit_ident_mut(&mut expr.field);
+}
@@ -0,0 +1,80 @@
+use crate::span::Span;
+
+/// How severe a diagnostic is
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+/// A message reported to the user about their code, shared by every stage of
+/// the compiler.
+///
+/// `code` is a stable identifier like `E0001`. Codes are never reused or
+/// renumbered, so tools can depend on them.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub code: &'static str,
+    pub message: String,
+    /// The main position this diagnostic is about
+    pub span: Option<Span>,
+    /// Other positions related to this diagnostic
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+/// A secondary position of a diagnostic, with a message explaining it
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            level,
+            code,
+            message: message.into(),
+            span: None,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Level::Error, code, message)
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Level::Warning, code, message)
+    }
+
+    pub fn with_span(mut self, span: Option<Span>) -> Diagnostic {
+        self.span = span;
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+}
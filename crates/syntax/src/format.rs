@@ -0,0 +1,565 @@
+//! A pretty-printer that turns an AST back into canonical r0 source.
+//!
+//! Comments are not part of the AST, so they are recovered from the trivia
+//! between the tokens of `spanned_lexer`. Each comment is printed on its own
+//! line before the item or statement that follows it, unless it was trailing
+//! some code on the same line, in which case it stays at the end of that line.
+
+use crate::{ast::*, lexer::spanned_lexer, span::Span, Token};
+
+const INDENT: &str = "    ";
+
+/// Format `program`, which must be parsed from `source` without errors.
+pub fn format_program(program: &Program, source: &str) -> String {
+    let mut f = Formatter {
+        source,
+        comments: collect_comments(source),
+        next_comment: 0,
+        out: String::new(),
+        indent: 0,
+        cursor: 0,
+    };
+    f.program(program);
+    f.out
+}
+
+#[derive(Debug)]
+struct Comment<'s> {
+    span: Span,
+    text: &'s str,
+    /// Whether this comment follows a token on the same line
+    trailing: bool,
+}
+
+/// Find every comment inside the trivia between tokens of `source`.
+fn collect_comments(source: &str) -> Vec<Comment<'_>> {
+    let mut comments = vec![];
+    let mut prev_end = None;
+    let spans = spanned_lexer(source)
+        .map(|(_, span)| span)
+        .chain(std::iter::once(Span::new(source.len(), 0)));
+
+    for span in spans {
+        let trivia_start = prev_end.unwrap_or(0);
+        let bytes = &source.as_bytes()[trivia_start..span.start()];
+        let mut trailing = prev_end.is_some();
+        let mut i = 0;
+        while i < bytes.len() {
+            let len = if bytes[i..].starts_with(b"//") {
+                bytes[i..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .unwrap_or(bytes.len() - i)
+            } else if bytes[i..].starts_with(b"/*") {
+                block_comment_len(&bytes[i..])
+            } else {
+                if bytes[i] == b'\n' {
+                    trailing = false;
+                }
+                i += 1;
+                continue;
+            };
+            let start = trivia_start + i;
+            comments.push(Comment {
+                span: Span::new(start, len),
+                text: source[start..start + len].trim_end(),
+                trailing,
+            });
+            i += len;
+        }
+        prev_end = Some(span.end());
+    }
+    comments
+}
+
+/// Length of the (possibly nested) block comment at the start of `bytes`
+fn block_comment_len(bytes: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+enum Item<'a> {
+    Struct(&'a StructStmt),
+    Decl(&'a DeclStmt),
+    Func(&'a FuncStmt),
+}
+
+impl Item<'_> {
+    fn span(&self) -> Span {
+        match self {
+            Item::Struct(x) => x.span,
+            Item::Decl(x) => x.span,
+            Item::Func(x) => x.span,
+        }
+    }
+}
+
+struct Formatter<'s> {
+    source: &'s str,
+    comments: Vec<Comment<'s>>,
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    /// Source position of the end of the last thing printed
+    cursor: usize,
+}
+
+impl<'s> Formatter<'s> {
+    fn line(&mut self, s: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    /// Add an empty line, unless it would be the first line of the output or
+    /// of a block, or follow another empty line.
+    fn blank_line(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// Whether the source has an empty line between `cursor` and `pos`
+    fn has_blank_line(&self, pos: usize) -> bool {
+        let gap = &self.source[self.cursor.min(pos)..pos];
+        let lines = gap.split('\n').collect::<Vec<_>>();
+        lines.len() > 2
+            && lines[1..lines.len() - 1]
+                .iter()
+                .any(|l| l.trim().is_empty())
+    }
+
+    /// Print every comment that starts before `pos`.
+    fn comments_before(&mut self, pos: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.start() >= pos {
+                break;
+            }
+            self.next_comment += 1;
+            let (text, span) = (comment.text, comment.span);
+
+            if comment.trailing && self.out.ends_with('\n') {
+                self.out.pop();
+                self.out.push(' ');
+                self.out.push_str(text);
+                self.out.push('\n');
+            } else {
+                if self.has_blank_line(span.start()) {
+                    self.blank_line();
+                }
+                self.line(text);
+            }
+            self.cursor = span.end();
+        }
+    }
+
+    /// Print the comments before a statement or item starting at `pos`, and
+    /// keep an empty line before it if there's one in the source.
+    fn leading_trivia(&mut self, pos: usize) {
+        self.comments_before(pos);
+        if self.has_blank_line(pos) {
+            self.blank_line();
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        let mut items = program
+            .structs
+            .iter()
+            .map(Item::Struct)
+            .chain(program.decls.iter().map(Item::Decl))
+            .chain(program.funcs.iter().map(Item::Func))
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| item.span().start());
+
+        let mut prev_is_decl = true;
+        for item in items {
+            let is_decl = matches!(item, Item::Decl(_));
+            // keep declarations together, but always separate other items
+            if !(prev_is_decl && is_decl) {
+                self.blank_line();
+            }
+            prev_is_decl = is_decl;
+
+            let span = item.span();
+            self.leading_trivia(span.start());
+            match item {
+                Item::Struct(x) => self.struct_stmt(x),
+                Item::Decl(x) => {
+                    let decl = self.decl(x);
+                    self.line(&decl)
+                }
+                Item::Func(x) => self.func(x),
+            }
+            self.cursor = self.cursor.max(span.end());
+        }
+        self.comments_before(usize::MAX);
+    }
+
+    fn struct_stmt(&mut self, stmt: &StructStmt) {
+        self.line(&format!("struct {} {{", stmt.name.name));
+        self.indent += 1;
+        for field in &stmt.fields {
+            self.leading_trivia(field.name.span.start());
+            let field_line = format!("{}: {},", field.name.name, ty(&field.ty));
+            self.line(&field_line);
+            self.cursor = field.ty.span.end();
+        }
+        self.comments_before(stmt.span.end() - 1);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn func(&mut self, func: &FuncStmt) {
+        let params = func
+            .params
+            .iter()
+            .map(|param| {
+                format!(
+                    "{}{}: {}",
+                    if param.is_const { "const " } else { "" },
+                    param.name.name,
+                    ty(&param.ty)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.line(&format!(
+            "fn {}({}) -> {} {{",
+            func.name.name,
+            params,
+            ty(&func.ret_ty)
+        ));
+        self.block(&func.body);
+        self.line("}");
+    }
+
+    fn decl(&self, decl: &DeclStmt) -> String {
+        let kw = if decl.is_const { "const" } else { "let" };
+        let annotation = match &decl.ty {
+            Some(decl_ty) => format!(": {}", ty(decl_ty)),
+            None => String::new(),
+        };
+        match &decl.val {
+            Some(val) => format!(
+                "{} {}{} = {};",
+                kw,
+                decl.name.name,
+                annotation,
+                self.expr(val)
+            ),
+            None => format!("{} {}{};", kw, decl.name.name, annotation),
+        }
+    }
+
+    /// Print the statements of a block. The lines containing its braces are
+    /// printed by the caller.
+    fn block(&mut self, block: &BlockStmt) {
+        self.indent += 1;
+        self.cursor = block.span.start() + 1;
+        for stmt in &block.stmts {
+            self.stmt(stmt);
+        }
+        self.comments_before(block.span.end() - 1);
+        self.indent -= 1;
+        self.cursor = block.span.end();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        let span = stmt.span();
+        self.leading_trivia(span.start());
+        match stmt {
+            Stmt::Block(block) => {
+                self.line("{");
+                self.block(block);
+                self.line("}");
+            }
+            Stmt::While(stmt) => {
+                let head = format!(
+                    "{}while {} {{",
+                    label_prefix(&stmt.label),
+                    self.expr(&stmt.cond)
+                );
+                self.line(&head);
+                self.block(&stmt.body);
+                self.line("}");
+            }
+            Stmt::For(stmt) => {
+                let mut head = format!("{}for (", label_prefix(&stmt.label));
+                match stmt.init.as_deref() {
+                    Some(Stmt::Decl(decl)) => head.push_str(&self.decl(decl)),
+                    Some(Stmt::Expr(expr)) => head.push_str(&format!("{};", self.expr(expr))),
+                    _ => head.push(';'),
+                }
+                if let Some(cond) = &stmt.cond {
+                    head.push(' ');
+                    head.push_str(&self.expr(cond));
+                }
+                head.push(';');
+                if let Some(step) = &stmt.step {
+                    head.push(' ');
+                    head.push_str(&self.expr(step));
+                }
+                head.push_str(") {");
+                self.line(&head);
+                self.block(&stmt.body);
+                self.line("}");
+            }
+            Stmt::DoWhile(stmt) => {
+                self.line(&format!("{}do {{", label_prefix(&stmt.label)));
+                self.block(&stmt.body);
+                let tail = format!("}} while {};", self.expr(&stmt.cond));
+                self.line(&tail);
+            }
+            Stmt::If(stmt) => self.if_stmt(stmt, ""),
+            Stmt::Switch(stmt) => self.switch_stmt(stmt),
+            Stmt::Expr(expr) => {
+                let line = format!("{};", self.expr(expr));
+                self.line(&line);
+            }
+            Stmt::Decl(decl) => {
+                let line = self.decl(decl);
+                self.line(&line);
+            }
+            Stmt::Return(stmt) => {
+                let line = match &stmt.val {
+                    Some(val) => format!("return {};", self.expr(val)),
+                    None => "return;".into(),
+                };
+                self.line(&line);
+            }
+            Stmt::Break(stmt) => self.line(&format!("break{};", label_suffix(&stmt.label))),
+            Stmt::Continue(stmt) => self.line(&format!("continue{};", label_suffix(&stmt.label))),
+            Stmt::Empty(_) => self.line(";"),
+        }
+        self.cursor = self.cursor.max(span.end());
+    }
+
+    fn if_stmt(&mut self, stmt: &IfStmt, prefix: &str) {
+        let head = format!("{}if {} {{", prefix, self.expr(&stmt.cond));
+        self.line(&head);
+        self.block(&stmt.if_block);
+        match &stmt.else_block {
+            IfElseBlock::None => self.line("}"),
+            IfElseBlock::If(stmt) => self.if_stmt(stmt, "} else "),
+            IfElseBlock::Block(block) => {
+                self.line("} else {");
+                self.block(block);
+                self.line("}");
+            }
+        }
+    }
+
+    fn switch_stmt(&mut self, stmt: &SwitchStmt) {
+        let head = format!("switch {} {{", self.expr(&stmt.expr));
+        self.line(&head);
+        self.cursor = stmt.expr.span().end();
+        self.indent += 1;
+        for case in &stmt.cases {
+            self.leading_trivia(case.span.start());
+            for label in &case.labels {
+                let line = match label.value {
+                    Some(value) => {
+                        let text = self.source.get(label.span.start()..label.span.end());
+                        match text {
+                            Some(text) if !text.is_empty() => format!("case {}:", text),
+                            _ => format!("case {}:", value),
+                        }
+                    }
+                    None => "default:".into(),
+                };
+                self.line(&line);
+                self.cursor = label.span.end();
+            }
+            self.indent += 1;
+            for stmt in &case.body.stmts {
+                self.stmt(stmt);
+            }
+            self.indent -= 1;
+        }
+        self.comments_before(stmt.span.end() - 1);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn expr(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Ident(ident) => ident.name.to_string(),
+            Expr::Literal(lit) => self.literal(lit),
+            Expr::Call(call) => format!(
+                "{}({})",
+                call.func.name,
+                call.params
+                    .iter()
+                    .map(|param| self.expr(param))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Expr::Index(expr) => format!(
+                "{}[{}]",
+                self.postfix_operand(&expr.base),
+                self.expr(&expr.index)
+            ),
+            Expr::Field(expr) => {
+                format!("{}.{}", self.postfix_operand(&expr.base), expr.field.name)
+            }
+            Expr::Unary(expr) => {
+                let op = match expr.op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Pos => "+",
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                let needs_paren = match expr.expr.as_ref() {
+                    Expr::Binary(_) | Expr::Cond(_) | Expr::Assign(_) | Expr::As(_) => true,
+                    // `- -x` must not become `--x`
+                    Expr::Unary(inner) => matches!(
+                        (expr.op, inner.op),
+                        (UnaryOp::Neg, UnaryOp::Neg) | (UnaryOp::Pos, UnaryOp::Pos)
+                    ),
+                    _ => false,
+                };
+                format!("{}{}", op, self.maybe_paren(&expr.expr, needs_paren))
+            }
+            Expr::As(expr) => {
+                let needs_paren = matches!(
+                    expr.val.as_ref(),
+                    Expr::Binary(_) | Expr::Cond(_) | Expr::Assign(_)
+                );
+                format!(
+                    "{} as {}",
+                    self.maybe_paren(&expr.val, needs_paren),
+                    ty(&expr.ty)
+                )
+            }
+            Expr::Binary(expr) => {
+                let (op, token) = binary_op_token(expr.op);
+                let precedence = token.precedence();
+                // binary operators are all left-associative
+                let lhs = self.maybe_paren(&expr.lhs, expr_precedence(&expr.lhs) < precedence);
+                let rhs = self.maybe_paren(&expr.rhs, expr_precedence(&expr.rhs) <= precedence);
+                format!("{} {} {}", lhs, op, rhs)
+            }
+            Expr::Cond(expr) => {
+                // `?` is right-associative
+                let precedence = Token::Question.precedence();
+                let cond = self.maybe_paren(&expr.cond, expr_precedence(&expr.cond) <= precedence);
+                let else_val =
+                    self.maybe_paren(&expr.else_val, expr_precedence(&expr.else_val) < precedence);
+                format!("{} ? {} : {}", cond, self.expr(&expr.if_val), else_val)
+            }
+            Expr::Assign(expr) => {
+                let precedence = Token::Assign.precedence();
+                let lhs = self.maybe_paren(&expr.lhs, expr_precedence(&expr.lhs) <= precedence);
+                let op = expr.op.map_or("", |op| binary_op_token(op).0);
+                format!("{} {}= {}", lhs, op, self.expr(&expr.rhs))
+            }
+        }
+    }
+
+    fn maybe_paren(&self, expr: &Expr, paren: bool) -> String {
+        if paren {
+            format!("({})", self.expr(expr))
+        } else {
+            self.expr(expr)
+        }
+    }
+
+    fn postfix_operand(&self, expr: &Expr) -> String {
+        let needs_paren = matches!(
+            expr,
+            Expr::Binary(_) | Expr::Cond(_) | Expr::Assign(_) | Expr::As(_) | Expr::Unary(_)
+        );
+        self.maybe_paren(expr, needs_paren)
+    }
+
+    fn literal(&self, lit: &LiteralExpr) -> String {
+        // Print literals as they are written, keeping radix prefixes,
+        // separators and escapes
+        if let Some(text) = self.source.get(lit.span.start()..lit.span.end()) {
+            if !text.is_empty() {
+                return text.into();
+            }
+        }
+        match &lit.kind {
+            LiteralKind::Integer(i) => i.to_string(),
+            LiteralKind::Float(f) => format!("{:?}", f),
+            LiteralKind::String(s) => format!("{:?}", s),
+            LiteralKind::Char(c) => format!("{:?}", c),
+            LiteralKind::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+/// `'label: ` before a loop, if it has one
+fn label_prefix(label: &Option<Ident>) -> String {
+    match label {
+        Some(label) => format!("'{}: ", label.name),
+        None => String::new(),
+    }
+}
+
+/// ` 'label` after `break` or `continue`, if it has one
+fn label_suffix(label: &Option<Ident>) -> String {
+    match label {
+        Some(label) => format!(" '{}", label.name),
+        None => String::new(),
+    }
+}
+
+fn ty(ty: &TyDef) -> String {
+    match &ty.kind {
+        TyDefKind::Named(name) => name.to_string(),
+        TyDefKind::Array { elem, len } => format!("[{}; {}]", self::ty(elem), len),
+    }
+}
+
+/// Binding strength of `expr` when used as an operand
+fn expr_precedence(expr: &Expr) -> u32 {
+    match expr {
+        Expr::Binary(expr) => binary_op_token(expr.op).1.precedence(),
+        Expr::Cond(_) => Token::Question.precedence(),
+        Expr::Assign(_) => Token::Assign.precedence(),
+        _ => u32::MAX,
+    }
+}
+
+fn binary_op_token(op: BinaryOp) -> (&'static str, Token) {
+    match op {
+        BinaryOp::Add => ("+", Token::Plus),
+        BinaryOp::Sub => ("-", Token::Minus),
+        BinaryOp::Mul => ("*", Token::Mul),
+        BinaryOp::Div => ("/", Token::Div),
+        BinaryOp::Mod => ("%", Token::Mod),
+        BinaryOp::Shl => ("<<", Token::Shl),
+        BinaryOp::Shr => (">>", Token::Shr),
+        BinaryOp::BitAnd => ("&", Token::BitAnd),
+        BinaryOp::BitOr => ("|", Token::BitOr),
+        BinaryOp::Xor => ("^", Token::Xor),
+        BinaryOp::Gt => (">", Token::Gt),
+        BinaryOp::Lt => ("<", Token::Lt),
+        BinaryOp::Ge => (">=", Token::Ge),
+        BinaryOp::Le => ("<=", Token::Le),
+        BinaryOp::Eq => ("==", Token::Eq),
+        BinaryOp::Neq => ("!=", Token::Neq),
+        BinaryOp::And => ("&&", Token::And),
+        BinaryOp::Or => ("||", Token::Or),
+    }
+}
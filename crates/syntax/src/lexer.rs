@@ -1,6 +1,6 @@
 use logos::Logos;
 
-use crate::{prelude::Span, token::Token};
+use crate::{prelude::Span, span::FileId, token::Token};
 
 pub type Lexer<'src> = logos::Lexer<'src, Token>;
 
@@ -9,7 +9,12 @@ pub fn lexer(s: &str) -> Lexer {
 }
 
 pub fn spanned_lexer<'s>(s: &'s str) -> impl Iterator<Item = (Token, Span)> + 's {
+    spanned_lexer_in(s, FileId::default())
+}
+
+/// Like `spanned_lexer`, but with spans pointing into `file`.
+pub fn spanned_lexer_in<'s>(s: &'s str, file: FileId) -> impl Iterator<Item = (Token, Span)> + 's {
     Token::lexer(s)
         .spanned()
-        .map(|(t, s)| (t, crate::prelude::Span::new_idx(s.start, s.end)))
+        .map(move |(t, s)| (t, Span::new_idx(s.start, s.end).in_file(file)))
 }
@@ -1,5 +1,9 @@
 /// A span representing a section of source file
 pub mod span;
+/// Source files and the resolution of spans into lines and columns
+pub mod source_map;
+/// Messages reported to the user, with error codes
+pub mod diagnostic;
 
 /// Utilities
 pub mod util;
@@ -11,6 +15,8 @@ pub mod token;
 
 /// Models of the abstract syntax tree.
 pub mod ast;
+/// Formatter that prints programs as canonical source
+pub mod format;
 /// Parser for r0 programs
 pub mod parser;
 
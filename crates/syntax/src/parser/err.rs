@@ -1,4 +1,4 @@
-use crate::{prelude::Span, Token};
+use crate::{diagnostic::Diagnostic, prelude::Span, Token};
 
 #[derive(Debug)]
 pub struct ParseError {
@@ -28,5 +28,150 @@ pub enum ParseErrorKind {
     ExpectToken(Token),
     ExpectedPattern(String),
     UnexpectedEof,
+    UnterminatedComment,
+    IntegerOutOfRange,
+    /// A case value, or `default`, appearing twice in the same switch
+    DuplicateCase {
+        case: String,
+        /// Where the case appears first
+        first: Span,
+    },
     Dummy,
 }
+
+impl ParseErrorKind {
+    /// The stable error code of this kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseErrorKind::ExpectToken(_) => "E0001",
+            ParseErrorKind::ExpectedPattern(_) => "E0002",
+            ParseErrorKind::UnexpectedEof => "E0003",
+            ParseErrorKind::UnterminatedComment => "E0004",
+            ParseErrorKind::IntegerOutOfRange => "E0005",
+            ParseErrorKind::DuplicateCase { .. } => "E0006",
+            ParseErrorKind::Dummy => "E0000",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ParseErrorKind::ExpectToken(token) => {
+                format!("expected {}", describe_pattern(&format!("{:?}", token)))
+            }
+            ParseErrorKind::ExpectedPattern(pat) => format!("expected {}", describe_pattern(pat)),
+            ParseErrorKind::UnexpectedEof => "unexpected end of file".into(),
+            ParseErrorKind::UnterminatedComment => "unterminated block comment".into(),
+            ParseErrorKind::IntegerOutOfRange => "integer literal is too large".into(),
+            ParseErrorKind::DuplicateCase { case, .. } => format!("duplicate case `{}`", case),
+            ParseErrorKind::Dummy => "unknown parse error".into(),
+        }
+    }
+}
+
+impl ParseError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diag = Diagnostic::error(self.kind.code(), self.kind.message()).with_span(self.span);
+        match &self.kind {
+            ParseErrorKind::UnterminatedComment => {
+                diag.with_note("block comments nest, so every `/*` needs its own `*/`")
+            }
+            ParseErrorKind::IntegerOutOfRange => {
+                diag.with_note("integer literals must fit in 64 bits")
+            }
+            ParseErrorKind::DuplicateCase { first, .. } => {
+                diag.with_label(*first, "first used here")
+            }
+            _ => diag,
+        }
+    }
+}
+
+/// Turn a token pattern like `Token::LetKw | Token::Ident(_)` into something
+/// readable like `` `let` or identifier ``.
+fn describe_pattern(pat: &str) -> String {
+    let mut alternatives = pat
+        .split('|')
+        .map(|alt| {
+            let name = alt.trim().trim_start_matches("Token::");
+            let name = name.split('(').next().unwrap_or(name);
+            describe_token_name(name)
+        })
+        .collect::<Vec<_>>();
+    match alternatives.len() {
+        0 | 1 => alternatives.pop().unwrap_or_default(),
+        _ => {
+            let last = alternatives.pop().unwrap();
+            format!("{} or {}", alternatives.join(", "), last)
+        }
+    }
+}
+
+fn describe_token_name(name: &str) -> String {
+    let spelling = match name {
+        "FnKw" => "fn",
+        "LetKw" => "let",
+        "ConstKw" => "const",
+        "AsKw" => "as",
+        "WhileKw" => "while",
+        "ForKw" => "for",
+        "DoKw" => "do",
+        "IfKw" => "if",
+        "ElseKw" => "else",
+        "ReturnKw" => "return",
+        "BreakKw" => "break",
+        "ContinueKw" => "continue",
+        "StructKw" => "struct",
+        "SwitchKw" => "switch",
+        "CaseKw" => "case",
+        "DefaultKw" => "default",
+        "TrueKw" => "true",
+        "FalseKw" => "false",
+        "UIntLiteral" => return "integer literal".into(),
+        "FloatLiteral" => return "float literal".into(),
+        "CharLiteral" => return "char literal".into(),
+        "StringLiteral" => return "string literal".into(),
+        "Ident" => return "identifier".into(),
+        "Label" => return "label".into(),
+        "Plus" => "+",
+        "Minus" => "-",
+        "Mul" => "*",
+        "Div" => "/",
+        "Mod" => "%",
+        "Shl" => "<<",
+        "Shr" => ">>",
+        "BitAnd" => "&",
+        "BitOr" => "|",
+        "Xor" => "^",
+        "BitNot" => "~",
+        "Assign" => "=",
+        "AddAssign" => "+=",
+        "SubAssign" => "-=",
+        "MulAssign" => "*=",
+        "DivAssign" => "/=",
+        "ModAssign" => "%=",
+        "Eq" => "==",
+        "Neq" => "!=",
+        "Lt" => "<",
+        "Gt" => ">",
+        "Le" => "<=",
+        "Ge" => ">=",
+        "And" => "&&",
+        "Or" => "||",
+        "Not" => "!",
+        "LParen" => "(",
+        "RParen" => ")",
+        "LBrace" => "{",
+        "RBrace" => "}",
+        "LBracket" => "[",
+        "RBracket" => "]",
+        "Arrow" => "->",
+        "Comma" => ",",
+        "Question" => "?",
+        "Colon" => ":",
+        "Dot" => ".",
+        "Semicolon" => ";",
+        // Free-form descriptions written by the parser
+        _ => return name.into(),
+    };
+    format!("`{}`", spelling)
+}
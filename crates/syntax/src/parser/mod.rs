@@ -13,18 +13,14 @@ use crate::ast::*;
 
 pub struct Parser<L> {
     pub lexer: L,
+    errors: Vec<ParseError>,
 }
 
 macro_rules! expect {
     ($self:expr, $($pat:pat)|+) => {
         $self
             .next_if(|token| matches!(token, $($pat)|+))
-            .map_err(|span| {
-                ParseError::new(
-                    ParseErrorKind::ExpectedPattern(stringify!($($pat)|+).to_owned()),
-                    span,
-                )
-            })
+            .map_err(|span| $self.unexpected(stringify!($($pat)|+), span))
     };
 }
 
@@ -34,31 +30,48 @@ macro_rules! is_next {
     };
 }
 
+/// Parse a possibly empty list of items, with `$detect_end` telling whether
+/// the list is already over before its first item.
 macro_rules! separated {
-    ( $parse:expr, $detect_sep:expr, $parse_sep:expr) => {{
-        let first: Result<_, ParseError> = (|| $parse)();
-        if let Ok(val) = first {
-            let mut v = vec![val];
+    ( $parse:expr, $detect_sep:expr, $parse_sep:expr, $detect_end:expr) => {{
+        if $detect_end {
+            Vec::new()
+        } else {
+            let first: Result<_, ParseError> = (|| $parse)();
+            let mut v = vec![first?];
             while $detect_sep {
                 let _ = $parse_sep;
                 let next = (|| $parse)()?;
                 v.push(next);
             }
             v
-        } else {
-            Vec::new()
         }
     }};
 }
 
+/// The error kind for finding `token` where `expected` should be. Tokens that
+/// the lexer produces for malformed input get their own error kinds.
+fn unexpected_kind(token: Option<&Token>, expected: &str) -> ParseErrorKind {
+    match token {
+        Some(Token::UnterminatedComment) => ParseErrorKind::UnterminatedComment,
+        _ => ParseErrorKind::ExpectedPattern(expected.to_owned()),
+    }
+}
+
 /// Combine `lhs` and `rhs` using `op`.
 ///
 /// Requires `op` to be a binary operator, aka `op.is_binary_op() == true`
 fn combine_expr(lhs: Expr, rhs: Expr, op: Token) -> Expr {
     match op {
-        Token::Assign => {
+        Token::Assign
+        | Token::AddAssign
+        | Token::SubAssign
+        | Token::MulAssign
+        | Token::DivAssign
+        | Token::ModAssign => {
             let span = lhs.span() + rhs.span();
             Expr::Assign(AssignExpr {
+                op: op.compound_assign_op(),
                 lhs: P::new(lhs),
                 rhs: P::new(rhs),
                 allow_assign_const: false,
@@ -80,17 +93,6 @@ fn combine_expr(lhs: Expr, rhs: Expr, op: Token) -> Expr {
     }
 }
 
-macro_rules! repeated {
-    ( $parse:expr, $detect_sep:expr) => {{
-        let mut v = vec![];
-        while !$detect_sep {
-            let val: Result<_, ParseError> = (|| $parse)();
-            v.push(val?);
-        }
-        v
-    }};
-}
-
 impl<L> Parser<Peekable<L>>
 where
     L: Iterator<Item = (Token, Span)>,
@@ -98,11 +100,19 @@ where
     pub fn new(lexer: L) -> Parser<Peekable<L>> {
         Parser {
             lexer: lexer.peekable(),
+            errors: vec![],
         }
     }
 
-    pub fn parse(&mut self) -> Result<Program, ParseError> {
-        self.parse_program()
+    /// Parse the whole program.
+    ///
+    /// The parser recovers from syntax errors by skipping to the next
+    /// synchronization point (`;`, `}` or `fn`), so the returned program may be
+    /// partial. Every error encountered is returned in source order; the
+    /// program is only valid when the error list is empty.
+    pub fn parse(&mut self) -> (Program, Vec<ParseError>) {
+        let program = self.parse_program();
+        (program, std::mem::take(&mut self.errors))
     }
 
     fn peek(&mut self) -> Option<&Token> {
@@ -121,24 +131,81 @@ where
         }
     }
 
-    fn parse_program(&mut self) -> Result<Program, ParseError> {
+    /// Build an error for the unexpected next token.
+    fn unexpected(&mut self, expected: &str, span: Option<Span>) -> ParseError {
+        ParseError::new(unexpected_kind(self.peek(), expected), span)
+    }
+
+    /// Skip tokens until a synchronization point is reached.
+    ///
+    /// A `;` or a brace-enclosed group that closes at the current nesting
+    /// level is consumed; an unmatched `}`, a `fn` or `struct` keyword or the
+    /// end of input is left in place for the caller to handle.
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Semicolon if depth == 0 => {
+                    self.lexer.next();
+                    return;
+                }
+                Token::FnKw | Token::StructKw if depth == 0 => return,
+                Token::LBrace => depth += 1,
+                Token::RBrace => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        self.lexer.next();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.lexer.next();
+        }
+    }
+
+    fn parse_program(&mut self) -> Program {
+        let mut structs = vec![];
         let mut funcs = vec![];
         let mut decls = vec![];
         loop {
-            if is_next!(self, Token::FnKw) {
-                let res = self.parse_fn_decl()?;
-                funcs.push(res);
+            let res = if is_next!(self, Token::FnKw) {
+                self.parse_fn_decl().map(|res| funcs.push(res))
+            } else if is_next!(self, Token::StructKw) {
+                self.parse_struct_decl().map(|res| structs.push(res))
             } else if is_next!(self, Token::LetKw) {
-                let res = self.parse_decl()?;
-                decls.push(res);
+                self.parse_decl().map(|res| decls.push(res))
             } else if is_next!(self, Token::ConstKw) {
-                let res = self.parse_const_decl()?;
-                decls.push(res);
+                self.parse_const_decl().map(|res| decls.push(res))
+            } else if let Some((token, span)) = self.lexer.next() {
+                // Skip everything until the next item
+                self.errors.push(ParseError::new_span(
+                    unexpected_kind(Some(&token), "fn, struct, let or const"),
+                    span,
+                ));
+                while !is_next!(
+                    self,
+                    Token::FnKw | Token::StructKw | Token::LetKw | Token::ConstKw
+                ) && self.lexer.next().is_some()
+                {}
+                Ok(())
             } else {
                 break;
+            };
+
+            if let Err(e) = res {
+                self.errors.push(e);
+                self.synchronize();
             }
         }
-        Ok(Program { decls, funcs })
+        Program {
+            structs,
+            decls,
+            funcs,
+        }
     }
 
     fn parse_ident(&mut self) -> Result<Ident, ParseError> {
@@ -149,21 +216,56 @@ where
         })
     }
 
+    fn parse_label(&mut self) -> Result<Ident, ParseError> {
+        let (label, span) = expect!(self, Token::Label(_))?;
+        let name = match label {
+            Token::Label(name) => name,
+            _ => unreachable!(),
+        };
+        Ok(Ident { span, name })
+    }
+
+    /// Parse the label of a `break` or `continue`, if there is one
+    fn parse_opt_label(&mut self) -> Result<Option<Ident>, ParseError> {
+        if is_next!(self, Token::Label(_)) {
+            Ok(Some(self.parse_label()?))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn parse_ty(&mut self) -> Result<TyDef, ParseError> {
+        // Ty -> Ident | '[' Ty ';' UIntLiteral ']'
+        if is_next!(self, Token::LBracket) {
+            let (_, start_span) = expect!(self, Token::LBracket)?;
+            let elem = self.parse_ty()?;
+            expect!(self, Token::Semicolon)?;
+            let (len, len_span) = expect!(self, Token::UIntLiteral(_))?;
+            let len = len
+                .get_uint()
+                .ok_or_else(|| ParseError::new_span(ParseErrorKind::IntegerOutOfRange, len_span))?;
+            let (_, end_span) = expect!(self, Token::RBracket)?;
+            return Ok(TyDef {
+                span: start_span + end_span,
+                kind: TyDefKind::Array {
+                    elem: P::new(elem),
+                    len,
+                },
+            });
+        }
+
         let (name, name_span) = expect!(self, Token::Ident(_))?;
         Ok(TyDef {
             span: name_span,
-            name: name.get_ident_owned().unwrap(),
-            params: None,
+            kind: TyDefKind::Named(name.get_ident_owned().unwrap()),
         })
     }
 
     fn parse_decl(&mut self) -> Result<DeclStmt, ParseError> {
+        // DeclStmt -> 'let' Ident (':' TypeDef)? ('=' Expr)? ';'
         let (_, _start_span) = expect!(self, Token::LetKw)?;
         let ident = self.parse_ident()?;
-
-        expect!(self, Token::Colon)?;
-        let ty = self.parse_ty()?;
+        let ty = self.parse_decl_ty()?;
 
         let val = if is_next!(self, Token::Assign) {
             expect!(self, Token::Assign)?;
@@ -184,11 +286,10 @@ where
     }
 
     fn parse_const_decl(&mut self) -> Result<DeclStmt, ParseError> {
+        // ConstDeclStmt -> 'const' Ident (':' TypeDef)? '=' Expr ';'
         let (_, _start_span) = expect!(self, Token::ConstKw)?;
         let ident = self.parse_ident()?;
-
-        expect!(self, Token::Colon)?;
-        let ty = self.parse_ty()?;
+        let ty = self.parse_decl_ty()?;
 
         expect!(self, Token::Assign)?;
         let val = P::new(self.parse_expr()?);
@@ -204,6 +305,16 @@ where
         })
     }
 
+    /// Parse the optional type annotation of a declaration
+    fn parse_decl_ty(&mut self) -> Result<Option<TyDef>, ParseError> {
+        if is_next!(self, Token::Colon) {
+            expect!(self, Token::Colon)?;
+            Ok(Some(self.parse_ty()?))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn parse_func_call(&mut self, func: Ident) -> Result<CallExpr, ParseError> {
         // FunctionCall -> Ident '(' (Expr (,Expr)* )? ')'
 
@@ -211,7 +322,8 @@ where
         let params = separated!(
             self.parse_expr(),
             is_next!(self, Token::Comma),
-            self.lexer.next()
+            self.lexer.next(),
+            is_next!(self, Token::RParen)
         );
         let (_, r_span) = expect!(self, Token::RParen)?;
 
@@ -237,11 +349,14 @@ where
             } else {
                 Ok(Expr::Ident(ident))
             }
-        } else if is_next!(self, Token::UIntLiteral(_)|Token::CharLiteral(_)) {
+        } else if is_next!(self, Token::UIntLiteral(_) | Token::CharLiteral(_)) {
             let (num, span) = self.lexer.next().unwrap();
+            let num = num
+                .get_uint()
+                .ok_or_else(|| ParseError::new_span(ParseErrorKind::IntegerOutOfRange, span))?;
             Ok(Expr::Literal(LiteralExpr {
                 span,
-                kind: LiteralKind::Integer(num.get_uint().unwrap()),
+                kind: LiteralKind::Integer(num),
             }))
         } else if is_next!(self, Token::FloatLiteral(_)) {
             let (num, span) = self.lexer.next().unwrap();
@@ -255,40 +370,59 @@ where
                 span,
                 kind: LiteralKind::String(num.get_string_owned().unwrap()),
             }))
+        } else if is_next!(self, Token::TrueKw | Token::FalseKw) {
+            let (val, span) = self.lexer.next().unwrap();
+            Ok(Expr::Literal(LiteralExpr {
+                span,
+                kind: LiteralKind::Bool(matches!(val, Token::TrueKw)),
+            }))
         } else if is_next!(self, Token::LParen) {
             expect!(self, Token::LParen)?;
             let expr = self.parse_expr()?;
             expect!(self, Token::RParen)?;
             Ok(expr)
         } else {
-            Err(ParseError {
-                kind: ParseErrorKind::ExpectedPattern(
-                    "Literal or Identifier or parenthesis".into(),
-                ),
-                span: self
-                    .lexer
-                    .peek()
-                    .map(|(_, s)| *s)
-                    .unwrap_or_else(Span::eof)
-                    .into(),
-            })
+            let span = self.lexer.peek().map(|(_, s)| *s).unwrap_or_else(Span::eof);
+            Err(self.unexpected("literal, identifier or parenthesis", Some(span)))
         }
     }
 
     fn parse_unary_expr(&mut self) -> Result<Expr, ParseError> {
-        // UExpr -> PreUOp* Item ProUOp*
-        // PreUOp -> '+' | '-'
+        // UExpr -> PreUOp* Item Postfix* ProUOp*
+        // PreUOp -> '-' | '!' | '~'
+        // Postfix -> '[' Expr ']' | '.' Ident
         // ProUOp -> 'as' TypeDef
         let mut prec_ops = vec![];
-        while is_next!(self, Token::Minus) {
+        while is_next!(self, Token::Minus | Token::Not | Token::BitNot) {
             prec_ops.push(self.lexer.next().unwrap())
         }
 
         let mut item = self.parse_item()?;
+        while is_next!(self, Token::LBracket | Token::Dot) {
+            let (postfix, _) = self.lexer.next().unwrap();
+            if let Token::LBracket = postfix {
+                let index = self.parse_expr()?;
+                let (_, end_span) = expect!(self, Token::RBracket)?;
+                item = Expr::Index(IndexExpr {
+                    span: item.span() + end_span,
+                    base: P::new(item),
+                    index: P::new(index),
+                });
+            } else {
+                let field = self.parse_ident()?;
+                item = Expr::Field(FieldExpr {
+                    span: item.span() + field.span,
+                    base: P::new(item),
+                    field,
+                });
+            }
+        }
         for (prec_op, span) in prec_ops.drain(..).rev() {
             let unary_op = match prec_op {
                 Token::Plus => UnaryOp::Pos,
                 Token::Minus => UnaryOp::Neg,
+                Token::Not => UnaryOp::Not,
+                Token::BitNot => UnaryOp::BitNot,
                 _ => unreachable!(),
             };
             item = Expr::Unary(UnaryExpr {
@@ -318,11 +452,20 @@ where
         }) {
             // OPG
             let (op, _) = self.lexer.next().unwrap();
+            // `?` is parsed as a binary operator between `cond` and
+            // `else_val`, whose other operand `if_val` is parsed here
+            let if_val = if let Token::Question = op {
+                let if_val = self.parse_expr()?;
+                expect!(self, Token::Colon)?;
+                Some(if_val)
+            } else {
+                None
+            };
             let mut rhs = self.parse_unary_expr()?;
 
             while self.lexer.peek().map_or(false, |(x, _)| {
                 x.is_binary_op()
-                    && ((x.precedence() > op.precedence() && x.is_left_assoc())
+                    && (x.precedence() > op.precedence()
                         || (x.precedence() == op.precedence() && !x.is_left_assoc()))
             }) {
                 let (op, _) = self.lexer.peek().unwrap();
@@ -330,7 +473,15 @@ where
                 rhs = self.parse_expr_opg(rhs, op_precedence)?;
             }
 
-            lhs = combine_expr(lhs, rhs, op);
+            lhs = match if_val {
+                Some(if_val) => Expr::Cond(CondExpr {
+                    span: lhs.span() + rhs.span(),
+                    cond: P::new(lhs),
+                    if_val: P::new(if_val),
+                    else_val: P::new(rhs),
+                }),
+                None => combine_expr(lhs, rhs, op),
+            };
         }
         Ok(lhs)
     }
@@ -348,7 +499,20 @@ where
 
     fn parse_block(&mut self) -> Result<BlockStmt, ParseError> {
         let (_, _start_span) = expect!(self, Token::LBrace)?;
-        let vals = repeated!(self.parse_stmt(), is_next!(self, Token::RBrace));
+        let mut vals = vec![];
+        // `fn` and `struct` keywords can never appear inside a block, so they
+        // mean the closing brace is missing.
+        while self.peek().is_some()
+            && !is_next!(self, Token::RBrace | Token::FnKw | Token::StructKw)
+        {
+            match self.parse_stmt() {
+                Ok(stmt) => vals.push(stmt),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize();
+                }
+            }
+        }
         let (_, _end_span) = expect!(self, Token::RBrace)?;
         Ok(BlockStmt {
             stmts: vals,
@@ -395,12 +559,171 @@ where
         let body = self.parse_block()?;
         let span = cond.span() + body.span;
         Ok(WhileStmt {
+            label: None,
             cond: P::new(cond),
             body: P::new(body),
             span,
         })
     }
 
+    fn parse_for_stmt(&mut self) -> Result<ForStmt, ParseError> {
+        // ForStmt -> 'for' '(' (DeclStmt | ExprStmt | ';') Expr? ';' Expr? ')' Block
+        let (_, start_span) = expect!(self, Token::ForKw)?;
+        expect!(self, Token::LParen)?;
+
+        let init = if is_next!(self, Token::LetKw) {
+            Some(P::new(Stmt::Decl(self.parse_decl()?)))
+        } else if is_next!(self, Token::Semicolon) {
+            self.lexer.next();
+            None
+        } else {
+            Some(P::new(Stmt::Expr(self.parse_expr_stmt()?)))
+        };
+
+        let cond = if !is_next!(self, Token::Semicolon) {
+            Some(P::new(self.parse_expr()?))
+        } else {
+            None
+        };
+        expect!(self, Token::Semicolon)?;
+
+        let step = if !is_next!(self, Token::RParen) {
+            Some(P::new(self.parse_expr()?))
+        } else {
+            None
+        };
+        expect!(self, Token::RParen)?;
+
+        let body = self.parse_block()?;
+        let span = start_span + body.span;
+        Ok(ForStmt {
+            span,
+            label: None,
+            init,
+            cond,
+            step,
+            body: P::new(body),
+        })
+    }
+
+    fn parse_do_while_stmt(&mut self) -> Result<DoWhileStmt, ParseError> {
+        // DoWhileStmt -> 'do' Block 'while' Expr ';'
+        let (_, start_span) = expect!(self, Token::DoKw)?;
+        let body = self.parse_block()?;
+        expect!(self, Token::WhileKw)?;
+        let cond = self.parse_expr()?;
+        let (_, end_span) = expect!(self, Token::Semicolon)?;
+        Ok(DoWhileStmt {
+            span: start_span + end_span,
+            label: None,
+            body: P::new(body),
+            cond: P::new(cond),
+        })
+    }
+
+    fn parse_switch_stmt(&mut self) -> Result<SwitchStmt, ParseError> {
+        // SwitchStmt -> 'switch' Expr '{' SwitchCase* '}'
+        // SwitchCase -> CaseLabel+ Stmt*
+        let (_, start_span) = expect!(self, Token::SwitchKw)?;
+        let expr = self.parse_expr()?;
+        expect!(self, Token::LBrace)?;
+
+        let mut cases = vec![];
+        let mut seen = vec![];
+        while is_next!(self, Token::CaseKw | Token::DefaultKw) {
+            let case_span = self.lexer.peek().unwrap().1;
+            let mut labels = vec![];
+            while is_next!(self, Token::CaseKw | Token::DefaultKw) {
+                let label = self.parse_case_label()?;
+                if let Some((_, first)) = seen.iter().find(|(value, _)| *value == label.value) {
+                    let case = match label.value {
+                        Some(value) => value.to_string(),
+                        None => "default".into(),
+                    };
+                    self.errors.push(ParseError::new_span(
+                        ParseErrorKind::DuplicateCase {
+                            case,
+                            first: *first,
+                        },
+                        label.span,
+                    ));
+                } else {
+                    seen.push((label.value, label.span));
+                }
+                labels.push(label);
+            }
+
+            let mut stmts = vec![];
+            while self.peek().is_some()
+                && !is_next!(
+                    self,
+                    Token::CaseKw
+                        | Token::DefaultKw
+                        | Token::RBrace
+                        | Token::FnKw
+                        | Token::StructKw
+                )
+            {
+                match self.parse_stmt() {
+                    Ok(stmt) => stmts.push(stmt),
+                    Err(e) => {
+                        self.errors.push(e);
+                        self.synchronize();
+                    }
+                }
+            }
+            let body_span = match (stmts.first(), stmts.last()) {
+                (Some(first), Some(last)) => first.span() + last.span(),
+                _ => labels.last().unwrap().span,
+            };
+            cases.push(SwitchCase {
+                span: case_span + body_span,
+                labels,
+                body: BlockStmt {
+                    span: body_span,
+                    stmts,
+                },
+            });
+        }
+
+        let (_, end_span) = expect!(self, Token::RBrace)?;
+        Ok(SwitchStmt {
+            span: start_span + end_span,
+            expr: P::new(expr),
+            cases,
+        })
+    }
+
+    fn parse_case_label(&mut self) -> Result<CaseLabel, ParseError> {
+        // CaseLabel -> 'case' '-'? (UIntLiteral | CharLiteral) ':' | 'default' ':'
+        if is_next!(self, Token::DefaultKw) {
+            let (_, span) = self.lexer.next().unwrap();
+            expect!(self, Token::Colon)?;
+            return Ok(CaseLabel { span, value: None });
+        }
+
+        expect!(self, Token::CaseKw)?;
+        let minus_span = if is_next!(self, Token::Minus) {
+            Some(self.lexer.next().unwrap().1)
+        } else {
+            None
+        };
+        let (num, num_span) = expect!(self, Token::UIntLiteral(_) | Token::CharLiteral(_))?;
+        let span = minus_span.map_or(num_span, |s| s + num_span);
+        let out_of_range = || ParseError::new_span(ParseErrorKind::IntegerOutOfRange, span);
+        let num = num.get_uint().ok_or_else(out_of_range)?;
+        let value = match minus_span {
+            Some(_) if num <= i64::MIN.unsigned_abs() => (num as i64).wrapping_neg(),
+            None if num <= i64::MAX as u64 => num as i64,
+            _ => return Err(out_of_range()),
+        };
+        expect!(self, Token::Colon)?;
+        Ok(CaseLabel {
+            span,
+            value: Some(value),
+        })
+    }
+
     fn parse_return_stmt(&mut self) -> Result<ReturnStmt, ParseError> {
         let (_, _start_span) = expect!(self, Token::ReturnKw)?;
 
@@ -417,16 +740,59 @@ where
         })
     }
 
-    fn parse_break_stmt(&mut self) -> Result<Span, ParseError> {
-        let (_, span) = expect!(self, Token::BreakKw)?;
-        expect!(self, Token::Semicolon)?;
-        Ok(span)
+    fn parse_break_stmt(&mut self) -> Result<BreakStmt, ParseError> {
+        // BreakStmt -> 'break' Label? ';'
+        let (_, start_span) = expect!(self, Token::BreakKw)?;
+        let label = self.parse_opt_label()?;
+        let (_, end_span) = expect!(self, Token::Semicolon)?;
+        Ok(BreakStmt {
+            span: start_span + end_span,
+            label,
+        })
     }
 
-    fn parse_continue_stmt(&mut self) -> Result<Span, ParseError> {
-        let (_, span) = expect!(self, Token::ContinueKw)?;
-        expect!(self, Token::Semicolon)?;
-        Ok(span)
+    fn parse_continue_stmt(&mut self) -> Result<ContinueStmt, ParseError> {
+        // ContinueStmt -> 'continue' Label? ';'
+        let (_, start_span) = expect!(self, Token::ContinueKw)?;
+        let label = self.parse_opt_label()?;
+        let (_, end_span) = expect!(self, Token::Semicolon)?;
+        Ok(ContinueStmt {
+            span: start_span + end_span,
+            label,
+        })
+    }
+
+    fn parse_labeled_loop(&mut self) -> Result<Stmt, ParseError> {
+        // LabeledLoop -> Label ':' (WhileStmt | ForStmt | DoWhileStmt)
+        let label = self.parse_label()?;
+        expect!(self, Token::Colon)?;
+        let span = label.span;
+        let label = Some(label);
+        if is_next!(self, Token::WhileKw) {
+            let stmt = self.parse_while_stmt()?;
+            Ok(Stmt::While(WhileStmt {
+                span: span + stmt.span,
+                label,
+                ..stmt
+            }))
+        } else if is_next!(self, Token::ForKw) {
+            let stmt = self.parse_for_stmt()?;
+            Ok(Stmt::For(ForStmt {
+                span: span + stmt.span,
+                label,
+                ..stmt
+            }))
+        } else if is_next!(self, Token::DoKw) {
+            let stmt = self.parse_do_while_stmt()?;
+            Ok(Stmt::DoWhile(DoWhileStmt {
+                span: span + stmt.span,
+                label,
+                ..stmt
+            }))
+        } else {
+            let span = self.lexer.peek().map(|(_, s)| *s).unwrap_or_else(Span::eof);
+            Err(self.unexpected("`while`, `for` or `do`", Some(span)))
+        }
     }
 
     fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
@@ -438,8 +804,16 @@ where
             Stmt::Block(self.parse_block()?)
         } else if is_next!(self, Token::IfKw) {
             Stmt::If(self.parse_if_stmt()?)
+        } else if is_next!(self, Token::SwitchKw) {
+            Stmt::Switch(self.parse_switch_stmt()?)
         } else if is_next!(self, Token::WhileKw) {
             Stmt::While(self.parse_while_stmt()?)
+        } else if is_next!(self, Token::ForKw) {
+            Stmt::For(self.parse_for_stmt()?)
+        } else if is_next!(self, Token::DoKw) {
+            Stmt::DoWhile(self.parse_do_while_stmt()?)
+        } else if is_next!(self, Token::Label(_)) {
+            self.parse_labeled_loop()?
         } else if is_next!(self, Token::BreakKw) {
             Stmt::Break(self.parse_break_stmt()?)
         } else if is_next!(self, Token::ContinueKw) {
@@ -454,6 +828,34 @@ where
         Ok(val)
     }
 
+    fn parse_struct_decl(&mut self) -> Result<StructStmt, ParseError> {
+        // StructDecl -> 'struct' Ident '{' (Ident ':' Ty (',' Ident ':' Ty)* ','?)? '}'
+        let (_, start_span) = expect!(self, Token::StructKw)?;
+        let name = self.parse_ident()?;
+
+        expect!(self, Token::LBrace)?;
+        let mut fields = vec![];
+        while !is_next!(self, Token::RBrace) {
+            let field_name = self.parse_ident()?;
+            expect!(self, Token::Colon)?;
+            let ty = self.parse_ty()?;
+            fields.push(StructField {
+                name: field_name,
+                ty,
+            });
+            if !is_next!(self, Token::RBrace) {
+                expect!(self, Token::Comma)?;
+            }
+        }
+        let (_, end_span) = expect!(self, Token::RBrace)?;
+
+        Ok(StructStmt {
+            span: start_span + end_span,
+            name,
+            fields,
+        })
+    }
+
     fn parse_fn_decl(&mut self) -> Result<FuncStmt, ParseError> {
         let (_, _start_span) = expect!(self, Token::FnKw)?;
         let fn_name = self.parse_ident()?;
@@ -475,7 +877,8 @@ where
                 })
             },
             is_next!(self, Token::Comma),
-            expect!(self, Token::Comma)
+            expect!(self, Token::Comma),
+            is_next!(self, Token::RParen)
         );
         expect!(self, Token::RParen)?;
 
@@ -504,29 +907,57 @@ impl Token {
                 | Token::Minus
                 | Token::Mul
                 | Token::Div
+                | Token::Mod
+                | Token::Shl
+                | Token::Shr
+                | Token::BitAnd
+                | Token::BitOr
+                | Token::Xor
                 | Token::Assign
+                | Token::AddAssign
+                | Token::SubAssign
+                | Token::MulAssign
+                | Token::DivAssign
+                | Token::ModAssign
+                | Token::Question
                 | Token::Eq
                 | Token::Neq
                 | Token::Lt
                 | Token::Gt
                 | Token::Le
                 | Token::Ge
+                | Token::And
+                | Token::Or
         )
     }
 
     pub fn precedence(&self) -> u32 {
         match self {
-            Token::Plus => 10,
-            Token::Minus => 10,
-            Token::Mul => 20,
-            Token::Div => 20,
-            Token::Assign => 1,
-            Token::Eq => 2,
-            Token::Neq => 2,
-            Token::Lt => 2,
-            Token::Gt => 2,
-            Token::Le => 2,
-            Token::Ge => 2,
+            Token::Plus => 11,
+            Token::Minus => 11,
+            Token::Mul => 12,
+            Token::Div => 12,
+            Token::Mod => 12,
+            Token::Shl => 10,
+            Token::Shr => 10,
+            Token::BitAnd => 7,
+            Token::BitOr => 5,
+            Token::Xor => 6,
+            Token::Assign
+            | Token::AddAssign
+            | Token::SubAssign
+            | Token::MulAssign
+            | Token::DivAssign
+            | Token::ModAssign => 1,
+            Token::Question => 2,
+            Token::Or => 3,
+            Token::And => 4,
+            Token::Eq => 8,
+            Token::Neq => 8,
+            Token::Lt => 9,
+            Token::Gt => 9,
+            Token::Le => 9,
+            Token::Ge => 9,
             _ => unreachable!("Precedence should only be called by binary operators"),
         }
     }
@@ -537,13 +968,27 @@ impl Token {
             | Token::Minus
             | Token::Mul
             | Token::Div
+            | Token::Mod
+            | Token::Shl
+            | Token::Shr
+            | Token::BitAnd
+            | Token::BitOr
+            | Token::Xor
             | Token::Eq
             | Token::Neq
             | Token::Lt
             | Token::Gt
             | Token::Le
-            | Token::Ge => true,
-            Token::Assign => false,
+            | Token::Ge
+            | Token::And
+            | Token::Or => true,
+            Token::Assign
+            | Token::AddAssign
+            | Token::SubAssign
+            | Token::MulAssign
+            | Token::DivAssign
+            | Token::ModAssign
+            | Token::Question => false,
             _ => unreachable!("Method should only be called by binary operators"),
         }
     }
@@ -554,12 +999,32 @@ impl Token {
             Token::Minus => Some(BinaryOp::Sub),
             Token::Mul => Some(BinaryOp::Mul),
             Token::Div => Some(BinaryOp::Div),
+            Token::Mod => Some(BinaryOp::Mod),
+            Token::Shl => Some(BinaryOp::Shl),
+            Token::Shr => Some(BinaryOp::Shr),
+            Token::BitAnd => Some(BinaryOp::BitAnd),
+            Token::BitOr => Some(BinaryOp::BitOr),
+            Token::Xor => Some(BinaryOp::Xor),
             Token::Eq => Some(BinaryOp::Eq),
             Token::Neq => Some(BinaryOp::Neq),
             Token::Lt => Some(BinaryOp::Lt),
             Token::Gt => Some(BinaryOp::Gt),
             Token::Le => Some(BinaryOp::Le),
             Token::Ge => Some(BinaryOp::Ge),
+            Token::And => Some(BinaryOp::And),
+            Token::Or => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+
+    /// The operator applied by a compound assignment token, like `+` for `+=`
+    pub fn compound_assign_op(&self) -> Option<BinaryOp> {
+        match self {
+            Token::AddAssign => Some(BinaryOp::Add),
+            Token::SubAssign => Some(BinaryOp::Sub),
+            Token::MulAssign => Some(BinaryOp::Mul),
+            Token::DivAssign => Some(BinaryOp::Div),
+            Token::ModAssign => Some(BinaryOp::Mod),
             _ => None,
         }
     }
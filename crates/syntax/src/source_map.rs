@@ -0,0 +1,123 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::span::{FileId, Span};
+
+/// A collection of source files, each with a precomputed table of line starts
+/// so that positions can be resolved to lines and columns quickly.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Add a file to the map, returning the ID spans inside it should use.
+    pub fn add_file(&mut self, name: impl Into<String>, src: impl Into<String>) -> FileId {
+        let id = FileId(self.files.len());
+        self.files.push(SourceFile::new(name.into(), src.into()));
+        id
+    }
+
+    pub fn file(&self, id: FileId) -> &SourceFile {
+        &self.files[id.0]
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files.iter().enumerate().map(|(i, f)| (FileId(i), f))
+    }
+
+    /// Resolve the start of `span` to a location in its file.
+    pub fn location(&self, span: Span) -> Location<'_> {
+        let file = self.file(span.file);
+        let pos = if span.is_eof() {
+            file.src.len()
+        } else {
+            span.start()
+        };
+        let (line, col) = file.line_col(pos);
+        Location {
+            file: &file.name,
+            line,
+            col,
+        }
+    }
+}
+
+/// A single source file.
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub src: String,
+    /// Byte offsets of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: String, src: String) -> SourceFile {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile {
+            name,
+            src,
+            line_starts,
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// The 0-based line containing byte offset `pos`.
+    pub fn line_index(&self, pos: usize) -> usize {
+        match self.line_starts.binary_search(&pos) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        }
+    }
+
+    /// The byte range of 0-based line `line`, without its line break.
+    pub fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.src.len(), |&next| next - 1);
+        let end = if self.src[start..end].ends_with('\r') {
+            end - 1
+        } else {
+            end
+        };
+        start..end
+    }
+
+    /// The text of 0-based line `line`, without its line break.
+    pub fn line(&self, line: usize) -> &str {
+        &self.src[self.line_range(line)]
+    }
+
+    /// The 1-based line and column (in characters) of byte offset `pos`.
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let pos = pos.min(self.src.len());
+        let line = self.line_index(pos);
+        let col = self.src[self.line_starts[line]..pos].chars().count();
+        (line + 1, col + 1)
+    }
+}
+
+/// A resolved position in a source file, displayed as `file:line:col`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
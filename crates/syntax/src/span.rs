@@ -1,11 +1,20 @@
 use std::{fmt::Debug, ops::Index};
 
+/// The identifier of a file inside a [`SourceMap`](crate::source_map::SourceMap).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileId(pub usize);
+
 /// A Span is the information of a piece of source code inside a file.
 ///
-/// `Span`s are only meaningful when indexing the file it is originated from.
+/// `Span`s are only meaningful when indexing the file it is originated from,
+/// which is recorded in `file`.
 #[derive(Clone, Copy, Eq, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Span {
+    /// The file this span is in
+    pub file: FileId,
+
     /// The start index (in bytes or other meaningful item index)
     /// in the file of this span
     pub idx: usize,
@@ -15,7 +24,7 @@ pub struct Span {
 }
 
 pub const DUMMY_SPAN: Span = Span {
-    // ctx: usize::max_value(),
+    file: FileId(0),
     idx: 0,
     len: 0,
 };
@@ -30,17 +39,31 @@ impl Span {
     }
 
     pub fn new(idx: usize, len: usize) -> Span {
-        Span { idx, len }
+        Span {
+            file: FileId::default(),
+            idx,
+            len,
+        }
     }
 
     pub fn new_idx(lo: usize, hi: usize) -> Span {
         let (lo, hi) = if lo > hi { (hi, lo) } else { (lo, hi) };
         let len = hi - lo;
-        Span { idx: lo, len }
+        Span::new(lo, len)
+    }
+
+    /// Move this span into `file`
+    pub fn in_file(self, file: FileId) -> Span {
+        Span { file, ..self }
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.idx == usize::MAX
     }
 
     pub const fn eof() -> Span {
         Span {
+            file: FileId(0),
             idx: usize::max_value(),
             len: 0,
         }
@@ -53,7 +76,7 @@ impl std::ops::Add for Span {
     fn add(self, rhs: Self) -> Self::Output {
         let start = std::cmp::min(self.start(), rhs.start());
         let end = std::cmp::max(self.end(), rhs.end());
-        Span::new_idx(start, end)
+        Span::new_idx(start, end).in_file(self.file)
     }
 }
 
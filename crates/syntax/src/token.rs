@@ -1,4 +1,4 @@
-use logos::{Lexer, Logos};
+use logos::{Filter, Lexer, Logos};
 use smol_str::SmolStr;
 
 fn parse_string_literal(i: &mut Lexer<Token>) -> Option<String> {
@@ -10,6 +10,45 @@ fn parse_char_literal(i: &mut Lexer<Token>) -> Option<char> {
     unescape::unescape(&i.slice()[1..i.slice().len() - 1]).and_then(|x| x.chars().next())
 }
 
+/// Parse an integer literal with an optional `0x`, `0o` or `0b` radix prefix
+/// and `_` separators. The inner value is `None` when it doesn't fit in `u64`.
+fn parse_int_literal(lex: &mut Lexer<Token>) -> Option<Option<u64>> {
+    let slice = lex.slice();
+    let (digits, radix) = match slice.get(..2) {
+        Some("0x") => (&slice[2..], 16),
+        Some("0o") => (&slice[2..], 8),
+        Some("0b") => (&slice[2..], 2),
+        _ => (slice, 10),
+    };
+    let digits = digits.replace('_', "");
+    Some(u64::from_str_radix(&digits, radix).ok())
+}
+
+/// Skip a (possibly nested) block comment, whose opening `/*` has just been
+/// matched. Emits a token spanning to the end of input if it's never closed.
+fn block_comment(lex: &mut Lexer<Token>) -> Filter<()> {
+    let rest = lex.remainder().as_bytes();
+    let mut depth = 1usize;
+    let mut i = 0;
+    while i < rest.len() {
+        if rest[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if rest[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                lex.bump(i);
+                return Filter::Skip;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    lex.bump(rest.len());
+    Filter::Emit(())
+}
+
 #[derive(Debug, Clone, Logos)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
@@ -23,6 +62,10 @@ pub enum Token {
     AsKw,
     #[token("while")]
     WhileKw,
+    #[token("for")]
+    ForKw,
+    #[token("do")]
+    DoKw,
     #[token("if")]
     IfKw,
     #[token("else")]
@@ -33,9 +76,25 @@ pub enum Token {
     BreakKw,
     #[token("continue")]
     ContinueKw,
+    #[token("struct")]
+    StructKw,
+    #[token("switch")]
+    SwitchKw,
+    #[token("case")]
+    CaseKw,
+    #[token("default")]
+    DefaultKw,
+    #[token("true")]
+    TrueKw,
+    #[token("false")]
+    FalseKw,
 
-    #[regex(r"\d+", |lex| lex.slice().parse())]
-    UIntLiteral(u64),
+    /// An integer literal, or `None` if it's out of range
+    #[regex(r"[0-9][0-9_]*", parse_int_literal)]
+    #[regex(r"0x[0-9a-fA-F][0-9a-fA-F_]*", parse_int_literal)]
+    #[regex(r"0o[0-7][0-7_]*", parse_int_literal)]
+    #[regex(r"0b[01][01_]*", parse_int_literal)]
+    UIntLiteral(Option<u64>),
     #[regex(r"\d+\.\d+([eE][+-]?\d+)?", |lex| lex.slice().parse())]
     FloatLiteral(f64),
     #[regex(r#"'([^\\']|\\[rnt\\/"'])'"#, parse_char_literal)]
@@ -44,6 +103,9 @@ pub enum Token {
     StringLiteral(String),
     #[regex(r"[_a-zA-Z][_a-zA-Z0-9]*", |lex| SmolStr::new(lex.slice()))]
     Ident(SmolStr),
+    /// A loop label like `'outer`, without the quote
+    #[regex(r"'[_a-zA-Z][_a-zA-Z0-9]*", |lex| SmolStr::new(&lex.slice()[1..]))]
+    Label(SmolStr),
 
     #[token(r"+")]
     Plus,
@@ -53,8 +115,32 @@ pub enum Token {
     Mul,
     #[token(r"/")]
     Div,
+    #[token(r"%")]
+    Mod,
+    #[token(r"<<")]
+    Shl,
+    #[token(r">>")]
+    Shr,
+    #[token(r"&")]
+    BitAnd,
+    #[token(r"|")]
+    BitOr,
+    #[token(r"^")]
+    Xor,
+    #[token(r"~")]
+    BitNot,
     #[token(r"=")]
     Assign,
+    #[token(r"+=")]
+    AddAssign,
+    #[token(r"-=")]
+    SubAssign,
+    #[token(r"*=")]
+    MulAssign,
+    #[token(r"/=")]
+    DivAssign,
+    #[token(r"%=")]
+    ModAssign,
     #[token(r"==")]
     Eq,
     #[token(r"!=")]
@@ -67,6 +153,12 @@ pub enum Token {
     Le,
     #[token(r">=")]
     Ge,
+    #[token(r"&&")]
+    And,
+    #[token(r"||")]
+    Or,
+    #[token(r"!")]
+    Not,
     #[token("(")]
     LParen,
     #[token(")")]
@@ -75,20 +167,32 @@ pub enum Token {
     LBrace,
     #[token("}")]
     RBrace,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
     #[token(r"->")]
     Arrow,
     #[token(r",")]
     Comma,
+    #[token(r"?")]
+    Question,
     #[token(r":")]
     Colon,
+    #[token(r".")]
+    Dot,
     #[token(r";")]
     Semicolon,
 
     // Empty stuff
     #[regex(r"\s+", logos::skip, priority = 1)]
     Whitespace,
-    #[regex(r"//.*\n", logos::skip)]
+    #[regex(r"//[^\n]*", logos::skip)]
     Comment,
+    /// A block comment without its closing `*/`. Properly closed block
+    /// comments are skipped.
+    #[token("/*", block_comment)]
+    UnterminatedComment,
 
     // Error token
     #[error]
@@ -112,7 +216,7 @@ impl Token {
 
     pub fn get_uint(&self) -> Option<u64> {
         match self {
-            Token::UIntLiteral(i) => Some(*i),
+            Token::UIntLiteral(i) => *i,
             Token::CharLiteral(c) => Some(*c as u64),
             _ => None,
         }
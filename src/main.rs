@@ -1,7 +1,10 @@
-use clap::Clap;
+use clap::{AppSettings, ArgSettings, Clap};
 use logos::{Lexer, Logos};
-use natrium::util::pretty_print_error;
-use r0syntax::{ast::Program, span::Span, token::Token};
+use natrium::util::{diagnostic_to_json, print_diagnostic};
+use r0codegen::lint::Lint;
+use r0syntax::{
+    ast::Program, diagnostic::Diagnostic, source_map::SourceMap, span::Span, token::Token,
+};
 use r0vm::s0::io::WriteBinary;
 use std::{
     io::{Read, Write},
@@ -11,22 +14,37 @@ use std::{
 
 fn main() {
     let opt = Opt::parse();
-    let input = std::fs::read_to_string(&opt.input).expect("Unable to read input file");
+    if let Some(Command::Fmt(fmt_opt)) = &opt.command {
+        format_files(fmt_opt);
+    }
+
+    let input_file = opt.input.as_ref().expect("Input file is required");
+    let input = std::fs::read_to_string(input_file).expect("Unable to read input file");
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file(input_file.display().to_string(), input.as_str());
 
     let output_file = get_output(&opt);
     let mut output = build_output(output_file, opt.interpret);
 
-    let lexer = r0syntax::lexer::spanned_lexer(&input);
+    let lexer = r0syntax::lexer::spanned_lexer_in(&input, file);
     if !opt.interpret && opt.emit == EmitTarget::Token {
         dump_lex(lexer, output);
     }
 
-    let program = parser(lexer, &input);
+    let program = parser(lexer, &source_map, opt.error_format);
     if !opt.interpret && opt.emit == EmitTarget::Ast {
         dump_ast(program, output);
     }
+    if !opt.interpret && opt.emit == EmitTarget::Hir {
+        dump_hir(&program, &source_map, opt.error_format, output);
+    }
 
-    let s0 = compile_s0(&program, &input);
+    let options = r0codegen::generator::CompileOptions {
+        bounds_check: !opt.no_bounds_check,
+        allowed_lints: allowed_lints(&opt),
+        opt_level: opt.opt_level.min(r0codegen::opt::MAX_OPT_LEVEL),
+    };
+    let s0 = compile_s0(&program, &source_map, &options, opt.error_format);
     if !opt.interpret {
         if opt.emit == EmitTarget::O0 {
             s0.write_binary(&mut output)
@@ -58,7 +76,8 @@ fn get_output(opt: &Opt) -> Option<PathBuf> {
         None => {
             let filename = opt
                 .input
-                .file_stem()
+                .as_ref()
+                .and_then(|x| x.file_stem())
                 .and_then(|x| x.to_str())
                 .unwrap_or("a");
             let ext = match opt.emit {
@@ -66,6 +85,7 @@ fn get_output(opt: &Opt) -> Option<PathBuf> {
                 EmitTarget::Text => "s0",
                 EmitTarget::Token => "tokenstream",
                 EmitTarget::Ast => "ast",
+                EmitTarget::Hir => "hir",
             };
             let out_file = format!("{}.{}", filename, ext);
             Some(out_file.into())
@@ -95,51 +115,141 @@ where
     std::process::exit(0);
 }
 
+/// Format files in place, or only check if they are formatted
+fn format_files(opt: &FmtOpt) -> ! {
+    let mut unformatted = false;
+    for path in &opt.files {
+        let input = std::fs::read_to_string(path).expect("Unable to read input file");
+        let mut source_map = SourceMap::new();
+        let file = source_map.add_file(path.display().to_string(), input.as_str());
+        let lexer = r0syntax::lexer::spanned_lexer_in(&input, file);
+        let program = parser(lexer, &source_map, ErrorFormat::Human);
+        let formatted = r0syntax::format::format_program(&program, &input);
+        if formatted == input {
+            continue;
+        }
+
+        if opt.check {
+            println!("{} is not formatted", path.display());
+            unformatted = true;
+        } else {
+            std::fs::write(path, formatted).expect("Failed to write to output");
+        }
+    }
+    std::process::exit(if unformatted { 1 } else { 0 });
+}
+
 fn dump_ast(ast: Program, mut output: Box<dyn Write>) -> ! {
     writeln!(output, "{:?}", ast).expect("Failed to write to output");
     std::process::exit(0);
 }
 
-fn parser<T>(lexer: T, input: &str) -> Program
+/// Write the typed intermediate representation of `program`, or its errors
+fn dump_hir(
+    program: &Program,
+    source_map: &SourceMap,
+    error_format: ErrorFormat,
+    mut output: Box<dyn Write>,
+) -> ! {
+    match r0codegen::hir::lower(program) {
+        Ok(hir) => {
+            write!(output, "{}", hir).expect("Failed to write to output");
+            std::process::exit(0);
+        }
+        Err(errors) => {
+            for e in errors {
+                report(
+                    &mut std::io::stdout(),
+                    &e.to_diagnostic(),
+                    source_map,
+                    error_format,
+                );
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Lints turned off by `-A`, unless turned back on by `-W`
+fn allowed_lints(opt: &Opt) -> Vec<Lint> {
+    let expand = |names: &[String]| {
+        let mut lints = vec![];
+        for name in names {
+            if name == "warnings" {
+                lints.extend_from_slice(&Lint::ALL);
+            } else {
+                match Lint::from_str(name) {
+                    Ok(lint) => lints.push(lint),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        lints
+    };
+    let warned = expand(&opt.warn);
+    let mut allowed = expand(&opt.allow);
+    allowed.retain(|lint| !warned.contains(lint));
+    allowed
+}
+
+/// Report a diagnostic to `w` in the requested format
+fn report(w: &mut dyn Write, diag: &Diagnostic, source_map: &SourceMap, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => print_diagnostic(w, source_map, diag).unwrap(),
+        ErrorFormat::Json => writeln!(w, "{}", diagnostic_to_json(source_map, diag)).unwrap(),
+    }
+}
+
+fn parser<T>(lexer: T, source_map: &SourceMap, error_format: ErrorFormat) -> Program
 where
     T: Iterator<Item = (Token, Span)>,
 {
     let mut p = r0syntax::parser::Parser::new(lexer);
-    let r = p.parse();
+    let (program, errors) = p.parse();
 
-    match r {
-        Ok(p) => p,
-        Err(e) => {
-            if let Some(span) = e.span {
-                pretty_print_error(
-                    &mut std::io::stdout(),
-                    &input,
-                    &format!("{:?}", e.kind),
-                    span,
-                )
-                .unwrap();
-            } else {
-                println!("{:?}", e.kind);
-            }
-            std::process::exit(1);
-        }
+    if errors.is_empty() {
+        return program;
+    }
+    for e in errors {
+        report(
+            &mut std::io::stdout(),
+            &e.to_diagnostic(),
+            source_map,
+            error_format,
+        );
     }
+    std::process::exit(1);
 }
 
-fn compile_s0(program: &Program, input: &str) -> r0vm::s0::S0 {
-    match r0codegen::generator::compile(program) {
+fn compile_s0(
+    program: &Program,
+    source_map: &SourceMap,
+    options: &r0codegen::generator::CompileOptions,
+    error_format: ErrorFormat,
+) -> r0vm::s0::S0 {
+    let (res, warnings) = r0codegen::generator::compile_with_warnings(program, options);
+    // warnings go to stderr so they don't mix with code written to stdout
+    for w in warnings {
+        report(
+            &mut std::io::stderr(),
+            &w.to_diagnostic(),
+            source_map,
+            error_format,
+        );
+    }
+    match res {
         Ok(p) => p,
-        Err(e) => {
-            if let Some(span) = e.span {
-                pretty_print_error(
+        Err(errors) => {
+            for e in errors {
+                report(
                     &mut std::io::stdout(),
-                    &input,
-                    &format!("{:?}", e.kind),
-                    span,
-                )
-                .unwrap();
-            } else {
-                println!("{:?}", e.kind);
+                    &e.to_diagnostic(),
+                    source_map,
+                    error_format,
+                );
             }
             std::process::exit(1);
         }
@@ -147,27 +257,89 @@ fn compile_s0(program: &Program, input: &str) -> r0vm::s0::S0 {
 }
 
 #[derive(Clap, Debug)]
+#[clap(setting = AppSettings::SubcommandsNegateReqs)]
 struct Opt {
     /// Input file
-    pub input: PathBuf,
+    #[clap(setting = ArgSettings::Required)]
+    pub input: Option<PathBuf>,
 
     /// Emit target
     ///
     /// O0: binary object code;
     /// Text: text format code;
     /// Token: token stream;
-    /// Ast: abstract syntax tree
+    /// Ast: abstract syntax tree;
+    /// Hir: typed intermediate representation
     #[clap(long, default_value = "o0")]
     pub emit: EmitTarget,
 
-    /// Output file. Defaults to `<input_file_name>.o0|s0|tt|ast`
+    /// Output file. Defaults to `<input_file_name>.o0|s0|tt|ast|hir`
     #[clap(long, short)]
     pub output: Option<String>,
 
+    /// Format of error messages
+    ///
+    /// Human: readable messages with source snippets;
+    /// Json: one JSON object per line
+    #[clap(long, default_value = "human")]
+    pub error_format: ErrorFormat,
+
+    /// Don't check array indices at runtime
+    #[clap(long)]
+    pub no_bounds_check: bool,
+
+    /// Optimisation level
+    ///
+    /// 0: none;
+    /// 1: constant folding, peephole rewrites and self tail calls;
+    /// 2: also jump threading and dead block removal;
+    /// 3: also SSA value numbering, loop-invariant code motion and slot
+    /// allocation
+    #[clap(short = 'O', long, default_value = "0")]
+    pub opt_level: u8,
+
+    /// Turn on a warning, overriding `-A`; `warnings` means all of them
+    #[clap(
+        short = 'W',
+        long = "warn",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub warn: Vec<String>,
+
+    /// Turn off a warning; `warnings` means all of them
+    #[clap(
+        short = 'A',
+        long = "allow",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub allow: Vec<String>,
+
     /// Interpret the input file with virtual machine; alias: `--run`
     #[cfg(feature = "vm")]
     #[clap(short = 'i', long, alias = "run")]
     pub interpret: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    /// Format source files into canonical form
+    Fmt(FmtOpt),
+}
+
+#[derive(Clap, Debug)]
+struct FmtOpt {
+    /// Files to format in place
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Don't write anything; exit with 1 if any file is not formatted
+    #[clap(long)]
+    pub check: bool,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -176,6 +348,7 @@ enum EmitTarget {
     Text,
     Token,
     Ast,
+    Hir,
 }
 
 impl FromStr for EmitTarget {
@@ -187,7 +360,31 @@ impl FromStr for EmitTarget {
             "text" | "s0" => EmitTarget::Text,
             "token" | "lex" => EmitTarget::Token,
             "ast" | "parse" => EmitTarget::Ast,
-            _ => return Err(format!("Expected one of: o0, text, token, ast; got: {}", s)),
+            "hir" => EmitTarget::Hir,
+            _ => {
+                return Err(format!(
+                    "Expected one of: o0, text, token, ast, hir; got: {}",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "human" => ErrorFormat::Human,
+            "json" => ErrorFormat::Json,
+            _ => return Err(format!("Expected one of: human, json; got: {}", s)),
         })
     }
 }
@@ -1,4 +1,54 @@
 use r0vm::s0::{io::WriteBinary, S0};
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+/// An output stream that can be inspected after being handed to the VM
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compile `input` and run it to the end with `stdin`, returning its output.
+/// The output must be the same at every optimisation level.
+fn compile_and_run(input: &str, stdin: &'static str) -> String {
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    let outputs = (0..=r0codegen::opt::MAX_OPT_LEVEL)
+        .map(|opt_level| {
+            let options = r0codegen::generator::CompileOptions {
+                opt_level,
+                ..Default::default()
+            };
+            let s0 = r0codegen::generator::compile_with_options(&program, &options).unwrap();
+            run(&s0, stdin)
+        })
+        .collect::<Vec<_>>();
+    for output in &outputs[1..] {
+        assert_eq!(output, &outputs[0]);
+    }
+    outputs[0].clone()
+}
+
+/// Run `s0` to the end with `stdin`, returning its output
+fn run(s0: &S0, stdin: &'static str) -> String {
+    let output = SharedOutput::default();
+    let mut vm =
+        r0vm::vm::R0Vm::new(s0, Box::new(stdin.as_bytes()), Box::new(output.clone())).unwrap();
+    vm.run_to_end().unwrap();
+    drop(vm);
+
+    let output = output.0.borrow();
+    String::from_utf8_lossy(&output).into_owned()
+}
 
 #[test]
 fn test_ser() {
@@ -37,7 +87,8 @@ fn main() -> void {
 }
     "#;
     let lexer = r0syntax::lexer::spanned_lexer(&input);
-    let program = r0syntax::parser::Parser::new(lexer).parse().unwrap();
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
     let s0 = r0codegen::generator::compile(&program).unwrap();
 
     let mut bin = vec![];
@@ -45,3 +96,1104 @@ fn main() -> void {
     let s0_re = S0::read_binary(&mut &bin[..]).unwrap().unwrap();
     assert_eq!(s0, s0_re);
 }
+
+#[test]
+fn test_parse_error_recovery() {
+    let input = r#"
+fn foo() -> int {
+    let a: int = 1 +;
+    a = a * 2;
+    return a
+}
+
+fn bar(x int) -> void {
+    putint(x);
+}
+
+fn main() -> void {
+    let b: int = ;
+    putint(foo());
+}
+    "#;
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+
+    assert_eq!(errors.len(), 4, "{:?}", errors);
+    let funcs = program
+        .funcs
+        .iter()
+        .map(|f| f.name.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(funcs, vec!["foo", "main"]);
+    assert_eq!(program.funcs[0].body.stmts.len(), 1);
+    assert_eq!(program.funcs[1].body.stmts.len(), 1);
+}
+
+#[test]
+fn test_logical_operators() {
+    let input = r#"
+fn side_effect(x: int) -> int {
+    putint(x);
+    return x;
+}
+
+fn main() -> void {
+    let a: int = 3;
+    if a > 0 && a < 5 || side_effect(1) {
+        putint(1);
+    }
+    if !(a > 0) && side_effect(2) {
+        putint(2);
+    }
+    if side_effect(0) || !a {
+        putint(3);
+    }
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "10\r\n");
+}
+
+#[test]
+fn test_bitwise_and_remainder_operators() {
+    let input = r#"
+fn main() -> void {
+    let a: int = 17;
+    putint(a % 5);
+    putchar(32);
+    putint(-7 % 3);
+    putchar(32);
+    putint(1 << 4 | 3);
+    putchar(32);
+    putint(6 & 3 ^ 1);
+    putchar(32);
+    putint(~5);
+    putchar(32);
+    putint(-16 >> 2);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "2 -1 19 3 -6 -4\r\n");
+
+    // both remainders share the same two temporaries, next to `a`
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    assert_eq!(s0.functions[1].loc_slots, 3, "{}", s0.functions[1]);
+}
+
+#[test]
+fn test_arrays() {
+    let input = r#"
+let fib: [int; 10];
+
+fn main() -> void {
+    let grid: [[int; 3]; 2];
+    let i: int = 2;
+    fib[0] = 1;
+    fib[1] = 1;
+    while i < 10 {
+        fib[i] = fib[i - 1] + fib[i - 2];
+        i = i + 1;
+    }
+    grid[1][2] = fib[9];
+    grid[0][0] = 3;
+    putint(grid[1][2] + grid[0][0]);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "58\r\n");
+}
+
+#[test]
+fn test_bool() {
+    let input = r#"
+let seen: bool;
+
+fn less(a: int, b: int) -> bool {
+    return a < b;
+}
+
+fn main() -> void {
+    let ok: bool = less(1, 2);
+    let flags: [bool; 2];
+    flags[1] = true;
+    seen = !false;
+    if ok && seen && flags[1] {
+        putint(1);
+    }
+    putint((1 != 3) as int);
+    putint((7 as bool) as int);
+    putint((0.0 as bool) as int);
+    putln();
+    let five = getint();
+    putint(((five as bool) & (2 as bool)) as int);
+    putint(((five as bool) == (2 as bool)) as int);
+    putint(((five as bool) != (0 as bool)) as int);
+    putint(((5 as bool) & (2 as bool)) as int);
+    putint(((5 as bool) == (2 as bool)) as int);
+}
+    "#;
+    assert_eq!(compile_and_run(input, "5"), "1110\r\n11111");
+}
+
+#[test]
+fn test_bool_bitwise_operators() {
+    // `!=` leaves -1 or 1 rather than 1 for true
+    let input = r#"
+fn main() -> void {
+    let x = getint();
+    let y = getint();
+    putint(((x != 1) ^ (y != 3)) as int);
+    putint(((x != 1) & (y != 5)) as int);
+    putint(((x != 0) | (y != 4)) as int);
+    let b = x != 1;
+    b = b ^ (y != 3);
+    putint(b as int);
+    b = (b | (y != 5)) & (x != 1);
+    putint(b as int);
+}
+    "#;
+    assert_eq!(compile_and_run(input, "0 4"), "01001");
+}
+
+#[test]
+fn test_inferred_decl() {
+    let input = r#"
+fn half(x: int) -> double {
+    return x as double / 2.0;
+}
+
+let scale = 2;
+const quarter = 1.0 / 4.0;
+let big_scale = scale > 1;
+
+fn main() -> void {
+    let sum = 0;
+    const h = half(3);
+    let big = h > 1.0;
+    for (let i = 1; i <= 4; i = i + 1) {
+        sum = sum + i;
+    }
+    if big && big_scale {
+        putint(sum * scale);
+    }
+    putdouble(quarter);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "200.250000\r\n");
+
+    // the initializer of a global is lowered once, even if it fails
+    let input = "let g;
+let k = later();
+fn later() -> int {
+    return 1;
+}
+fn main() -> void {
+    let x;
+    let y = putln();
+    let z = 0;
+    z = 1.0;
+    let f = putint;
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let errors = r0codegen::generator::compile(&program).unwrap_err();
+    let codes = errors.iter().map(|e| e.kind.code()).collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        vec!["E0116", "E0101", "E0116", "E0103", "E0104", "E0104"]
+    );
+}
+
+#[test]
+fn test_compound_assignment() {
+    let input = r#"
+let calls: int;
+
+fn index() -> int {
+    calls += 1;
+    return 1;
+}
+
+fn main() -> void {
+    let a: [int; 2];
+    let d: double = 3.0;
+    let n: int = 17;
+    a[1] = 5;
+    a[index()] += 10;
+    a[index()] *= 2;
+    d /= 2.0;
+    n %= 5;
+    n %= 3;
+    n -= 1;
+    putint(a[1]);
+    putchar(' ');
+    putint(calls);
+    putchar(' ');
+    putdouble(d);
+    putchar(' ');
+    putint(n);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "30 2 1.500000 1\r\n");
+
+    // both `%=` share one temporary after the locals
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    assert_eq!(s0.functions[2].loc_slots, 5, "{}", s0.functions[2]);
+}
+
+#[test]
+fn test_cond_expr() {
+    let input = r#"
+fn max(a: int, b: int) -> int {
+    return a > b ? a : b;
+}
+
+fn main() -> void {
+    let x: int = 3;
+    let sign: int = x > 0 ? 1 : x < 0 ? -1 : 0;
+    putint(max(4, 9) + (sign == 1 ? 100 : 200));
+    putchar(' ');
+    putdouble(x > 5 ? 1.5 : 2.5);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "109 2.500000\r\n");
+}
+
+#[test]
+fn test_array_bounds_check() {
+    let input = r#"
+fn main() -> void {
+    let a: [int; 4];
+    let i: int = -1;
+    a[i] = 1;
+}
+    "#;
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    let mut vm =
+        r0vm::vm::R0Vm::new(&s0, Box::new(std::io::empty()), Box::new(std::io::sink())).unwrap();
+    assert!(matches!(vm.run_to_end(), Err(r0vm::error::Error::Halt)));
+
+    // no index is in bounds of an empty array
+    let input = "fn main() -> void {
+    let a: [int; 0];
+    let b: [[int; 0]; 2];
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let errors = r0codegen::generator::compile(&program).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .all(|e| matches!(e.kind, r0codegen::err::CompileErrorKind::ZeroLengthArray)));
+}
+
+#[test]
+fn test_structs() {
+    let input = r#"
+struct Point {
+    x: int,
+    y: int,
+}
+
+struct Segment {
+    from: Point,
+    to: Point,
+    tags: [int; 2],
+}
+
+let origin: Point;
+
+fn main() -> void {
+    let s: Segment;
+    origin.x = 1;
+    origin.y = 2;
+    s.from.x = origin.x;
+    s.from.y = origin.y;
+    s.to.x = 10;
+    s.to.y = 20;
+    s.tags[1] = 5;
+    putint((s.to.x - s.from.x) * (s.to.y - s.from.y) + s.tags[1]);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "167\r\n");
+}
+
+#[test]
+fn test_for_and_do_while() {
+    let input = r#"
+fn main() -> void {
+    let sum: int = 0;
+    let n: int = 0;
+    for (let i: int = 0; i < 10; i = i + 1) {
+        if i % 2 == 0 {
+            continue;
+        }
+        sum = sum + i;
+    }
+    putint(sum);
+    putchar(32);
+    do {
+        n = n + 1;
+        if n < 5 {
+            continue;
+        }
+        break;
+    } while 1;
+    putint(n);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "25 5\r\n");
+}
+
+#[test]
+fn test_labeled_loops() {
+    let input = r#"
+fn main() -> void {
+    let found: int = 0;
+    let skipped: int = 0;
+    'outer: for (let i: int = 1; i < 10; i += 1) {
+        for (let j: int = 1; j < 10; j += 1) {
+            if j > i {
+                skipped += 1;
+                continue 'outer;
+            }
+            if i * j == 12 {
+                found = i * 100 + j;
+                break 'outer;
+            }
+        }
+    }
+    putint(found);
+    putchar(' ');
+    putint(skipped);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "403 3\r\n");
+
+    let input = "fn main() -> void {
+    'a: while 1 {
+        break 'b;
+    }
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let errors = r0codegen::generator::compile(&program).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(
+        matches!(&errors[0].kind, r0codegen::err::CompileErrorKind::UnknownLabel(name) if name == "b")
+    );
+}
+
+#[test]
+fn test_switch() {
+    let input = r#"
+fn classify(x: int) -> int {
+    switch x {
+        case -1:
+            return 10;
+        case 'a':
+        case 'b':
+            return 20;
+        default:
+            return 30;
+    }
+    return 0;
+}
+
+// every path returns, since the first case falls through into the default
+fn pick(x: int) -> int {
+    switch x {
+        case 0: x = 5;
+        default: return x;
+    }
+}
+
+fn main() -> void {
+    putint(pick(0));
+    putint(classify(-1));
+    putint(classify(97));
+    putint(classify(98));
+    putint(classify(5));
+    putchar(' ');
+
+    let sum = 0;
+    for (let i = 0; i < 12; i += 1) {
+        switch i {
+            case 0: sum += 1; break;
+            case 1: sum += 2; break;
+            case 2: sum += 4; break;
+            case 3: sum += 8; break;
+            case 4: sum += 16; break;
+            case 5:
+                continue;
+            case 6: sum += 64; break;
+            case 7:
+                break;
+            case 9: sum += 512; break;
+            case 10: sum += 1024; break;
+            default: sum += 10000;
+        }
+        sum += 100000;
+    }
+    putint(sum);
+    putchar(' ');
+
+    // cases fall through until a `break`
+    for (let i = 0; i < 4; i += 1) {
+        switch (i) {
+            case 0: putint(0);
+            case 1: putint(1);
+            case 2:
+                putint(2);
+                break;
+            default: putint(9);
+        }
+    }
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "510202030 1121631 0121229\r\n");
+
+    // the second switch reuses the slot of the first one's scrutinee
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    assert_eq!(s0.functions[3].loc_slots, 4, "{}", s0.functions[3]);
+
+    let input = "fn main() -> void {
+    switch 1 {
+        case 1:
+        case 0x1:
+            putint(1);
+    }
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (_, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert_eq!(errors.len(), 1);
+    assert!(
+        matches!(&errors[0].kind, r0syntax::parser::err::ParseErrorKind::DuplicateCase { case, .. } if case == "1"),
+        "{:?}",
+        errors
+    );
+}
+
+#[test]
+fn test_optimisation() {
+    use r0vm::opcodes::Op;
+
+    let input = r#"
+fn main() -> void {
+    let x = 2;
+    x = x;
+    if 2 * 3 > 5 {
+        putint(x + ((1 << 4) - 6));
+    } else {
+        putint(4);
+    }
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "12");
+
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let options = r0codegen::generator::CompileOptions {
+        opt_level: 2,
+        ..Default::default()
+    };
+    let s0 = r0codegen::generator::compile_with_options(&program, &options).unwrap();
+    let main = &s0.functions[1].ins;
+    assert!(main.contains(&Op::Push(10)), "{:?}", main);
+    assert!(!main.contains(&Op::Push(4)), "{:?}", main);
+    assert!(
+        !main
+            .iter()
+            .any(|op| matches!(op, Op::BrTrue(_) | Op::Br(_) | Op::StackAlloc(0))),
+        "{:?}",
+        main
+    );
+    // `x = x` is gone
+    assert_eq!(main.iter().filter(|op| **op == Op::Store64).count(), 1);
+}
+
+#[test]
+fn test_block_layout() {
+    use r0vm::opcodes::Op;
+
+    let input = r#"
+let start: int = 1 > 0 ? 3 : 4;
+
+fn main() -> void {
+    let i = start;
+    while i < 10 {
+        i += 1;
+    }
+    putint(i);
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "10");
+
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    let main = &s0.functions[1].ins;
+    // one jump into the loop, and one back at its end
+    let branches = main
+        .iter()
+        .filter(|op| matches!(op, Op::Br(_) | Op::BrTrue(_) | Op::BrFalse(_)))
+        .collect::<Vec<_>>();
+    assert!(
+        matches!(branches[..], [Op::Br(_), Op::BrTrue(off)] if *off < 0),
+        "{:?}",
+        main
+    );
+}
+
+#[test]
+fn test_comments() {
+    let input = "/* leading /* nested */ comment */
+fn main() -> void {
+    putint(1 /* inline */ + 2); // trailing
+    putln();
+}
+// no newline at the end";
+    assert_eq!(compile_and_run(input, ""), "3\r\n");
+
+    let input = "fn main() -> void {\n    putln();\n}\n/* never /* closed */\n";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (_, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(matches!(
+        errors[0].kind,
+        r0syntax::parser::err::ParseErrorKind::UnterminatedComment
+    ));
+    assert_eq!(errors[0].span.unwrap().idx, input.find("/*").unwrap());
+}
+
+#[test]
+fn test_integer_literals() {
+    let input = r#"
+fn main() -> void {
+    putint(0xff + 0b1010 + 0o17 + 1_000);
+    putchar(32);
+    putint(-9223372036854775808 + 0x7fff_ffff_ffff_ffff);
+    putln();
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "1280 -1\r\n");
+
+    let input = "fn main() -> void { putint(18446744073709551616); }";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (_, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(
+        matches!(
+            errors[..],
+            [r0syntax::parser::err::ParseError {
+                kind: r0syntax::parser::err::ParseErrorKind::IntegerOutOfRange,
+                ..
+            }]
+        ),
+        "{:?}",
+        errors
+    );
+
+    let input = "fn main() -> void { putint(9223372036854775808); }";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty());
+    let errors = r0codegen::generator::compile(&program).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0].kind,
+        r0codegen::err::CompileErrorKind::IntegerOutOfRange
+    ));
+}
+
+#[test]
+fn test_format() {
+    let input = "// counter
+let n: int=0x10;
+fn main()->void{
+  let i:int=0; // index
+
+
+  while i<n {i=i+(1<<2)*-(2-1);}
+  /* done */
+}";
+    let expected = "// counter
+let n: int = 0x10;
+
+fn main() -> void {
+    let i: int = 0; // index
+
+    while i < n {
+        i = i + (1 << 2) * -(2 - 1);
+    }
+    /* done */
+}
+";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let formatted = r0syntax::format::format_program(&program, input);
+    assert_eq!(formatted, expected);
+
+    let lexer = r0syntax::lexer::spanned_lexer(&formatted);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(
+        r0syntax::format::format_program(&program, &formatted),
+        formatted
+    );
+}
+
+#[test]
+fn test_visitor() {
+    use r0syntax::ast::*;
+
+    struct CallCollector<'a>(Vec<&'a str>);
+    impl<'ast> Visitor<'ast> for CallCollector<'ast> {
+        fn visit_call(&mut self, expr: &'ast CallExpr) {
+            self.0.push(&expr.func.name);
+            walk_call(self, expr)
+        }
+    }
+
+    struct RenameCalls;
+    impl VisitorMut for RenameCalls {
+        fn visit_call_mut(&mut self, expr: &mut CallExpr) {
+            if expr.func.name == "one" {
+                expr.func.name = "two".into();
+            }
+            walk_call_mut(self, expr)
+        }
+    }
+
+    let input = "fn one() -> int { return 1; }
+fn two() -> int { return 2; }
+fn main() -> void {
+    let x: int = one();
+    if x > 0 { putint(x + one() * getint()); }
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (mut program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    let mut collector = CallCollector(vec![]);
+    collector.visit_program(&program);
+    assert_eq!(collector.0, vec!["one", "putint", "one", "getint"]);
+
+    RenameCalls.visit_program_mut(&mut program);
+    let s0 = r0codegen::generator::compile(&program).unwrap();
+    let output = SharedOutput::default();
+    let mut vm = r0vm::vm::R0Vm::new(&s0, Box::new(&b"3"[..]), Box::new(output.clone())).unwrap();
+    vm.run_to_end().unwrap();
+    drop(vm);
+    assert_eq!(String::from_utf8_lossy(&output.0.borrow()), "8");
+}
+
+#[test]
+fn test_source_map() {
+    use r0syntax::{source_map::SourceMap, span::Span};
+
+    let mut source_map = SourceMap::new();
+    let a = source_map.add_file("a.c0", "let x: int = 1;\r\n");
+    let b = source_map.add_file("b.c0", "fn main() -> void {\n    x = y;\n}\n");
+
+    let y = r0syntax::lexer::spanned_lexer_in(&source_map.file(b).src, b)
+        .find(|(t, _)| t.get_ident() == Some("y"))
+        .unwrap()
+        .1;
+    assert_eq!(y, Span::new(28, 1).in_file(b));
+    assert_eq!(source_map.location(y).to_string(), "b.c0:2:9");
+    assert_eq!(
+        source_map.location(Span::new(4, 1).in_file(a)).to_string(),
+        "a.c0:1:5"
+    );
+    assert_eq!(source_map.file(a).line(0), "let x: int = 1;");
+
+    let mut output = vec![];
+    crate::util::pretty_print_error(&mut output, &source_map, "NoSuchSymbol", y).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "NoSuchSymbol
+  --> b.c0:2:9
+fn main() -> void {
+    x = y;
+        ^
+}
+"
+    );
+}
+
+#[test]
+fn test_diagnostics() {
+    use r0syntax::source_map::SourceMap;
+
+    let input = "fn main() -> void {
+    let x: int = 1.0;
+}";
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file("main.c0", input);
+    let lexer = r0syntax::lexer::spanned_lexer_in(input, file);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let diag = r0codegen::generator::compile(&program).unwrap_err()[0].to_diagnostic();
+    assert_eq!(diag.code, "E0104");
+    assert_eq!(
+        diag.message,
+        "mismatched types: expected `int`, found `double`"
+    );
+
+    let json = crate::util::diagnostic_to_json(&source_map, &diag);
+    assert_eq!(json["code"], "E0104");
+    assert_eq!(json["level"], "error");
+    assert_eq!(json["span"]["file"], "main.c0");
+    assert_eq!(json["span"]["line"], 2);
+    assert_eq!(json["span"]["column"], 18);
+    assert_eq!(json["span"]["end_column"], 21);
+    assert_eq!(json["notes"].as_array().unwrap().len(), 1);
+
+    assert!(json["labels"].as_array().unwrap().is_empty());
+
+    let lexer = r0syntax::lexer::spanned_lexer("fn main() -> void { let x: int }");
+    let (_, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    let diag = errors[0].to_diagnostic();
+    assert_eq!(diag.code, "E0002");
+    assert_eq!(diag.message, "expected `;`");
+
+    // errors about something defined earlier point at that definition too
+    let input = "const limit: int = 1;
+fn main() -> void {
+    let x: int = 0;
+    let x: int = 1;
+    limit = 2;
+    switch x {
+        case 1:
+        case 1:
+    }
+}";
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file("main.c0", input);
+    let lexer = r0syntax::lexer::spanned_lexer_in(input, file);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    let diags = errors
+        .iter()
+        .map(|e| e.to_diagnostic())
+        .chain(
+            r0codegen::generator::compile(&program)
+                .unwrap_err()
+                .iter()
+                .map(|e| e.to_diagnostic()),
+        )
+        .map(|diag| crate::util::diagnostic_to_json(&source_map, &diag))
+        .collect::<Vec<_>>();
+    let labels = diags
+        .iter()
+        .map(|json| {
+            let label = &json["labels"][0];
+            (
+                json["code"].as_str().unwrap(),
+                label["span"]["line"].as_u64().unwrap(),
+                label["span"]["column"].as_u64().unwrap(),
+                label["message"].as_str().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        vec![
+            ("E0006", 7, 14, "first used here"),
+            ("E0102", 3, 9, "`x` is first defined here"),
+            ("E0112", 1, 7, "the constant is declared here"),
+        ]
+    );
+}
+
+#[test]
+fn test_multiple_compile_errors() {
+    use r0codegen::err::CompileErrorKind;
+
+    let input = "const limit: int = 10;
+fn f(x: int) -> int {
+    let y: int = x + z;
+    limit = y;
+    return y * 2.0;
+}
+fn main() -> void {
+    let a: int = undefined_fn(1);
+    let b: double = a;
+    putint(f(b) + missing);
+    if nothing { putint(1.5); }
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let errors = r0codegen::generator::compile(&program).unwrap_err();
+    let kinds = errors
+        .iter()
+        .map(|e| match &e.kind {
+            CompileErrorKind::NoSuchSymbol(name) => format!("NoSuchSymbol({})", name),
+            CompileErrorKind::TypeMismatch { .. } => "TypeMismatch".into(),
+            CompileErrorKind::AssignToConst { .. } => "AssignToConst".into(),
+            kind => format!("{:?}", kind),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![
+            "NoSuchSymbol(z)",
+            "AssignToConst",
+            "TypeMismatch",
+            "NoSuchSymbol(undefined_fn)",
+            "TypeMismatch",
+            "TypeMismatch",
+            "NoSuchSymbol(missing)",
+            "NoSuchSymbol(nothing)",
+            "TypeMismatch",
+        ]
+    );
+}
+
+#[test]
+fn test_warnings() {
+    use r0codegen::{generator::CompileOptions, lint::Lint};
+
+    let input = "struct Pair { a: int, b: int }
+fn helper(a: int) -> int {
+    let unused: int = 3;
+    return 1;
+    putint(2);
+}
+fn main() -> void {
+    let x: int = 1;
+    let _y: int = 2;
+    let written: [int; 2];
+    let pair: Pair;
+    let counts: [int; 2];
+    x = 2;
+    putint(x);
+    while x > 0 { x = x - 1; }
+    written[x] = 1;
+    pair.a = 2;
+    counts[0] = 0;
+    counts[0] += 1;
+}";
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    let warnings_of = |options: &CompileOptions| {
+        let (res, warnings) = r0codegen::generator::compile_with_warnings(&program, options);
+        assert!(res.is_ok());
+        warnings
+            .iter()
+            .map(|w| format!("{}: {}", w.lint.code(), w.message))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        warnings_of(&CompileOptions::default()),
+        vec![
+            "W0003: unreachable statement",
+            "W0001: `a` is never read",
+            "W0001: `unused` is never read",
+            "W0004: value assigned to `x` is never read",
+            "W0001: `written` is never read",
+            "W0001: `pair` is never read",
+            "W0002: function `helper` is never called from `main`",
+        ]
+    );
+
+    let options = CompileOptions {
+        allowed_lints: vec![Lint::UnusedVariables, Lint::UnusedFunctions],
+        ..CompileOptions::default()
+    };
+    assert_eq!(
+        warnings_of(&options),
+        vec![
+            "W0003: unreachable statement",
+            "W0004: value assigned to `x` is never read",
+        ]
+    );
+}
+
+#[test]
+fn test_hir() {
+    use r0codegen::{err::CompileErrorKind, hir};
+
+    let lower = |input: &str| {
+        let lexer = r0syntax::lexer::spanned_lexer(input);
+        let (program, errors) = r0syntax::parser::Parser::new(lexer).parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+        hir::lower(&program)
+    };
+
+    let program = lower(
+        "fn sign(x: int) -> int {
+    if x < 0 { return -1; } else { return 1; }
+}
+fn main() -> void {
+    let a: [int; 2];
+    let d = 1.5;
+    a[0] = sign(2);
+    putdouble(a[0] as double + d);
+}",
+    )
+    .unwrap();
+
+    let main = &program.funcs[1];
+    let assign = match &main.body.stmts[2].kind {
+        hir::StmtKind::Expr(expr) => expr,
+        stmt => panic!("expected an expression, got {:?}", stmt),
+    };
+    let (place, rhs) = match &assign.kind {
+        hir::ExprKind::Assign { place, rhs, .. } => (place, rhs),
+        expr => panic!("expected an assignment, got {:?}", expr),
+    };
+    assert_eq!(place.ty, r0codegen::ty::Ty::Int);
+    assert_eq!(rhs.ty, r0codegen::ty::Ty::Int);
+    // the place refers to the declared variable
+    let a_id = match &main.body.stmts[0].kind {
+        hir::StmtKind::Decl { var, .. } => var.id,
+        stmt => panic!("expected a declaration, got {:?}", stmt),
+    };
+    match &place.kind {
+        hir::PlaceKind::Index { base, .. } => assert_eq!(base.as_var().unwrap().id, a_id),
+        place => panic!("expected an index, got {:?}", place),
+    }
+
+    let text = program.to_string();
+    assert!(text.contains("let d#"), "{}", text);
+    assert!(text.contains("(a#"), "{}", text);
+    assert!(text.contains(" as double) + d#"), "{}", text);
+
+    // missing returns are found without generating code
+    let errors = lower(
+        "fn f(x: int) -> int {
+    while x > 0 { return x; }
+}
+fn main() -> void {}",
+    )
+    .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0].kind,
+        CompileErrorKind::NotAllRoutesReturn
+    ));
+}
+
+#[test]
+fn test_ssa() {
+    use r0vm::opcodes::Op;
+
+    let input = r#"
+fn digits(n: int, base: int) -> int {
+    if n < base {
+        return 1;
+    }
+    {
+        let q = n / base;
+        let r = n % base;
+        putint(r);
+    }
+    let k = 3 * base;
+    while k > 0 {
+        let t = 3 * base;
+        k = k - t;
+    }
+    return digits(n / base, base) + 1;
+}
+
+fn main() -> void {
+    putint(digits(1234, 10));
+}
+    "#;
+    assert_eq!(compile_and_run(input, ""), "4324");
+
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let compile = |opt_level| {
+        let options = r0codegen::generator::CompileOptions {
+            opt_level,
+            ..Default::default()
+        };
+        r0codegen::generator::compile_with_options(&program, &options).unwrap()
+    };
+    let (o2, o3) = (compile(2), compile(3));
+    // locals live in the parameters' slots once those are dead, so each
+    // recursive call needs less of the stack
+    assert!(
+        o3.functions[1].loc_slots < o2.functions[1].loc_slots,
+        "{}\n{}",
+        o2.functions[1],
+        o3.functions[1]
+    );
+    // `3 * base` is computed once, before the loop
+    let digits = &o3.functions[1].ins;
+    assert_eq!(
+        digits.iter().filter(|op| **op == Op::MulI).count(),
+        2,
+        "{:?}",
+        digits
+    );
+}
+
+#[test]
+fn test_tail_calls() {
+    use r0vm::opcodes::Op;
+
+    let input = r#"
+fn sum(n: int, acc: int) -> int {
+    if n == 0 {
+        return acc;
+    }
+    return sum(n - 1, acc + n);
+}
+
+fn swap_count(a: int, b: int, n: int) -> int {
+    if n == 0 {
+        return a * 10 + b;
+    }
+    return swap_count(b, a, n - 1);
+}
+
+fn main() -> void {
+    putint(sum(getint(), 0));
+    putchar(32);
+    putint(swap_count(1, 2, 3));
+}
+    "#;
+    assert_eq!(compile_and_run(input, "100"), "5050 21");
+
+    let lexer = r0syntax::lexer::spanned_lexer(input);
+    let (program, _) = r0syntax::parser::Parser::new(lexer).parse();
+    let options = r0codegen::generator::CompileOptions {
+        opt_level: 1,
+        ..Default::default()
+    };
+    let s0 = r0codegen::generator::compile_with_options(&program, &options).unwrap();
+    for func in &s0.functions[1..3] {
+        assert!(
+            !func.ins.iter().any(|op| matches!(op, Op::Call(_))),
+            "{}",
+            func
+        );
+    }
+    // deeper than the stack could hold a frame for every call
+    assert_eq!(run(&s0, "1000000"), "500000500000 21");
+}
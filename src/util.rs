@@ -1,67 +1,110 @@
-use r0syntax::span::Span;
+use r0syntax::{diagnostic::Diagnostic, source_map::SourceMap, span::Span};
+use serde_json::{json, Value};
 use std::io::Write;
 use unicode_width::UnicodeWidthStr;
 
 pub fn pretty_print_error(
     writer: &mut dyn Write,
-    input: &str,
+    source_map: &SourceMap,
     error: &str,
     span: Span,
 ) -> Result<(), std::io::Error> {
     writeln!(writer, "{}", error)?;
+    writeln!(writer, "  --> {}", source_map.location(span))?;
 
-    if span == Span::eof() {
+    let file = source_map.file(span.file);
+    let input = file.src.as_str();
+
+    if span.is_eof() {
         let line = input.lines().last().unwrap_or("");
         writeln!(writer, "{}", line)?;
-        writeln!(writer, "{:space_width$}^", space_width = line.width())?;
+        writeln!(writer, "{:space_width$}^", "", space_width = line.width())?;
 
-        Ok(())
-    } else {
-        let start = line_span::find_line_range(input, span.start());
-        let end = line_span::find_line_range(input, span.end());
+        return Ok(());
+    }
+
+    let start_line = file.line_index(span.start());
+    let end_line = file.line_index(span.end());
 
-        if let Some(line) = line_span::find_prev_line_range(input, span.start()) {
-            writeln!(writer, "{}", &input[line])?;
-        }
-        if start == end {
-            writeln!(writer, "{}", &input[start.clone()])?;
-            writeln!(
-                writer,
-                "{:space_width$}{:^^line_width$}",
-                "",
-                "",
-                space_width = input[start.start..span.start()].width(),
-                line_width = input[span.start()..span.end()].width()
-            )?;
-        } else {
-            let print_range = start.start..end.end;
-            let input_range = input[print_range].lines().collect::<Vec<_>>();
+    if start_line > 0 {
+        writeln!(writer, "{}", file.line(start_line - 1))?;
+    }
+    for line in start_line..=end_line {
+        let range = file.line_range(line);
+        let lo = span.start().clamp(range.start, range.end);
+        let hi = span.end().clamp(range.start, range.end);
+        writeln!(writer, "{}", &input[range.clone()])?;
+        writeln!(
+            writer,
+            "{:space_width$}{:^^line_width$}",
+            "",
+            "",
+            space_width = input[range.start..lo].width(),
+            line_width = input[lo..hi].width()
+        )?;
+    }
+    if end_line + 1 < file.line_count() {
+        writeln!(writer, "{}", file.line(end_line + 1))?;
+    }
+    Ok(())
+}
 
-            writeln!(writer, "{}", input_range[0])?;
-            writeln!(
-                writer,
-                "{:space_width$}{:^^line_width$}",
-                "",
-                "",
-                space_width = input[start.start..span.start()].width(),
-                line_width = input[span.start()..start.end].width()
-            )?;
-            for i in 1..(input_range.len() - 1) {
-                writeln!(writer, "{}", input_range[i])?;
-                writeln!(writer, "{:^^len$}", "", len = input_range[i].width())?;
-            }
-            writeln!(writer, "{}", input_range[input_range.len() - 1])?;
-            writeln!(
-                writer,
-                "{:^^line_width$}",
-                "",
-                line_width = input[end.start..(span.end())].width()
-            )?;
-        }
-        if let Some(line) = line_span::find_next_line_range(input, span.end()) {
-            writeln!(writer, "{}", &input[line])?;
-        }
-        Ok(())
+/// Print `diag` for humans, with snippets of the code it points at.
+pub fn print_diagnostic(
+    writer: &mut dyn Write,
+    source_map: &SourceMap,
+    diag: &Diagnostic,
+) -> Result<(), std::io::Error> {
+    let header = format!("{}[{}]: {}", diag.level.as_str(), diag.code, diag.message);
+    match diag.span {
+        Some(span) => pretty_print_error(writer, source_map, &header, span)?,
+        None => writeln!(writer, "{}", header)?,
+    }
+    for label in &diag.labels {
+        let message = format!("note: {}", label.message);
+        pretty_print_error(writer, source_map, &message, label.span)?;
+    }
+    for note in &diag.notes {
+        writeln!(writer, "  = note: {}", note)?;
     }
+    Ok(())
 }
 
+/// Convert `diag` into a JSON object for tools to consume.
+pub fn diagnostic_to_json(source_map: &SourceMap, diag: &Diagnostic) -> Value {
+    json!({
+        "level": diag.level.as_str(),
+        "code": diag.code,
+        "message": diag.message,
+        "span": diag.span.map(|span| span_to_json(source_map, span)),
+        "labels": diag
+            .labels
+            .iter()
+            .map(|label| json!({
+                "message": label.message,
+                "span": span_to_json(source_map, label.span),
+            }))
+            .collect::<Vec<_>>(),
+        "notes": diag.notes,
+    })
+}
+
+fn span_to_json(source_map: &SourceMap, span: Span) -> Value {
+    let file = source_map.file(span.file);
+    let (start, end) = if span.is_eof() {
+        (file.src.len(), file.src.len())
+    } else {
+        (span.start(), span.end())
+    };
+    let (line, column) = file.line_col(start);
+    let (end_line, end_column) = file.line_col(end);
+    json!({
+        "file": file.name,
+        "start": start,
+        "end": end,
+        "line": line,
+        "column": column,
+        "end_line": end_line,
+        "end_column": end_column,
+    })
+}
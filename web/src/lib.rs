@@ -1,7 +1,8 @@
 use bytes::Bytes;
-use natrium::util::pretty_print_error;
+use natrium::util::print_diagnostic;
+use r0syntax::source_map::SourceMap;
 use r0vm::s0::S0;
-use std::{fmt::Write as FmtWrite, io, io::Write};
+use std::{fmt::Write as FmtWrite, io};
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 
@@ -22,32 +23,27 @@ pub fn main_js() -> Result<(), JsValue> {
 }
 
 fn compile_internal(input: &str) -> Result<S0, String> {
-    let l = r0syntax::lexer::spanned_lexer(input);
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file("input.c0", input);
+    let l = r0syntax::lexer::spanned_lexer_in(input, file);
     let mut p = r0syntax::parser::Parser::new(l);
-    let r = p.parse();
-    let program = match r {
-        Ok(p) => p,
-        Err(e) => {
-            let mut err = Vec::new();
-            if let Some(span) = e.span {
-                pretty_print_error(&mut err, input, &format!("{:?}", e.kind), span)
-                    .map_err(|x| x.to_string())?;
-            } else {
-                writeln!(err, "{:?}", e.kind).map_err(|x| x.to_string())?;
-            }
-            return Err(unsafe { String::from_utf8_unchecked(err) });
+    let (program, errors) = p.parse();
+    if !errors.is_empty() {
+        let mut err = Vec::new();
+        for e in errors {
+            print_diagnostic(&mut err, &source_map, &e.to_diagnostic())
+                .map_err(|x| x.to_string())?;
         }
-    };
+        return Err(unsafe { String::from_utf8_unchecked(err) });
+    }
 
     let s0 = match r0codegen::generator::compile(&program) {
         Ok(p) => p,
-        Err(e) => {
+        Err(errors) => {
             let mut err = Vec::new();
-            if let Some(span) = e.span {
-                pretty_print_error(&mut err, input, &format!("{:?}", e.kind), span)
+            for e in errors {
+                print_diagnostic(&mut err, &source_map, &e.to_diagnostic())
                     .map_err(|x| x.to_string())?;
-            } else {
-                writeln!(err, "{:?}", e.kind).map_err(|x| x.to_string())?;
             }
             return Err(unsafe { String::from_utf8_unchecked(err) });
         }